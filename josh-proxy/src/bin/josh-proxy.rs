@@ -4,11 +4,12 @@ use base64;
 
 use futures::future;
 use futures::FutureExt;
-use futures::TryStreamExt;
+use hyper::body::HttpBody;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Request, Response, Server};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::{Arc, RwLock};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::process::Command;
 use tracing_futures::Instrument;
 
@@ -29,10 +30,33 @@ josh::regex_parsed!(
     [upstream_repo, filter, pathinfo, headref]
 );
 
-josh::regex_parsed!(KvUrl, r"/@kv/(?P<k>.*)", [k]);
+josh::regex_parsed!(
+    KvUrl,
+    r"^/@kv(?P<upstream_repo>/.*?[.]git)?/(?P<k>.*)$",
+    [upstream_repo, k]
+);
+
+josh::regex_parsed!(
+    ChangeUrl,
+    r"^(?P<upstream_repo>/.*?[.]git)?/c/(?P<change>[0-9]+)/(?P<patchset>[0-9]*)$",
+    [upstream_repo, change, patchset]
+);
+
+josh::regex_parsed!(
+    CommitsUrl,
+    r"^/@commits(?P<upstream_repo>/.*[.]git)/(?P<sha>[0-9a-f]{40})$",
+    [upstream_repo, sha]
+);
 
 type CredentialCache = HashMap<String, std::time::Instant>;
 
+const HEAD_REFRESH_INTERVAL: std::time::Duration =
+    std::time::Duration::from_secs(60 * 60);
+
+// Upload-pack requests up to this size are checked for a plain NAK answer,
+// larger ones are passed on without progress
+const UPLOAD_PACK_PEEK_LIMIT: usize = 1024 * 1024;
+
 #[derive(Clone)]
 struct JoshProxyService {
     port: String,
@@ -42,9 +66,12 @@ struct JoshProxyService {
     forward_maps: Arc<RwLock<josh::filter_cache::FilterCache>>,
     backward_maps: Arc<RwLock<josh::filter_cache::FilterCache>>,
     credential_cache: Arc<RwLock<CredentialCache>>,
+    head_refreshed: Arc<RwLock<HashMap<String, std::time::Instant>>>,
     fetch_permits: Arc<tokio::sync::Semaphore>,
     filter_permits: Arc<tokio::sync::Semaphore>,
-    kv_store: Arc<RwLock<std::collections::HashMap<String, serde_json::Value>>>,
+    kv_store: josh::kv_store::SharedKvStore,
+    maintenance_status: josh::maintenance::MaintenanceStatus,
+    query_limits: josh::query::QueryLimits,
 }
 
 impl std::fmt::Debug for JoshProxyService {
@@ -145,17 +172,50 @@ async fn fetch_upstream(
     let credential_cache = service.credential_cache.clone();
     let br_path = service.repo_path.clone();
 
+    // The upstream HEAD is looked up with an extra round trip, so it is only
+    // refreshed when missing or stale, or once per HEAD_REFRESH_INTERVAL
+    let head_refreshed = service.head_refreshed.clone();
+    let head_due = head_refreshed
+        .read()?
+        .get(&upstream_repo)
+        .map(|last| last.elapsed() > HEAD_REFRESH_INTERVAL)
+        .unwrap_or(true);
+
     let permit = service.fetch_permits.acquire().await;
 
     let res = tokio::task::spawn_blocking(move || {
-        josh_proxy::fetch_refs_from_url(
+        let res = josh_proxy::fetch_refs_from_url(
             &br_path,
             &upstream_repo,
             &remote_url,
             &refs_to_fetch,
             &username,
             &password,
-        )
+        );
+        if let Ok(true) = res {
+            if head_due
+                || josh_proxy::upstream_head_is_stale(&br_path, &upstream_repo)
+            {
+                match josh_proxy::fetch_head_from_url(
+                    &br_path,
+                    &upstream_repo,
+                    &remote_url,
+                    &username,
+                    &password,
+                ) {
+                    Ok(()) => {
+                        head_refreshed.write().unwrap().insert(
+                            upstream_repo.clone(),
+                            std::time::Instant::now(),
+                        );
+                    }
+                    Err(e) => {
+                        tracing::warn!("fetch_head_from_url failed: {:?}", e)
+                    }
+                }
+            }
+        }
+        res
     })
     .await??;
 
@@ -191,14 +251,30 @@ async fn static_paths(
                 .unwrap_or(Response::default()),
         );
     }
+    if path == "/maintenance" {
+        let body_str = serde_json::to_string_pretty(
+            &*service.maintenance_status.read().unwrap(),
+        )
+        .unwrap_or("{}".to_owned());
+        return Some(
+            Response::builder()
+                .status(hyper::StatusCode::OK)
+                .body(hyper::Body::from(body_str))
+                .unwrap_or(Response::default()),
+        );
+    }
     if path == "/filters" {
         service.credential_cache.write().unwrap().clear();
         let service = service.clone();
 
         let body_str = tokio::task::spawn_blocking(move || {
             let repo = git2::Repository::init_bare(&service.repo_path).unwrap();
-            let known_filters =
-                josh::housekeeping::discover_filter_candidates(&repo).ok();
+            let known_filters = josh::housekeeping::prebuild_candidates(
+                &repo,
+                ARGS.value_of("prebuild-config").map(std::path::Path::new),
+            )
+            .ok()
+            .map(|(known_filters, _)| known_filters);
             toml::to_string_pretty(&known_filters).unwrap()
         })
         .await
@@ -247,6 +323,13 @@ async fn repo_update_fn(
     .unwrap();
 }
 
+fn filter_threads() -> usize {
+    return ARGS
+        .value_of("threads")
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(1);
+}
+
 #[tracing::instrument]
 async fn do_filter(
     repo_path: std::path::PathBuf,
@@ -255,10 +338,34 @@ async fn do_filter(
     temp_ns: Arc<josh_proxy::TmpGitNamespace>,
     filter_spec: String,
     headref: String,
+    messages: Option<tokio::sync::mpsc::UnboundedSender<String>>,
 ) -> josh::JoshResult<git2::Repository> {
     let forward_maps = service.forward_maps.clone();
     let backward_maps = service.backward_maps.clone();
     let permit = service.filter_permits.acquire().await;
+
+    // When the client goes away this future gets dropped, which
+    // stops the filtering at the next commit and frees the permit
+    let token = josh::progress::CancellationToken::new();
+    let _cancel_on_drop = josh::progress::CancelOnDrop(token.clone());
+    let progress = Arc::new(josh::progress::Progress::new(
+        Some(Box::new(
+            move |filter_spec: &str, walked: usize, total: usize| {
+                if walked % 1000 == 0 || walked == total {
+                    let msg = format!(
+                        "filtering {}: {}/{} commits",
+                        filter_spec, walked, total
+                    );
+                    tracing::info!("{}", msg);
+                    if let Some(messages) = &messages {
+                        messages.send(msg).ok();
+                    }
+                }
+            },
+        )),
+        token,
+    ));
+
     let r = tokio::task::spawn_blocking(move || {
         let repo = git2::Repository::init_bare(&repo_path)?;
         let filter = josh::filters::parse(&filter_spec);
@@ -279,17 +386,31 @@ async fn do_filter(
             temp_ns.reference(&headref),
         ));
 
-        let mut bm = josh::filter_cache::new_downstream(&backward_maps);
-        let mut fm = josh::filter_cache::new_downstream(&forward_maps);
-        josh::scratch::apply_filter_to_refs(
-            &repo, &*filter, &from_to, &mut fm, &mut bm,
-        )?;
-        josh::filter_cache::try_merge_both(
-            forward_maps,
-            backward_maps,
-            &fm,
-            &bm,
-        );
+        let threads = filter_threads();
+        if threads > 1 {
+            josh::scratch::apply_filter_to_refs_parallel(
+                &repo.path(),
+                &filter_spec,
+                &from_to,
+                forward_maps,
+                backward_maps,
+                threads,
+                Some(progress),
+            )?;
+        } else {
+            let mut bm = josh::filter_cache::new_downstream(&backward_maps);
+            let mut fm = josh::filter_cache::new_downstream(&forward_maps);
+            fm.set_progress(progress);
+            josh::scratch::apply_filter_to_refs(
+                &repo, &*filter, &from_to, &mut fm, &mut bm,
+            )?;
+            josh::filter_cache::try_merge_both(
+                forward_maps,
+                backward_maps,
+                &fm,
+                &bm,
+            );
+        }
         repo.reference_symbolic(
             &temp_ns.reference("HEAD"),
             &temp_ns.reference(&headref),
@@ -305,6 +426,376 @@ async fn do_filter(
     return r;
 }
 
+// Counterparts of an upstream commit in all known views of the repo,
+// or the original commits of a filtered commit
+async fn commits_fn(
+    serv: Arc<JoshProxyService>,
+    req: Request<hyper::Body>,
+    commits_url: CommitsUrl,
+) -> Response<hyper::Body> {
+    let upstream_repo = commits_url.upstream_repo.clone();
+    if let Some(resp) = authorize(
+        serv.clone(),
+        &upstream_repo,
+        &[serv.upstream_url.as_str(), upstream_repo.as_str()].join(""),
+        &format!("refs/josh/upstream/{}/HEAD", josh::to_ns(&upstream_repo)),
+        parse_auth(&req),
+    )
+    .await
+    {
+        return resp;
+    }
+
+    let filter_permits = serv.filter_permits.clone();
+    let permit = filter_permits.acquire().await;
+    let result = tokio::task::spawn_blocking(move || {
+        let repo = git2::Repository::init_bare(&serv.repo_path)?;
+        let sha = git2::Oid::from_str(&commits_url.sha)?;
+        if repo.find_commit(sha).is_err() {
+            return Ok(None);
+        }
+        let originals = josh::housekeeping::find_original_commits(
+            &repo,
+            &commits_url.upstream_repo,
+            sha,
+            serv.backward_maps.clone(),
+        )?;
+        let (views, not_built) = if originals.is_empty() {
+            josh::some_or!(
+                josh::housekeeping::find_filtered_commits(
+                    &repo,
+                    &commits_url.upstream_repo,
+                    sha,
+                    ARGS.value_of("prebuild-config").map(std::path::Path::new),
+                    std::time::Instant::now() + serv.query_limits.timeout,
+                    serv.forward_maps.clone(),
+                    serv.backward_maps.clone(),
+                )?,
+                {
+                    return Ok(None);
+                }
+            )
+        } else {
+            (BTreeMap::new(), vec![])
+        };
+        let to_str = |m: BTreeMap<String, git2::Oid>| {
+            m.into_iter()
+                .map(|(k, v)| (k, v.to_string()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        return Ok(Some(serde_json::to_string_pretty(&serde_json::json!({
+            "commit": sha.to_string(),
+            "views": to_str(views),
+            "not_built": not_built,
+            "originals": to_str(originals),
+        }))?));
+    })
+    .await
+    .unwrap_or(Err(josh::josh_error("commits_fn: task failed")));
+    std::mem::drop(permit);
+
+    return match result {
+        Ok(Some(body_str)) => Response::builder()
+            .status(hyper::StatusCode::OK)
+            .body(hyper::Body::from(format!("{}\n", body_str)))
+            .unwrap(),
+        Ok(None) => Response::builder()
+            .status(hyper::StatusCode::NOT_FOUND)
+            .body(hyper::Body::from("commit not found\n"))
+            .unwrap(),
+        Err(josh::JoshError(e)) => Response::builder()
+            .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+            .body(hyper::Body::from(format!("{}\n", e)))
+            .unwrap(),
+    };
+}
+
+// Diff of a review under refs/changes against its parent, as expected by
+// josh-review
+async fn change_fn(
+    serv: Arc<JoshProxyService>,
+    req: Request<hyper::Body>,
+    change_url: ChangeUrl,
+) -> Response<hyper::Body> {
+    let patchset = Some(change_url.patchset.clone()).filter(|x| x != "");
+
+    // Without a repo in the url the change is only looked up in the refs
+    // fetched before, so only the repo it belongs to gets authorized
+    let upstream_repo = if change_url.upstream_repo != "" {
+        change_url.upstream_repo.clone()
+    } else {
+        let repo_path = serv.repo_path.clone();
+        let change = change_url.change.clone();
+        let patchset = patchset.clone();
+        let found = tokio::task::spawn_blocking(move || {
+            josh_proxy::find_change(
+                &repo_path,
+                &change,
+                patchset.as_deref(),
+                None,
+            )
+        })
+        .await;
+        match found {
+            Ok(Ok(Some((upstream_repo, _)))) => upstream_repo,
+            Ok(Ok(None)) => {
+                return Response::builder()
+                    .status(hyper::StatusCode::NOT_FOUND)
+                    .body(hyper::Body::from("change not found\n"))
+                    .unwrap();
+            }
+            _ => {
+                return Response::builder()
+                    .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(hyper::Body::empty())
+                    .unwrap();
+            }
+        }
+    };
+
+    let remote_url =
+        [serv.upstream_url.as_str(), upstream_repo.as_str()].join("");
+    let auth = parse_auth(&req);
+    if let Some(resp) = authorize(
+        serv.clone(),
+        &upstream_repo,
+        &remote_url,
+        &format!("refs/josh/upstream/{}/HEAD", josh::to_ns(&upstream_repo)),
+        auth.clone(),
+    )
+    .await
+    {
+        return resp;
+    }
+
+    let repo_path = serv.repo_path.clone();
+    let fetch_permits = serv.fetch_permits.clone();
+    let permit = fetch_permits.acquire().await;
+    let found = {
+        let upstream_repo = upstream_repo.clone();
+        let remote_url = remote_url.clone();
+        tokio::task::spawn_blocking(move || {
+            let found = josh_proxy::find_change(
+                &repo_path,
+                &change_url.change,
+                patchset.as_deref(),
+                Some(&upstream_repo),
+            )?;
+            if found.is_some() {
+                return Ok(found);
+            }
+
+            // Not fetched yet, for example because it was created since the
+            // last request for its repo
+            let (username, password) = auth;
+            josh_proxy::fetch_refs_from_url(
+                &repo_path,
+                &upstream_repo,
+                &remote_url,
+                &[&josh_proxy::change_refs(&change_url.change)],
+                &username,
+                &password,
+            )?;
+            josh_proxy::find_change(
+                &repo_path,
+                &change_url.change,
+                patchset.as_deref(),
+                Some(&upstream_repo),
+            )
+        })
+        .await
+    };
+    std::mem::drop(permit);
+
+    let headref = match found {
+        Ok(Ok(Some((_, headref)))) => headref,
+        Ok(Ok(None)) => {
+            return Response::builder()
+                .status(hyper::StatusCode::NOT_FOUND)
+                .body(hyper::Body::from("change not found\n"))
+                .unwrap();
+        }
+        _ => {
+            return Response::builder()
+                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+                .body(hyper::Body::empty())
+                .unwrap();
+        }
+    };
+
+    let temp_ns = match filter_namespace(
+        serv.clone(),
+        &upstream_repo,
+        ":nop",
+        &headref,
+        None,
+    )
+    .await
+    {
+        Ok(temp_ns) => temp_ns,
+        Err(_) => {
+            return Response::builder()
+                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+                .body(hyper::Body::empty())
+                .unwrap();
+        }
+    };
+
+    let res = tokio::task::spawn_blocking(move || {
+        let repo = git2::Repository::init_bare(&serv.repo_path)?;
+        josh::query::render(
+            &repo,
+            &temp_ns.reference(&headref),
+            "diff=HEAD",
+            serv.kv_store.clone(),
+            &upstream_repo,
+            serv.forward_maps.clone(),
+            serv.backward_maps.clone(),
+            &serv.query_limits,
+        )
+    })
+    .await;
+
+    return match res {
+        Ok(Ok(body_str)) => Response::builder()
+            .status(hyper::StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(hyper::Body::from(body_str))
+            .unwrap(),
+        _ => Response::builder()
+            .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+            .body(hyper::Body::empty())
+            .unwrap(),
+    };
+}
+
+fn kv_response(
+    status: hyper::StatusCode,
+    body: String,
+) -> Response<hyper::Body> {
+    return Response::builder()
+        .status(status)
+        .body(hyper::Body::from(body))
+        .unwrap();
+}
+
+// Writes the kv store file outside of the lock of the store
+async fn write_kv(
+    pending: josh::kv_store::PendingWrite,
+) -> josh::JoshResult<String> {
+    tokio::task::spawn_blocking(move || pending.write())
+        .await
+        .unwrap_or(Err(josh::josh_error("write_kv: task failed")))?;
+    return Ok("ok".to_owned());
+}
+
+// GET, POST/PUT and DELETE of a key, GET without a key lists the keys.
+// Keys below /@kv/<repo>.git/ belong to that repo, the others are seen
+// by all repos.
+async fn kv_fn(
+    serv: Arc<JoshProxyService>,
+    req: Request<hyper::Body>,
+    kv_url: KvUrl,
+) -> Response<hyper::Body> {
+    let method = req.method().clone();
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(e) => {
+            return kv_response(
+                hyper::StatusCode::BAD_REQUEST,
+                format!("cannot read body: {}\n", e),
+            )
+        }
+    };
+    let ns = &kv_url.upstream_repo;
+    let key = &kv_url.k;
+
+    let result = if key == "" {
+        if method != hyper::Method::GET {
+            return kv_response(
+                hyper::StatusCode::METHOD_NOT_ALLOWED,
+                "missing key\n".to_owned(),
+            );
+        }
+        serv.kv_store
+            .read()
+            .map_err(|_| josh::josh_error("kv store lock poisoned"))
+            .map(|kv| serde_json::json!(kv.keys(&ns)).to_string())
+    } else if method == hyper::Method::GET && body.is_empty() {
+        match serv.kv_store.read() {
+            Ok(kv) => match kv.get(&ns, &key) {
+                Some(v) => Ok(v.to_string()),
+                None => {
+                    return kv_response(
+                        hyper::StatusCode::NOT_FOUND,
+                        "".to_owned(),
+                    )
+                }
+            },
+            Err(_) => Err(josh::josh_error("kv store lock poisoned")),
+        }
+    } else if method == hyper::Method::DELETE {
+        let removed = serv
+            .kv_store
+            .write()
+            .map_err(|_| josh::josh_error("kv store lock poisoned"))
+            .and_then(|mut kv| kv.remove(&ns, &key));
+        match removed {
+            Ok(Some(pending)) => write_kv(pending).await,
+            Ok(None) => {
+                return kv_response(hyper::StatusCode::NOT_FOUND, "".to_owned())
+            }
+            Err(e) => Err(e),
+        }
+    } else {
+        let value = match serde_json::from_slice(&body) {
+            Ok(value) => value,
+            Err(e) => {
+                return kv_response(
+                    hyper::StatusCode::BAD_REQUEST,
+                    format!("invalid json: {}\n", e),
+                )
+            }
+        };
+        let set = serv
+            .kv_store
+            .write()
+            .map_err(|_| josh::josh_error("kv store lock poisoned"))
+            .and_then(|mut kv| kv.set(&ns, &key, value));
+        match set {
+            Ok(pending) => write_kv(pending).await,
+            Err(e) => Err(e),
+        }
+    };
+
+    return match result {
+        Ok(body) => kv_response(hyper::StatusCode::OK, body),
+        Err(josh::JoshError(e)) => {
+            tracing::error!("kv: {}", e);
+            kv_response(hyper::StatusCode::INTERNAL_SERVER_ERROR, "".to_owned())
+        }
+    };
+}
+
+// Arguments of an "info" query like "info&rev=HEAD~1&path=a&path=b"
+fn info_query(query: Option<&str>) -> Option<Vec<(String, String)>> {
+    let mut split = query?.split('&');
+    if split.next() != Some("info") {
+        return None;
+    }
+    return Some(
+        split
+            .map(|arg| {
+                let mut kv = arg.splitn(2, '=');
+                (
+                    kv.next().unwrap_or("").to_owned(),
+                    josh::from_ns(kv.next().unwrap_or("")),
+                )
+            })
+            .collect(),
+    );
+}
+
 /* #[tracing::instrument] */
 async fn call_service(
     serv: Arc<JoshProxyService>,
@@ -327,29 +818,15 @@ async fn call_service(
     }
 
     if let Some(kv_url) = KvUrl::from_str(&path) {
-        let body = req
-            .into_body()
-            .try_fold(String::new(), |mut acc, elt| async move {
-                acc.push_str(std::str::from_utf8(&elt).unwrap());
-                Ok(acc)
-            })
-            .await
-            .unwrap();
+        return kv_fn(serv, req, kv_url).await;
+    }
 
-        if body == "" {
-            if let Some(v) = serv.kv_store.read().unwrap().get(&kv_url.k) {
-                return Response::builder()
-                    .body(hyper::Body::from(v.to_string()))
-                    .unwrap();
-            }
-            return Response::builder().body(hyper::Body::from("")).unwrap();
-        } else {
-            serv.kv_store
-                .write()
-                .unwrap()
-                .insert(kv_url.k, serde_json::from_str(&body).unwrap());
-            return Response::builder().body(hyper::Body::from("ok")).unwrap();
-        }
+    if let Some(change_url) = ChangeUrl::from_str(&path) {
+        return change_fn(serv, req, change_url).await;
+    }
+
+    if let Some(commits_url) = CommitsUrl::from_str(&path) {
+        return commits_fn(serv, req, commits_url).await;
     }
 
     let parsed_url = {
@@ -378,6 +855,33 @@ async fn call_service(
     ]
     .join("");
 
+    let req = if req.method() == hyper::Method::POST
+        && parsed_url.pathinfo == "/git-upload-pack"
+        && !req.headers().contains_key(hyper::header::CONTENT_ENCODING)
+    {
+        let (parts, body) = req.into_parts();
+        let (start, complete, body) =
+            match peek_body(body, UPLOAD_PACK_PEEK_LIMIT).await {
+                Ok(r) => r,
+                Err(_) => {
+                    return Response::builder()
+                        .status(hyper::StatusCode::BAD_REQUEST)
+                        .body(hyper::Body::empty())
+                        .unwrap();
+                }
+            };
+        let req = Request::from_parts(parts, body);
+        if complete && josh_proxy::upload_pack_starts_with_nak(&start) {
+            return upload_pack_with_progress(
+                serv, req, parsed_url, headref, remote_url,
+            )
+            .await;
+        }
+        req
+    } else {
+        req
+    };
+
     let (username, password) = parse_auth(&req);
 
     let temp_ns = match prepare_namespace(
@@ -394,9 +898,21 @@ async fn call_service(
         PrepareNsResult::Resp(resp) => return resp,
     };
 
-    if req.uri().query() == Some("info") {
+    if let Some(info_args) = info_query(req.uri().query()) {
         let forward_maps = serv.forward_maps.clone();
-        let backward_maps = serv.forward_maps.clone();
+        let backward_maps = serv.backward_maps.clone();
+
+        let rev = info_args
+            .iter()
+            .filter(|(k, _)| k == "rev")
+            .map(|(_, v)| v.clone())
+            .last()
+            .unwrap_or("".to_owned());
+        let paths: Vec<String> = info_args
+            .into_iter()
+            .filter(|(k, _)| k == "path")
+            .map(|(_, v)| v)
+            .collect();
 
         let info_str = tokio::task::spawn_blocking(move || {
             let repo = git2::Repository::init_bare(&serv.repo_path).unwrap();
@@ -405,10 +921,14 @@ async fn call_service(
                 &*josh::filters::parse(&parsed_url.filter),
                 &parsed_url.upstream_repo,
                 &headref,
+                &rev,
+                &paths,
                 forward_maps.clone(),
                 backward_maps.clone(),
             )
-            .unwrap_or("get_info: error".to_owned())
+            .unwrap_or_else(|josh::JoshError(e)| {
+                format!("get_info: error: {}", e)
+            })
         })
         .await
         .unwrap();
@@ -419,8 +939,6 @@ async fn call_service(
             .unwrap();
     }
 
-    let repo_path = serv.repo_path.to_str().unwrap();
-
     if let Some(q) = req.uri().query().map(|x| x.to_string()) {
         if parsed_url.pathinfo == "" {
             let res = tokio::task::spawn_blocking(move || {
@@ -431,10 +949,18 @@ async fn call_service(
                     &temp_ns.reference(&headref),
                     &q,
                     serv.kv_store.clone(),
+                    &parsed_url.upstream_repo,
                     serv.forward_maps.clone(),
                     serv.backward_maps.clone(),
+                    &serv.query_limits,
                 )
-                .unwrap_or(format!("query ERROR {:?}", q))
+                .unwrap_or_else(|josh::JoshError(e)| {
+                    if e.starts_with(josh::query::LIMIT_EXCEEDED) {
+                        format!("query ERROR {:?}: {}", q, e)
+                    } else {
+                        format!("query ERROR {:?}", q)
+                    }
+                })
             })
             .await
             .unwrap();
@@ -445,21 +971,13 @@ async fn call_service(
         }
     }
 
-    let mut cmd = Command::new("git");
-    cmd.arg("http-backend");
-    cmd.current_dir(&serv.repo_path);
-    cmd.env("GIT_DIR", repo_path);
-    cmd.env("GIT_HTTP_EXPORT_ALL", "");
-    cmd.env("GIT_NAMESPACE", temp_ns.name().clone());
-    cmd.env("GIT_PROJECT_ROOT", repo_path);
-    cmd.env("JOSH_BASE_NS", josh::to_ns(&parsed_url.upstream_repo));
-    cmd.env("JOSH_PASSWORD", password.value);
-    cmd.env("JOSH_PORT", serv.port.clone());
-    cmd.env("JOSH_REMOTE", remote_url);
-    cmd.env("JOSH_USERNAME", username);
-    cmd.env("JOSH_VIEWSTR", parsed_url.filter.clone());
-    cmd.env("PATH_INFO", parsed_url.pathinfo.clone());
-
+    let cmd = http_backend(
+        &serv,
+        &parsed_url,
+        &temp_ns,
+        (username, password),
+        &remote_url,
+    );
     let cgires = hyper_cgi::do_cgi(req, cmd).await.0;
 
     // This is chained as a seperate future to make sure that
@@ -469,19 +987,15 @@ async fn call_service(
     return cgires;
 }
 
-enum PrepareNsResult {
-    Ns(std::sync::Arc<josh_proxy::TmpGitNamespace>),
-    Resp(hyper::Response<hyper::Body>),
-}
-
-async fn prepare_namespace(
+// Fetches from upstream with the credentials of the request, returns the
+// response to send instead if that fails
+async fn authorize(
     serv: Arc<JoshProxyService>,
     upstream_repo: &str,
     remote_url: &str,
-    filter_spec: &str,
     headref: &str,
     auth: (String, josh_proxy::Password),
-) -> PrepareNsResult {
+) -> Option<Response<hyper::Body>> {
     let (username, password) = auth;
 
     let authorized = fetch_upstream(
@@ -498,42 +1012,297 @@ async fn prepare_namespace(
         let builder = Response::builder()
             .header("WWW-Authenticate", "Basic realm=User Visible Realm")
             .status(hyper::StatusCode::UNAUTHORIZED);
-        return PrepareNsResult::Resp(
-            builder.body(hyper::Body::empty()).unwrap(),
-        );
+        return Some(builder.body(hyper::Body::empty()).unwrap());
     }
 
     if let Err(_) = authorized {
         let builder = Response::builder()
             .status(hyper::StatusCode::INTERNAL_SERVER_ERROR);
-        return PrepareNsResult::Resp(
-            builder.body(hyper::Body::empty()).unwrap(),
-        );
+        return Some(builder.body(hyper::Body::empty()).unwrap());
     }
+    return None;
+}
 
-    let temp_ns = Arc::new(josh_proxy::TmpGitNamespace::new(&serv.repo_path));
+fn http_backend(
+    serv: &JoshProxyService,
+    parsed_url: &FilteredRepoUrl,
+    temp_ns: &josh_proxy::TmpGitNamespace,
+    auth: (String, josh_proxy::Password),
+    remote_url: &str,
+) -> Command {
+    let (username, password) = auth;
+    let repo_path = serv.repo_path.to_str().unwrap();
+    let mut cmd = Command::new("git");
+    cmd.arg("http-backend");
+    cmd.current_dir(&serv.repo_path);
+    cmd.env("GIT_DIR", repo_path);
+    cmd.env("GIT_HTTP_EXPORT_ALL", "");
+    cmd.env("GIT_NAMESPACE", temp_ns.name().clone());
+    cmd.env("GIT_PROJECT_ROOT", repo_path);
+    cmd.env("JOSH_BASE_NS", josh::to_ns(&parsed_url.upstream_repo));
+    cmd.env("JOSH_PASSWORD", password.value);
+    cmd.env("JOSH_PORT", serv.port.clone());
+    cmd.env("JOSH_REMOTE", remote_url);
+    cmd.env("JOSH_USERNAME", username);
+    cmd.env("JOSH_VIEWSTR", parsed_url.filter.clone());
+    cmd.env("PATH_INFO", parsed_url.pathinfo.clone());
+    return cmd;
+}
 
-    let serv = serv.clone();
+// Reads the start of a body, up to limit bytes. Returns the bytes read,
+// whether that is the whole body and a body that yields all of it again.
+async fn peek_body(
+    body: hyper::Body,
+    limit: usize,
+) -> Result<(Vec<u8>, bool, hyper::Body), hyper::Error> {
+    let mut body = body;
+    let mut start = vec![];
+    while start.len() <= limit {
+        match body.data().await {
+            Some(chunk) => start.extend_from_slice(&chunk?),
+            None => {
+                let whole = hyper::Body::from(start.clone());
+                return Ok((start, true, whole));
+            }
+        }
+    }
+    let first = hyper::body::Bytes::from(start.clone());
+    let first = futures::stream::once(future::ok::<_, hyper::Error>(first));
+    let rest = futures::StreamExt::chain(first, body);
+    return Ok((start, false, hyper::Body::wrap_stream(rest)));
+}
 
-    if !do_filter(
-        serv.repo_path.clone(),
+// Answers an upload-pack request while the view is still being filtered,
+// sending the filter progress and keepalives on side-band 2 before the
+// pack. Only used for requests where upload-pack answers with a plain NAK,
+// which is sent up front.
+async fn upload_pack_with_progress(
+    serv: Arc<JoshProxyService>,
+    req: Request<hyper::Body>,
+    parsed_url: FilteredRepoUrl,
+    headref: String,
+    remote_url: String,
+) -> Response<hyper::Body> {
+    if let Some(resp) = authorize(
         serv.clone(),
-        upstream_repo.to_owned(),
-        temp_ns.to_owned(),
-        filter_spec.to_owned(),
-        headref.to_string(),
+        &parsed_url.upstream_repo,
+        &remote_url,
+        &headref,
+        parse_auth(&req),
     )
     .await
-    .is_ok()
     {
-        let builder = Response::builder()
-            .status(hyper::StatusCode::INTERNAL_SERVER_ERROR);
-        return PrepareNsResult::Resp(
-            builder.body(hyper::Body::empty()).unwrap(),
+        return resp;
+    }
+
+    let (mut sender, body) = hyper::Body::channel();
+    tokio::spawn(async move {
+        if sender.send_data(josh_proxy::NAK.into()).await.is_err() {
+            return;
+        }
+
+        let (messages, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let filtered = filter_namespace(
+            serv.clone(),
+            &parsed_url.upstream_repo,
+            &parsed_url.filter,
+            &headref,
+            Some(messages),
+        );
+        tokio::pin!(filtered);
+
+        let interval = std::time::Duration::from_secs(5);
+        let mut keepalive = tokio::time::interval_at(
+            tokio::time::Instant::now() + interval,
+            interval,
         );
+        let filtered = loop {
+            let data = tokio::select! {
+                r = &mut filtered => break r,
+                Some(msg) = received.recv() => {
+                    josh_proxy::sideband(2, &format!("{}\n", msg))
+                }
+                _ = keepalive.tick() => josh_proxy::sideband(1, ""),
+            };
+            // Dropping the filter future cancels the filtering
+            if sender.send_data(data.into()).await.is_err() {
+                return;
+            }
+        };
+        while let Ok(msg) = received.try_recv() {
+            let data = josh_proxy::sideband(2, &format!("{}\n", msg));
+            if sender.send_data(data.into()).await.is_err() {
+                return;
+            }
+        }
+
+        let temp_ns = josh::ok_or!(filtered, {
+            let data = josh_proxy::sideband(3, "filtering failed\n");
+            sender.send_data(data.into()).await.ok();
+            return;
+        });
+        let mut started = false;
+        let sent = send_upload_pack(
+            &serv,
+            &temp_ns,
+            req.into_body(),
+            &mut sender,
+            &mut started,
+        )
+        .await;
+        if let Err(josh::JoshError(e)) = sent {
+            tracing::warn!("upload-pack failed: {}", e);
+            if started {
+                sender.abort();
+            } else {
+                let data = josh_proxy::sideband(3, "upload-pack failed\n");
+                sender.send_data(data.into()).await.ok();
+            }
+        }
+        std::mem::drop(temp_ns);
+    });
+
+    return Response::builder()
+        .header("Content-Type", "application/x-git-upload-pack-result")
+        .header("Cache-Control", "no-cache")
+        .body(body)
+        .unwrap();
+}
+
+// Runs upload-pack on the filtered namespace and sends its output as it is
+// produced, without the leading NAK which was sent already. started is set
+// once the first part of the pack was sent.
+async fn send_upload_pack(
+    serv: &JoshProxyService,
+    temp_ns: &josh_proxy::TmpGitNamespace,
+    body: hyper::Body,
+    sender: &mut hyper::body::Sender,
+    started: &mut bool,
+) -> josh::JoshResult<()> {
+    let mut child = Command::new("git")
+        .arg("upload-pack")
+        .arg("--stateless-rpc")
+        .arg(&serv.repo_path)
+        .current_dir(&serv.repo_path)
+        .env("GIT_NAMESPACE", temp_ns.name())
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .spawn()?;
+    let mut stdin = josh::some_or!(child.stdin.take(), {
+        return Err(josh::josh_error("no stdin"));
+    });
+    let mut stdout = josh::some_or!(child.stdout.take(), {
+        return Err(josh::josh_error("no stdout"));
+    });
+
+    let write_stdin = async move {
+        let mut body = body;
+        while let Some(chunk) = body.data().await {
+            stdin.write_all(&chunk?).await?;
+        }
+        return Ok::<_, josh::JoshError>(());
+    };
+    let read_stdout = async {
+        let mut nak = vec![0; josh_proxy::NAK.len()];
+        stdout.read_exact(&mut nak).await?;
+        if nak != josh_proxy::NAK.as_bytes() {
+            return Err(josh::josh_error("upload-pack did not send a NAK"));
+        }
+        let mut buffer = vec![0; 65536];
+        loop {
+            let n = stdout.read(&mut buffer).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            *started = true;
+            sender
+                .send_data(hyper::body::Bytes::copy_from_slice(&buffer[..n]))
+                .await?;
+        }
+    };
+    tokio::try_join!(write_stdin, read_stdout)?;
+
+    if !child.await?.success() {
+        return Err(josh::josh_error("upload-pack failed"));
+    }
+    return Ok(());
+}
+
+enum PrepareNsResult {
+    Ns(std::sync::Arc<josh_proxy::TmpGitNamespace>),
+    Resp(hyper::Response<hyper::Body>),
+}
+
+async fn prepare_namespace(
+    serv: Arc<JoshProxyService>,
+    upstream_repo: &str,
+    remote_url: &str,
+    filter_spec: &str,
+    headref: &str,
+    auth: (String, josh_proxy::Password),
+) -> PrepareNsResult {
+    if let Some(resp) =
+        authorize(serv.clone(), upstream_repo, remote_url, headref, auth).await
+    {
+        return PrepareNsResult::Resp(resp);
     }
 
-    return PrepareNsResult::Ns(temp_ns);
+    return match filter_namespace(
+        serv,
+        upstream_repo,
+        filter_spec,
+        headref,
+        None,
+    )
+    .await
+    {
+        Ok(temp_ns) => PrepareNsResult::Ns(temp_ns),
+        Err(_) => PrepareNsResult::Resp(
+            Response::builder()
+                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+                .body(hyper::Body::empty())
+                .unwrap(),
+        ),
+    };
+}
+
+// Filters the view into a new temporary namespace, sending the filter
+// progress to messages if given
+async fn filter_namespace(
+    serv: Arc<JoshProxyService>,
+    upstream_repo: &str,
+    filter_spec: &str,
+    headref: &str,
+    messages: Option<tokio::sync::mpsc::UnboundedSender<String>>,
+) -> josh::JoshResult<Arc<josh_proxy::TmpGitNamespace>> {
+    let temp_ns = Arc::new(josh_proxy::TmpGitNamespace::new(&serv.repo_path));
+    do_filter(
+        serv.repo_path.clone(),
+        serv.clone(),
+        upstream_repo.to_owned(),
+        temp_ns.clone(),
+        filter_spec.to_owned(),
+        headref.to_owned(),
+        messages,
+    )
+    .await?;
+    return Ok(temp_ns);
+}
+
+// Loads the config for creating filtered commits once for all requests,
+// this drops the maps if it changed since they were persisted
+fn set_rewrite_config(
+    repo: &git2::Repository,
+    forward_maps: &Arc<RwLock<josh::filter_cache::FilterCache>>,
+    backward_maps: &Arc<RwLock<josh::filter_cache::FilterCache>>,
+) {
+    let config = Arc::new(josh::scratch::RewriteConfig::from_repo(&repo));
+    forward_maps
+        .write()
+        .unwrap()
+        .set_rewrite_config(config.clone());
+    backward_maps.write().unwrap().set_rewrite_config(config);
 }
 
 #[tokio::main]
@@ -547,6 +1316,28 @@ async fn run_proxy() -> josh::JoshResult<i32> {
     );
 
     josh_proxy::create_repo(&local)?;
+    josh_proxy::configure_signing(
+        &local,
+        ARGS.is_present("keep-signature"),
+        ARGS.value_of("signing-key"),
+        ARGS.value_of("signing-format"),
+    )?;
+    josh_proxy::configure_branches(
+        &local,
+        &ARGS
+            .values_of("branch")
+            .map(|x| x.collect())
+            .unwrap_or(vec![]),
+    )?;
+    josh_proxy::configure_committer(
+        &local,
+        ARGS.value_of("committer-name"),
+        ARGS.value_of("committer-email"),
+        ARGS.value_of("merge-message"),
+    )?;
+
+    josh_proxy::sweep_tmp_namespaces(&local, namespace_grace())?;
+    josh_proxy::spawn_namespace_sweeper(local.clone(), namespace_grace());
 
     let forward_maps = Arc::new(RwLock::new(josh::filter_cache::try_load(
         &local.join("josh_forward_maps"),
@@ -554,6 +1345,27 @@ async fn run_proxy() -> josh::JoshResult<i32> {
     let backward_maps = Arc::new(RwLock::new(josh::filter_cache::try_load(
         &local.join("josh_backward_maps"),
     )));
+    set_rewrite_config(
+        &git2::Repository::open(&local)?,
+        &forward_maps,
+        &backward_maps,
+    );
+
+    let maintenance_status = Arc::new(RwLock::new(BTreeMap::new()));
+    let schedule = ARGS.value_of("maintenance").or(if ARGS.is_present("gc") {
+        Some("count-objects=60,repack=3600,prune=3600")
+    } else {
+        None
+    });
+    if let Some(schedule) = schedule {
+        josh::maintenance::spawn_thread(
+            local.clone(),
+            josh::maintenance::MaintenanceConfig::default()
+                .with_schedule(&schedule)?,
+            maintenance_status.clone(),
+            vec![forward_maps.clone(), backward_maps.clone()],
+        );
+    }
 
     let proxy_service = Arc::new(JoshProxyService {
         port: port,
@@ -562,9 +1374,14 @@ async fn run_proxy() -> josh::JoshResult<i32> {
         backward_maps: backward_maps,
         upstream_url: remote.to_owned(),
         credential_cache: Arc::new(RwLock::new(CredentialCache::new())),
+        head_refreshed: Arc::new(RwLock::new(HashMap::new())),
         fetch_permits: Arc::new(tokio::sync::Semaphore::new(1)),
         filter_permits: Arc::new(tokio::sync::Semaphore::new(10)),
-        kv_store: Arc::new(RwLock::new(HashMap::new())),
+        kv_store: Arc::new(RwLock::new(josh::kv_store::KvStore::load(
+            &local.join("josh_kv.json"),
+        )?)),
+        maintenance_status: maintenance_status,
+        query_limits: query_limits()?,
     });
 
     let make_service = make_service_fn(move |_| {
@@ -589,6 +1406,44 @@ async fn run_proxy() -> josh::JoshResult<i32> {
     Ok(0)
 }
 
+fn query_limits() -> josh::JoshResult<josh::query::QueryLimits> {
+    let mut limits = josh::query::QueryLimits::default();
+    let number = |name: &str| -> josh::JoshResult<Option<u64>> {
+        return match ARGS.value_of(name) {
+            Some(x) => Ok(Some(x.parse().map_err(|_| {
+                josh::josh_error(&format!("invalid value for --{}", name))
+            })?)),
+            None => Ok(None),
+        };
+    };
+    if let Some(x) = number("query-timeout")? {
+        limits.timeout = std::time::Duration::from_secs(x);
+    }
+    if let Some(x) = number("query-max-helper-calls")? {
+        limits.max_helper_calls = x as usize;
+    }
+    if let Some(x) = number("query-max-output")? {
+        limits.max_output = x as usize;
+    }
+    if let Some(x) = ARGS.value_of("query-helpers") {
+        limits.helpers = Some(
+            x.split(',')
+                .map(|x| x.trim().to_owned())
+                .filter(|x| !x.is_empty())
+                .collect(),
+        );
+    }
+    return Ok(limits);
+}
+
+fn namespace_grace() -> std::time::Duration {
+    return std::time::Duration::from_secs(
+        ARGS.value_of("namespace-grace")
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(60 * 60),
+    );
+}
+
 fn parse_args() -> clap::ArgMatches<'static> {
     let args = {
         let mut args = vec![];
@@ -618,7 +1473,19 @@ fn parse_args() -> clap::ArgMatches<'static> {
             clap::Arg::with_name("gc")
                 .long("gc")
                 .takes_value(false)
-                .help("Run git gc in maintanance"),
+                .help(
+                    "Run repack and prune in maintenance, \
+                    hourly unless --maintenance is given",
+                ),
+        )
+        .arg(
+            clap::Arg::with_name("maintenance")
+                .long("maintenance")
+                .takes_value(true)
+                .help(
+                    "Run maintenance steps in the background, \
+                    for example: count-objects=60,repack=3600,prune=3600",
+                ),
         )
         .arg(
             clap::Arg::with_name("m")
@@ -631,12 +1498,113 @@ fn parse_args() -> clap::ArgMatches<'static> {
                 "Number of concurrent upstream git fetch/push operations",
             ),
         )
+        .arg(
+            clap::Arg::with_name("prebuild-config")
+                .long("prebuild-config")
+                .takes_value(true)
+                .help("TOML file listing views to build in maintanance"),
+        )
+        .arg(
+            clap::Arg::with_name("branch")
+                .long("branch")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help(
+                    "Pattern of upstream branches to filter in maintanance, \
+                    in addition to the default branch",
+                ),
+        )
+        .arg(
+            clap::Arg::with_name("threads")
+                .long("threads")
+                .takes_value(true)
+                .help("Number of threads used to filter the refs of a repo"),
+        )
+        .arg(
+            clap::Arg::with_name("namespace-grace")
+                .long("namespace-grace")
+                .takes_value(true)
+                .help(
+                    "Seconds after which temporary request namespaces \
+                    are considered leaked and removed (default: 3600)",
+                ),
+        )
+        .arg(
+            clap::Arg::with_name("query-timeout")
+                .long("query-timeout")
+                .takes_value(true)
+                .help("Seconds a query may take to render (default: 30)"),
+        )
+        .arg(
+            clap::Arg::with_name("query-max-helper-calls")
+                .long("query-max-helper-calls")
+                .takes_value(true)
+                .help(
+                    "Number of git-* and josh-* helper calls allowed \
+                    in one query (default: 10000)",
+                ),
+        )
+        .arg(
+            clap::Arg::with_name("query-max-output")
+                .long("query-max-output")
+                .takes_value(true)
+                .help("Size of query results in bytes (default: 16777216)"),
+        )
+        .arg(
+            clap::Arg::with_name("query-helpers")
+                .long("query-helpers")
+                .takes_value(true)
+                .help(
+                    "Comma separated list of the git-* and josh-* helpers \
+                    templates may use (default: all)",
+                ),
+        )
         /* .arg( */
         /*     clap::Arg::with_name("g") */
         /*         .short("g") */
         /*         .takes_value(false) */
         /*         .help("Enable gerrit integration"), */
         /* ) */
+        .arg(
+            clap::Arg::with_name("keep-signature")
+                .long("keep-signature")
+                .takes_value(false)
+                .help("Keep signatures of rewritten commits as extra header"),
+        )
+        .arg(
+            clap::Arg::with_name("signing-key")
+                .long("signing-key")
+                .takes_value(true)
+                .help("Key used to sign rewritten commits"),
+        )
+        .arg(
+            clap::Arg::with_name("signing-format")
+                .long("signing-format")
+                .takes_value(true)
+                .possible_values(&["openpgp", "ssh"])
+                .help("Format of --signing-key"),
+        )
+        .arg(
+            clap::Arg::with_name("committer-name")
+                .long("committer-name")
+                .takes_value(true)
+                .requires("committer-email")
+                .help("Committer name of commits created by josh"),
+        )
+        .arg(
+            clap::Arg::with_name("committer-email")
+                .long("committer-email")
+                .takes_value(true)
+                .requires("committer-name")
+                .help("Committer email of commits created by josh"),
+        )
+        .arg(
+            clap::Arg::with_name("merge-message")
+                .long("merge-message")
+                .takes_value(true)
+                .help("Message template of josh-merge commits"),
+        )
         .arg(clap::Arg::with_name("port").long("port").takes_value(true))
         .get_matches_from(args)
 }
@@ -734,15 +1702,47 @@ fn main() {
     )));
     if ARGS.is_present("m") {
         let repo = git2::Repository::init_bare(&local).unwrap();
-        let known_filters =
-            josh::housekeeping::discover_filter_candidates(&repo).unwrap();
-        josh::housekeeping::refresh_known_filters(
-            &repo,
+        set_rewrite_config(&repo, &forward_maps, &backward_maps);
+        josh_proxy::sweep_tmp_namespaces(&repo.path(), namespace_grace())
+            .unwrap();
+        let (known_filters, known_branches) =
+            match josh::housekeeping::prebuild_candidates(
+                &repo,
+                ARGS.value_of("prebuild-config").map(std::path::Path::new),
+            ) {
+                Ok(x) => x,
+                Err(e) => {
+                    println!("ERROR: {:?}", e);
+                    std::process::exit(1);
+                }
+            };
+        josh::housekeeping::refresh_known_filters_parallel(
+            &repo.path(),
             &known_filters,
+            &known_branches,
             forward_maps.clone(),
             backward_maps.clone(),
+            filter_threads(),
         )
         .unwrap();
+
+        if ARGS.is_present("gc") {
+            let config = josh::maintenance::MaintenanceConfig::default()
+                .with_schedule(
+                    ARGS.value_of("maintenance")
+                        .unwrap_or("count-objects=0,repack=0,prune=0"),
+                )
+                .unwrap();
+            let status = josh::maintenance::run_all(
+                &repo.path(),
+                &config,
+                &[forward_maps.clone(), backward_maps.clone()],
+            );
+            println!("{}", serde_json::to_string_pretty(&status).unwrap());
+            if status.iter().any(|x| !x.success) {
+                std::process::exit(1);
+            }
+        }
         std::process::exit(0);
     }
 
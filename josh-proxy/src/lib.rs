@@ -57,9 +57,11 @@ pub fn process_repo_update(
 
     let (baseref, push_to, options) = baseref_and_options(refname)?;
     let josh_merge = options.contains(&"josh-merge".to_string());
+    let josh_rebase = options.contains(&"josh-rebase".to_string());
 
     tracing::debug!("push options: {:?}", options);
     tracing::debug!("josh-merge: {:?}", josh_merge);
+    tracing::debug!("josh-rebase: {:?}", josh_rebase);
 
     let old = if old == git2::Oid::zero() {
         let rev = format!("refs/namespaces/{}/{}", git_ns, &baseref);
@@ -116,17 +118,37 @@ pub fn process_repo_update(
             let merged_tree = repo
                 .merge_commits(&base_commit, &backward_commit, None)?
                 .write_tree_to(&repo)?;
+            let config = josh::scratch::RewriteConfig::from_repo(&repo);
+            let committer = config
+                .committer(&backward_commit.committer().when())?
+                .unwrap_or(backward_commit.committer().to_owned());
             repo.commit(
                 None,
                 &backward_commit.author(),
-                &backward_commit.committer(),
-                &format!("Merge from {}", &filter_spec),
+                &committer,
+                &config.merge_message(&filter_spec),
                 &repo.find_tree(merged_tree)?,
                 &[&base_commit, &backward_commit],
             )?
         } else {
             return Err(josh::josh_error("josh_merge failed"));
         }
+    } else if josh_rebase {
+        // Make sure to rebase onto the current state of the upstream
+        if !fetch_refs_from_url(
+            &repo.path(),
+            &josh::from_ns(&base_ns),
+            &remote_url,
+            &[&baseref],
+            &username,
+            &Password {
+                value: password.to_owned(),
+            },
+        )? {
+            return Err(josh::josh_error("josh_rebase: fetch failed"));
+        }
+        let rev = format!("refs/josh/upstream/{}/{}", &base_ns, &baseref);
+        rebase_onto(&repo, &rev, backward_new_oid)?
     } else {
         backward_new_oid
     };
@@ -152,6 +174,72 @@ pub fn process_repo_update(
     );
 }
 
+// Replays the commits reachable from new but not from rev on top of rev,
+// keeping everything but parents and tree of the original commits.
+// Fails if any of them conflicts.
+fn rebase_onto(
+    repo: &git2::Repository,
+    rev: &str,
+    new: git2::Oid,
+) -> josh::JoshResult<git2::Oid> {
+    let base = if let Ok(Ok(base)) =
+        repo.revparse_single(&rev).map(|x| x.peel_to_commit())
+    {
+        base
+    } else {
+        return Err(josh::josh_error("josh_rebase failed"));
+    };
+
+    if base.id() == new || repo.graph_descendant_of(new, base.id())? {
+        return Ok(new);
+    }
+
+    let walk = {
+        let mut walk = repo.revwalk()?;
+        walk.set_sorting(git2::Sort::REVERSE | git2::Sort::TOPOLOGICAL)?;
+        walk.push(new)?;
+        walk.hide(base.id())?;
+        walk
+    };
+
+    let config = josh::scratch::RewriteConfig::from_repo(&repo);
+
+    let mut onto = base;
+    for rev in walk {
+        let commit = repo.find_commit(rev?)?;
+        tracing::trace!("josh_rebase: {} onto {}", commit.id(), onto.id());
+
+        if commit.parent_count() != 1 {
+            return Err(josh::josh_error(&format!(
+                "josh_rebase: can't rebase merge commit {}",
+                commit.id()
+            )));
+        }
+
+        let mut index = repo.cherrypick_commit(&commit, &onto, 0, None)?;
+        if index.has_conflicts() {
+            return Err(josh::josh_error(&format!(
+                "josh_rebase: conflict in commit {}: {}",
+                commit.id(),
+                josh::conflict_paths(&index)?.join(", ")
+            )));
+        }
+
+        let tree = repo.find_tree(index.write_tree_to(&repo)?)?;
+        let rebased = josh::scratch::rewrite_with_committer(
+            &repo,
+            &commit,
+            &[&onto],
+            &tree,
+            config.committer(&commit.committer().when())?.as_ref(),
+            &config,
+        )?;
+        onto = repo.find_commit(rebased)?;
+    }
+
+    return Ok(onto.id());
+}
+
 fn push_head_url(
     repo: &git2::Repository,
     oid: git2::Oid,
@@ -189,6 +277,54 @@ fn push_head_url(
     return Ok(stderr);
 }
 
+// Finds the upstream repo and ref of a review under refs/changes, the
+// latest patchset unless one is given. Only the refs fetched from
+// upstream_repo are searched if it is given.
+pub fn find_change(
+    path: &std::path::Path,
+    change: &str,
+    patchset: Option<&str>,
+    upstream_repo: Option<&str>,
+) -> josh::JoshResult<Option<(String, String)>> {
+    let repo = git2::Repository::open(&path)?;
+    let mut found: Option<(u64, String, String)> = None;
+    for reference in
+        repo.references_glob("refs/josh/upstream/*/refs/changes/*")?
+    {
+        let reference = reference?;
+        let name = reference.name().unwrap_or("");
+        let idx = josh::some_or!(name.find("/refs/changes/"), {
+            continue;
+        });
+        let (ns, refname) =
+            (&name["refs/josh/upstream/".len()..idx], &name[idx + 1..]);
+        let found_repo = format!("/{}", josh::from_ns(ns));
+        if upstream_repo.map(|x| x != found_repo).unwrap_or(false) {
+            continue;
+        }
+        let parts: Vec<_> = refname.split('/').collect();
+        if let [_, _, _, c, p] = parts.as_slice() {
+            if *c != change || patchset.map(|x| x != *p).unwrap_or(false) {
+                continue;
+            }
+            let p = p.parse::<u64>().unwrap_or(0);
+            if found.as_ref().map(|x| x.0 < p).unwrap_or(true) {
+                found = Some((p, found_repo, refname.to_owned()));
+            }
+        }
+    }
+    return Ok(
+        found.map(|(_, upstream_repo, refname)| (upstream_repo, refname))
+    );
+}
+
+// The refs of all patchsets of a change, in the layout used by Gerrit:
+// refs/changes/<last two digits>/<change>/<patchset>
+pub fn change_refs(change: &str) -> String {
+    let digits = &change[change.len().saturating_sub(2)..];
+    return format!("refs/changes/{:0>2}/{}/*", digits, change);
+}
+
 pub fn create_repo(path: &std::path::Path) -> josh::JoshResult<()> {
     tracing::debug!("init base repo: {:?}", path);
     std::fs::create_dir_all(path).expect("can't create_dir_all");
@@ -214,6 +350,140 @@ pub fn create_repo(path: &std::path::Path) -> josh::JoshResult<()> {
     return Ok(());
 }
 
+// Only writes keys whose value differs, so restarting with the same options
+// leaves the config file alone
+fn update_config(
+    config: &mut git2::Config,
+    key: &str,
+    value: Option<&str>,
+) -> josh::JoshResult<()> {
+    let current = config.snapshot()?.get_string(key).ok();
+    if current.as_deref() == value {
+        return Ok(());
+    }
+    if let Some(value) = value {
+        config.set_str(key, value)?;
+    } else {
+        config.remove(key)?;
+    }
+    return Ok(());
+}
+
+pub fn configure_signing(
+    path: &std::path::Path,
+    keep_signature: bool,
+    signing_key: Option<&str>,
+    signing_format: Option<&str>,
+) -> josh::JoshResult<()> {
+    let mut config = git2::Repository::open(path)?.config()?;
+    update_config(
+        &mut config,
+        "josh.keepSignature",
+        Some(if keep_signature { "true" } else { "false" }),
+    )?;
+    update_config(&mut config, "josh.signingKey", signing_key)?;
+    update_config(
+        &mut config,
+        "josh.signingFormat",
+        Some(signing_format.unwrap_or("openpgp")),
+    )?;
+    return Ok(());
+}
+
+pub fn configure_branches(
+    path: &std::path::Path,
+    branches: &[&str],
+) -> josh::JoshResult<()> {
+    let mut config = git2::Repository::init_bare(path)?.config()?;
+    config.remove_multivar("josh.branches", ".*").ok();
+    for branch in branches.iter() {
+        config.set_multivar("josh.branches", "^$", branch)?;
+    }
+    return Ok(());
+}
+
+pub fn configure_committer(
+    path: &std::path::Path,
+    committer_name: Option<&str>,
+    committer_email: Option<&str>,
+    merge_message: Option<&str>,
+) -> josh::JoshResult<()> {
+    let mut config = git2::Repository::open(path)?.config()?;
+    update_config(&mut config, "josh.committerName", committer_name)?;
+    update_config(&mut config, "josh.committerEmail", committer_email)?;
+    update_config(&mut config, "josh.mergeMessage", merge_message)?;
+    return Ok(());
+}
+
+fn url_with_username(url: &str, username: &str) -> String {
+    let splitted: Vec<&str> = url.splitn(2, "://").collect();
+    let proto = splitted[0];
+    let rest = splitted[1];
+    if username != "" {
+        format!("{}://{}@{}", &proto, &username, &rest)
+    } else {
+        format!("{}://{}@{}", &proto, "annonymous", &rest)
+    }
+}
+
+// True when the mirrored upstream HEAD is missing or points to a branch that
+// doesn't exist anymore, for example because the default branch was renamed
+pub fn upstream_head_is_stale(
+    path: &std::path::Path,
+    upstream_repo: &str,
+) -> bool {
+    let repo = josh::ok_or!(git2::Repository::open(&path), {
+        return true;
+    });
+    return repo
+        .find_reference(&format!(
+            "refs/josh/upstream/{}/HEAD",
+            josh::to_ns(upstream_repo)
+        ))
+        .and_then(|x| x.resolve())
+        .is_err();
+}
+
+// Mirrors the upstream HEAD as symbolic ref refs/josh/upstream/<repo>/HEAD,
+// so housekeeping knows the default branch of the upstream repo
+pub fn fetch_head_from_url(
+    path: &std::path::Path,
+    upstream_repo: &str,
+    url: &str,
+    username: &str,
+    password: &Password,
+) -> josh::JoshResult<()> {
+    let shell = josh::shell::Shell {
+        cwd: path.to_owned(),
+    };
+    let cmd = format!(
+        "git ls-remote --symref {} HEAD",
+        url_with_username(url, username)
+    );
+    let (stdout, stderr) =
+        shell.command_env(&cmd, &[("GIT_PASSWORD", &password.value)]);
+    tracing::debug!("fetch_head_from_url {:?} {:?}", cmd, stderr);
+
+    for line in stdout.lines() {
+        if !line.starts_with("ref: ") || !line.ends_with("\tHEAD") {
+            continue;
+        }
+        let target = line["ref: ".len()..line.len() - "\tHEAD".len()].trim();
+        let repo = git2::Repository::init_bare(&path)?;
+        repo.reference_symbolic(
+            &format!("refs/josh/upstream/{}/HEAD", josh::to_ns(upstream_repo)),
+            &format!(
+                "refs/josh/upstream/{}/{}",
+                josh::to_ns(upstream_repo),
+                target
+            ),
+            true,
+            "fetch_head_from_url",
+        )?;
+    }
+    return Ok(());
+}
+
 pub fn fetch_refs_from_url(
     path: &std::path::Path,
     upstream_repo: &str,
@@ -237,17 +507,7 @@ pub fn fetch_refs_from_url(
     let shell = josh::shell::Shell {
         cwd: path.to_owned(),
     };
-    let nurl = if username != "" {
-        let splitted: Vec<&str> = url.splitn(2, "://").collect();
-        let proto = splitted[0];
-        let rest = splitted[1];
-        format!("{}://{}@{}", &proto, &username, &rest)
-    } else {
-        let splitted: Vec<&str> = url.splitn(2, "://").collect();
-        let proto = splitted[0];
-        let rest = splitted[1];
-        format!("{}://{}@{}", &proto, "annonymous", &rest)
-    };
+    let nurl = url_with_username(url, username);
 
     let cmd = format!("git fetch --no-tags {} {}", &nurl, &specs.join(" "));
     tracing::info!("fetch_refs_from_url {:?} {:?} {:?}", cmd, path, "");
@@ -292,10 +552,19 @@ pub struct TmpGitNamespace {
     repo_path: std::path::PathBuf,
 }
 
+fn now_secs() -> u64 {
+    return std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0);
+}
+
 impl TmpGitNamespace {
     pub fn new(repo_path: &std::path::Path) -> TmpGitNamespace {
         TmpGitNamespace {
-            name: format!("request_{}", uuid::Uuid::new_v4()),
+            // The creation time is part of the name so leaked namespaces
+            // can be recognized by sweep_tmp_namespaces
+            name: format!("request_{}_{}", now_secs(), uuid::Uuid::new_v4()),
             repo_path: repo_path.to_owned(),
         }
     }
@@ -322,10 +591,154 @@ impl Drop for TmpGitNamespace {
         if std::env::var_os("JOSH_KEEP_NS") != None {
             return;
         }
-        let request_tmp_namespace =
-            self.repo_path.join("refs/namespaces").join(&self.name);
-        std::fs::remove_dir_all(request_tmp_namespace).unwrap_or_else(|e| {
-            tracing::warn!("remove_dir_all failed: {:?}", e)
+        remove_tmp_namespace(&self.repo_path, &self.name).unwrap_or_else(|e| {
+            tracing::warn!("remove_tmp_namespace failed: {:?}", e);
+            0
+        });
+    }
+}
+
+// Deletes all refs of the namespace through the refs API, so packed refs
+// are removed as well. Returns the number of refs deleted.
+fn remove_tmp_namespace(
+    repo_path: &std::path::Path,
+    name: &str,
+) -> josh::JoshResult<usize> {
+    let repo = git2::Repository::open(repo_path)?;
+    let mut deleted = 0;
+    for reference in
+        repo.references_glob(&format!("refs/namespaces/{}/*", name))?
+    {
+        reference?.delete()?;
+        deleted += 1;
+    }
+    let dir = repo_path.join("refs/namespaces").join(name);
+    if dir.exists() {
+        std::fs::remove_dir_all(dir)?;
+    }
+    return Ok(deleted);
+}
+
+// Age in seconds of a namespace created by TmpGitNamespace::new, namespaces
+// created before the time was part of the name are considered infinitely old
+fn tmp_namespace_age(name: &str, now: u64) -> Option<u64> {
+    if !name.starts_with("request_") {
+        return None;
+    }
+    let created = name["request_".len()..]
+        .splitn(2, '_')
+        .next()
+        .and_then(|x| x.parse::<u64>().ok())
+        .unwrap_or(0);
+    return Some(now.saturating_sub(created));
+}
+
+// Removes request namespaces left behind by requests that did not finish,
+// for example because the proxy was killed. Namespaces younger than grace
+// are kept as they may belong to requests still in flight.
+pub fn sweep_tmp_namespaces(
+    repo_path: &std::path::Path,
+    grace: std::time::Duration,
+) -> josh::JoshResult<usize> {
+    let repo = git2::Repository::open(repo_path)?;
+    let now = now_secs();
+    let mut stale = std::collections::BTreeSet::new();
+    for reference in repo.references_glob("refs/namespaces/request_*")? {
+        let reference = reference?;
+        let name = reference
+            .name()
+            .unwrap_or("")
+            .splitn(4, '/')
+            .nth(2)
+            .unwrap_or("")
+            .to_owned();
+        if let Some(age) = tmp_namespace_age(&name, now) {
+            if age >= grace.as_secs() {
+                stale.insert(name);
+            }
+        }
+    }
+
+    let mut deleted = 0;
+    for name in stale.iter() {
+        deleted += remove_tmp_namespace(&repo_path, &name)?;
+    }
+    if !stale.is_empty() {
+        tracing::info!(
+            "removed {} stale namespaces ({} refs)",
+            stale.len(),
+            deleted
+        );
+    }
+    return Ok(stale.len());
+}
+
+pub fn spawn_namespace_sweeper(
+    repo_path: std::path::PathBuf,
+    grace: std::time::Duration,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(std::time::Duration::from_secs(60));
+        sweep_tmp_namespaces(&repo_path, grace).unwrap_or_else(|e| {
+            tracing::warn!("sweep_tmp_namespaces failed: {:?}", e);
+            0
         });
+    })
+}
+
+// First line of the upload-pack response when there is nothing in common
+pub const NAK: &str = "0008NAK\n";
+
+// Splits a request body into pkt-lines, flush and delimiter packets
+// are skipped
+fn pkt_lines(mut data: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut lines = vec![];
+    while data.len() >= 4 {
+        let len =
+            usize::from_str_radix(std::str::from_utf8(&data[..4]).ok()?, 16)
+                .ok()?;
+        if len < 4 {
+            data = &data[4..];
+            continue;
+        }
+        if len > data.len() {
+            return None;
+        }
+        lines.push(&data[4..len]);
+        data = &data[len..];
     }
+    return Some(lines);
+}
+
+// True for upload-pack requests (protocol v0) that upload-pack answers with
+// a plain NAK followed by the pack multiplexed with side-band: requests with
+// "done" but without "have" or shallow lines, like the ones of a clone.
+pub fn upload_pack_starts_with_nak(body: &[u8]) -> bool {
+    let lines = josh::some_or!(pkt_lines(&body), {
+        return false;
+    });
+    let sideband = lines
+        .first()
+        .map(|x| {
+            String::from_utf8_lossy(x)
+                .split_whitespace()
+                .any(|x| x == "side-band" || x == "side-band-64k")
+        })
+        .unwrap_or(false);
+    let done = lines.iter().any(|x| x == b"done\n");
+    let negotiating = lines.iter().any(|x| {
+        x.starts_with(b"have ")
+            || x.starts_with(b"shallow ")
+            || x.starts_with(b"deepen")
+    });
+    return sideband && done && !negotiating;
+}
+
+// Encodes data as pkt-line on the given side-band channel, 1 is pack data,
+// 2 progress and 3 a fatal error
+pub fn sideband(channel: u8, data: &str) -> Vec<u8> {
+    let mut line = format!("{:04x}", data.len() + 5).into_bytes();
+    line.push(channel);
+    line.extend(data.as_bytes());
+    return line;
 }
@@ -1,24 +1,834 @@
 /* #![deny(warnings)] */
 #![warn(unused_extern_crates)]
 
-#[macro_use]
-extern crate lazy_static;
-
 use std::sync::{Arc, RwLock};
 
-use std::fs::read_to_string;
+type Maps = Arc<RwLock<josh::filter_cache::FilterCache>>;
+
+const SUBCOMMANDS: &[&str] =
+    &["filter", "unapply", "query", "permissions", "cache", "info"];
+
+fn progress_bar(filter_spec: &str, walked: usize, total: usize) {
+    let step = std::cmp::max(total / 100, 1);
+    if walked % step != 0 && walked != total {
+        return;
+    }
+    let width = 30;
+    let done = walked * width / std::cmp::max(total, 1);
+    eprint!(
+        "\r[{}{}] {}/{} {}",
+        "#".repeat(done),
+        " ".repeat(width - done),
+        walked,
+        total,
+        filter_spec
+    );
+    if walked == total {
+        eprintln!();
+    }
+}
+
+// One ref to filter, from the positional arguments or a section of --file
+struct Job {
+    src: String,
+    target: String,
+    spec: String,
+}
+
+// Parses the --file format: sections starting with "[src:target]" at the
+// beginning of a line, followed by the filter spec, which can span multiple
+// lines
+fn parse_file(content: &str) -> josh::JoshResult<Vec<Job>> {
+    let mut jobs: Vec<Job> = vec![];
+    for (n, line) in content.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            let end = josh::some_or!(trimmed.find(']'), {
+                return Err(josh::josh_error(&format!(
+                    "line {}: missing \"]\"",
+                    n + 1
+                )));
+            });
+            let from_to = &trimmed[1..end];
+            let mut splitted = from_to.splitn(2, ':');
+            let src = splitted.next().unwrap_or("").trim();
+            let target = josh::some_or!(splitted.next(), {
+                return Err(josh::josh_error(&format!(
+                    "line {}: from_to must contain \":\"",
+                    n + 1
+                )));
+            });
+            jobs.push(Job {
+                src: src.to_owned(),
+                target: target.trim().to_owned(),
+                spec: format!("{}\n", &trimmed[end + 1..]),
+            });
+        } else if let Some(job) = jobs.last_mut() {
+            job.spec.push_str(line);
+            job.spec.push('\n');
+        } else if trimmed != "" {
+            return Err(josh::josh_error(&format!(
+                "line {}: filter spec outside of a section",
+                n + 1
+            )));
+        }
+    }
+    for job in jobs.iter_mut() {
+        job.spec = job.spec.trim().to_owned();
+    }
+    return Ok(jobs);
+}
+
+struct Options {
+    infofile: bool,
+    squash: bool,
+    check_permissions: bool,
+}
+
+fn build_filter(
+    job: &Job,
+    options: &Options,
+) -> Box<dyn josh::filters::Filter> {
+    let mut filterobj = josh::filters::parse(&job.spec);
+
+    if options.infofile {
+        for (p, v) in filterobj.prefixes().iter() {
+            filterobj = josh::build_chain(
+                filterobj,
+                josh::filters::parse(&format!(
+                    ":info={},commit=#sha1,tree=#tree,src={},filter={}",
+                    p,
+                    &job.src,
+                    v.replace(":", "<colon>").replace(",", "<comma>")
+                )),
+            );
+        }
+    }
+
+    if options.squash {
+        filterobj = josh::build_chain(
+            josh::filters::parse(&format!(":cutoff={}", &job.src)),
+            filterobj,
+        );
+    }
+
+    if options.check_permissions {
+        filterobj = josh::build_chain(josh::filters::parse(":DIRS"), filterobj);
+        filterobj = josh::build_chain(filterobj, josh::filters::parse(":FOLD"));
+    }
+
+    return filterobj;
+}
+
+fn load_maps(repo: &git2::Repository, progress: bool) -> (Maps, Maps) {
+    let forward_maps = Arc::new(RwLock::new(josh::filter_cache::try_load(
+        &repo.path().join("josh_forward_maps"),
+    )));
+    let backward_maps = Arc::new(RwLock::new(josh::filter_cache::try_load(
+        &repo.path().join("josh_backward_maps"),
+    )));
+
+    let config = Arc::new(josh::scratch::RewriteConfig::from_repo(&repo));
+    forward_maps
+        .write()
+        .unwrap()
+        .set_rewrite_config(config.clone());
+    backward_maps.write().unwrap().set_rewrite_config(config);
+
+    if progress {
+        forward_maps.write().unwrap().set_progress(Arc::new(
+            josh::progress::Progress::new(
+                Some(Box::new(progress_bar)),
+                josh::progress::CancellationToken::new(),
+            ),
+        ));
+    }
+    return (forward_maps, backward_maps);
+}
+
+fn persist_maps(
+    repo: &git2::Repository,
+    forward_maps: &Maps,
+    backward_maps: &Maps,
+) {
+    josh::filter_cache::persist(
+        &backward_maps.read().unwrap(),
+        &repo.path().join("josh_backward_maps"),
+    )
+    .ok();
+    josh::filter_cache::persist(
+        &forward_maps.read().unwrap(),
+        &repo.path().join("josh_forward_maps"),
+    )
+    .ok();
+}
+
+// Full name of the ref given as src, for example "refs/heads/master" for
+// "master" or "HEAD"
+fn resolve_src(repo: &git2::Repository, src: &str) -> josh::JoshResult<String> {
+    return Ok(repo
+        .revparse_ext(&src)?
+        .1
+        .ok_or(josh::josh_error("reference not found"))?
+        .name()
+        .unwrap()
+        .to_string());
+}
+
+// Filters the ref src into target and returns the filtered commit, zero if
+// the filter didn't produce a commit
+fn filter_ref(
+    repo: &git2::Repository,
+    filterobj: &dyn josh::filters::Filter,
+    src: &str,
+    target: &str,
+    forward_maps: &Maps,
+    backward_maps: &Maps,
+) -> josh::JoshResult<git2::Oid> {
+    josh::apply_filter_to_refs(
+        &repo,
+        &*filterobj,
+        &[(src.to_owned(), target.to_owned())],
+        &mut forward_maps.write().unwrap(),
+        &mut backward_maps.write().unwrap(),
+    )?;
+    return Ok(repo.refname_to_id(&target).unwrap_or(git2::Oid::zero()));
+}
+
+fn status_name(status: &josh::scratch::RefUpdateStatus) -> &str {
+    return match status {
+        josh::scratch::RefUpdateStatus::Updated => "updated",
+        josh::scratch::RefUpdateStatus::Unchanged => "unchanged",
+        josh::scratch::RefUpdateStatus::Empty => "empty",
+        josh::scratch::RefUpdateStatus::SourceMissing => "source-missing",
+    };
+}
+
+// Filters the refs of a job. A src containing "*" selects all matching
+// refs, stored in target with its "*" replaced by the part of the ref the
+// glob matched. With prune targets whose source is gone are deleted.
+fn filter_job(
+    repo: &git2::Repository,
+    job: &Job,
+    options: &Options,
+    prune: bool,
+    forward_maps: &Maps,
+    backward_maps: &Maps,
+) -> josh::JoshResult<Vec<serde_json::Value>> {
+    let glob = job.src.contains('*');
+    if prune && !glob {
+        return Err(josh::josh_error("--prune needs a glob as input_ref"));
+    }
+    let from_to = if glob {
+        josh::housekeeping::glob_from_to(&repo, &job.src, &job.target)?
+    } else {
+        vec![(resolve_src(&repo, &job.src)?, job.target.clone())]
+    };
+
+    // --squash and --infofile make the filter depend on the source, refs
+    // that end up with the same filter are filtered together
+    let mut groups = std::collections::BTreeMap::new();
+    for (src, target) in from_to.into_iter() {
+        let filterobj = build_filter(
+            &Job {
+                src: if glob { src.clone() } else { job.src.clone() },
+                target: target.clone(),
+                spec: job.spec.clone(),
+            },
+            &options,
+        );
+        groups
+            .entry(filterobj.filter_spec())
+            .or_insert((filterobj, vec![]))
+            .1
+            .push((src, target));
+    }
+
+    let mut results = vec![];
+    for (filter_spec, (filterobj, refs)) in groups.iter() {
+        let updates = josh::apply_filter_to_refs(
+            &repo,
+            &**filterobj,
+            &refs,
+            &mut forward_maps.write().unwrap(),
+            &mut backward_maps.write().unwrap(),
+        )?;
+        for u in updates.iter() {
+            results.push(serde_json::json!({
+                "src": u.from_refname,
+                "target": u.to_refname,
+                "filter": filter_spec,
+                "commit": format!("{}", u.new),
+                "status": status_name(&u.status),
+            }));
+        }
+    }
+
+    if prune {
+        let filter_spec = josh::filters::parse(&job.spec).filter_spec();
+        for (src, target) in
+            josh::housekeeping::stale_targets(&repo, &job.src, &job.target)?
+        {
+            repo.find_reference(&target)?.delete()?;
+            results.push(serde_json::json!({
+                "src": src,
+                "target": target,
+                "filter": filter_spec,
+                "commit": format!("{}", git2::Oid::zero()),
+                "status": "deleted",
+            }));
+        }
+    }
+    return Ok(results);
+}
+
+// Original to filtered commit for the history of src, dropped commits only
+// with include_dropped
+fn history_map(
+    repo: &git2::Repository,
+    src: &str,
+    filter_spec: &str,
+    include_dropped: bool,
+    forward_maps: &Maps,
+) -> josh::JoshResult<Vec<josh::filter_cache::MapEntry>> {
+    let head = repo.revparse_single(&src)?.peel_to_commit()?.id();
+    let mut entries = vec![];
+    for entry in
+        forward_maps
+            .read()
+            .unwrap()
+            .history(&repo, &filter_spec, head)?
+    {
+        let entry = entry?;
+        if include_dropped || entry.dropped.is_none() {
+            entries.push(entry);
+        }
+    }
+    return Ok(entries);
+}
+
+fn print_map(entries: &[josh::filter_cache::MapEntry]) {
+    for e in entries.iter() {
+        if let Some(reason) = e.dropped {
+            println!("{}\t{}\t{}", e.original, e.filtered, reason.as_str());
+        } else {
+            println!("{}\t{}", e.original, e.filtered);
+        }
+    }
+}
+
+fn map_json(entries: &[josh::filter_cache::MapEntry]) -> serde_json::Value {
+    return serde_json::json!(entries
+        .iter()
+        .map(|e| serde_json::json!({
+            "original": format!("{}", e.original),
+            "filtered": format!("{}", e.filtered),
+            "dropped": e.dropped.map(|x| x.as_str()),
+        }))
+        .collect::<Vec<_>>());
+}
+
+// Prints the map of every ref filtered by filter_job, or adds it to the
+// results for json
+fn add_maps(
+    repo: &git2::Repository,
+    results: &mut Vec<serde_json::Value>,
+    include_dropped: bool,
+    json: bool,
+    forward_maps: &Maps,
+) -> josh::JoshResult<()> {
+    for result in results.iter_mut() {
+        if result["status"] == "deleted" {
+            continue;
+        }
+        let entries = history_map(
+            &repo,
+            result["src"].as_str().unwrap_or(""),
+            result["filter"].as_str().unwrap_or(""),
+            include_dropped,
+            &forward_maps,
+        )?;
+        if json {
+            result["map"] = map_json(&entries);
+        } else {
+            print_map(&entries);
+        }
+    }
+    return Ok(());
+}
+
+fn print_permissions(check: &josh::housekeeping::PermissionCheck, json: bool) {
+    if json {
+        print_json(&serde_json::json!(check));
+    } else {
+        for d in check.missing.iter() {
+            println!("missing permission for: {}", &d);
+        }
+        println!("Allowed = {:?}", check.allowed);
+    }
+}
+
+fn render_query(
+    repo: &git2::Repository,
+    target: &str,
+    query: &str,
+    forward_maps: &Maps,
+    backward_maps: &Maps,
+) -> josh::JoshResult<String> {
+    let kv_store = Arc::new(RwLock::new(josh::kv_store::KvStore::load(
+        &repo.path().join("josh_kv.json"),
+    )?));
+    return josh::query::render(
+        &repo,
+        &target,
+        &query,
+        kv_store,
+        josh::kv_store::GLOBAL,
+        forward_maps.clone(),
+        backward_maps.clone(),
+        &josh::query::QueryLimits::default(),
+    );
+}
+
+// Applies the changes made on the filtered ref target back onto src. The
+// original history is filtered into refs/JOSH_TMP first, to find the
+// commits that are new in target.
+fn unapply(
+    repo: &git2::Repository,
+    filterobj: &dyn josh::filters::Filter,
+    src: &str,
+    target: &str,
+    forward_maps: &Maps,
+    backward_maps: &Maps,
+) -> josh::JoshResult<josh::UnapplyFilter> {
+    filter_ref(
+        &repo,
+        &*filterobj,
+        &src,
+        "refs/JOSH_TMP",
+        &forward_maps,
+        &backward_maps,
+    )?;
+    let new = repo.revparse_single(&target)?.id();
+    let old = repo.revparse_single("JOSH_TMP")?.id();
+
+    let result = josh::unapply_filter(
+        &repo,
+        backward_maps.clone(),
+        &*filterobj,
+        old,
+        new,
+    )?;
+    if let josh::UnapplyFilter::Done(rewritten) = result {
+        repo.reference(&src, rewritten, true, "unapply_filter")?;
+    }
+    return Ok(result);
+}
+
+fn unapply_result(result: &josh::UnapplyFilter) -> (&str, Option<String>) {
+    return match result {
+        josh::UnapplyFilter::Done(rewritten) => {
+            ("done", Some(format!("{}", rewritten)))
+        }
+        josh::UnapplyFilter::RejectMerge(parents) => (
+            "reject-merge",
+            Some(format!(
+                "merges with {} parents can't be unapplied",
+                parents
+            )),
+        ),
+        josh::UnapplyFilter::BranchDoesNotExist => {
+            ("branch-does-not-exist", None)
+        }
+    };
+}
+
+fn print_json(value: &serde_json::Value) {
+    println!("{}", serde_json::to_string_pretty(&value).unwrap());
+}
 
-lazy_static! {
-    static ref FILE_REGEX: regex::Regex =
-        regex::Regex::new(r"\[(?P<src>.*)\](?P<spec>[^\[]*)")
-            .expect("can't compile regex");
-    static ref STR_REGEX: regex::Regex =
-        regex::Regex::new(r"(?P<src>[^:]*)(?P<spec>:[^\[]*)")
-            .expect("can't compile regex");
+// Arguments of the subcommands that filter a single ref. The ref and spec
+// are optional for "filter", which can read them from --file instead.
+fn job_args<'a, 'b>(
+    app: clap::App<'a, 'b>,
+    required: bool,
+) -> clap::App<'a, 'b> {
+    return app
+        .arg(
+            clap::Arg::with_name("input_ref")
+                .required(required)
+                .help("Ref to filter, for example HEAD or master"),
+        )
+        .arg(
+            clap::Arg::with_name("spec")
+                .required(required)
+                .help("Filter spec, for example :/sub1"),
+        )
+        .arg(
+            clap::Arg::with_name("update")
+                .long("update")
+                .takes_value(true)
+                .help(
+                    "Ref to store the filtered history in \
+                    (default: refs/JOSH_HEAD)",
+                ),
+        )
+        .arg(
+            clap::Arg::with_name("squash")
+                .long("squash")
+                .help("Only keep the tip of the filtered history"),
+        )
+        .arg(
+            clap::Arg::with_name("infofile")
+                .long("infofile")
+                .help("Add .joshinfo files naming the source of each prefix"),
+        )
+        .arg(clap::Arg::with_name("progress").long("progress"))
+        .arg(clap::Arg::with_name("json").long("json"));
 }
 
-fn run_filter(args: Vec<String>) -> josh::JoshResult<i32> {
-    let args = clap::App::new("josh-filter")
+fn app<'a, 'b>() -> clap::App<'a, 'b> {
+    return clap::App::new("josh-filter")
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            job_args(
+                clap::SubCommand::with_name("filter")
+                    .about("Filter refs, given as arguments or in a --file"),
+                false,
+            )
+            .arg(
+                clap::Arg::with_name("file")
+                    .long("file")
+                    .takes_value(true)
+                    .help(
+                        "File with sections of \"[src:target]\" followed \
+                        by a filter spec",
+                    ),
+            )
+            .arg(clap::Arg::with_name("prune").long("prune").help(
+                "With a glob as input_ref, delete targets whose source \
+                ref doesn't exist anymore",
+            ))
+            .arg(
+                clap::Arg::with_name("print-map")
+                    .long("print-map")
+                    .help("Print the filtered commit of every original commit"),
+            )
+            .arg(
+                clap::Arg::with_name("include-dropped")
+                    .long("include-dropped")
+                    .help(
+                        "With --print-map, also print commits without a \
+                        filtered commit of their own and why",
+                    ),
+            ),
+        )
+        .subcommand(job_args(
+            clap::SubCommand::with_name("unapply").about(
+                "Apply the changes made on the filtered ref back onto \
+                input_ref",
+            ),
+            true,
+        ))
+        .subcommand(
+            job_args(
+                clap::SubCommand::with_name("query").about(
+                    "Filter input_ref and run a query like get=<path> or \
+                    render=<path> on the result",
+                ),
+                true,
+            )
+            .arg(clap::Arg::with_name("query").required(true)),
+        )
+        .subcommand(
+            job_args(
+                clap::SubCommand::with_name("permissions").about(
+                    "Check that all original directories in the view \
+                    match a regex",
+                ),
+                true,
+            )
+            .arg(clap::Arg::with_name("regex").required(true)),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("cache")
+                .about("Show the number of cached commits per filter")
+                .arg(
+                    clap::Arg::with_name("clear")
+                        .long("clear")
+                        .help("Remove the cache files"),
+                )
+                .arg(clap::Arg::with_name("json").long("json")),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("info")
+                .about(
+                    "Show the filtered commit of a revision and translate \
+                    paths",
+                )
+                .arg(clap::Arg::with_name("rev").required(true))
+                .arg(clap::Arg::with_name("spec").required(true))
+                .arg(
+                    clap::Arg::with_name("path")
+                        .long("path")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(clap::Arg::with_name("json").long("json")),
+        );
+}
+
+fn options(args: &clap::ArgMatches) -> Options {
+    return Options {
+        infofile: args.is_present("infofile"),
+        squash: args.is_present("squash"),
+        check_permissions: false,
+    };
+}
+
+fn positional_job(args: &clap::ArgMatches) -> Job {
+    return Job {
+        src: args.value_of("input_ref").unwrap_or("").to_owned(),
+        target: args
+            .value_of("update")
+            .unwrap_or("refs/JOSH_HEAD")
+            .to_owned(),
+        spec: args.value_of("spec").unwrap_or("").to_owned(),
+    };
+}
+
+fn cmd_filter(args: &clap::ArgMatches) -> josh::JoshResult<i32> {
+    let jobs = if let Some(file) = args.value_of("file") {
+        parse_file(&std::fs::read_to_string(file).map_err(|e| {
+            josh::josh_error(&format!("cannot read {}: {}", file, e))
+        })?)?
+    } else if args.is_present("spec") {
+        vec![positional_job(&args)]
+    } else {
+        return Err(josh::josh_error("missing input_ref and spec or --file"));
+    };
+
+    let repo = git2::Repository::open_from_env()?;
+    let (forward_maps, backward_maps) =
+        load_maps(&repo, args.is_present("progress"));
+    let options = options(&args);
+
+    let json = args.is_present("json");
+    let mut results = vec![];
+    for job in jobs.iter() {
+        results.append(&mut filter_job(
+            &repo,
+            &job,
+            &options,
+            args.is_present("prune"),
+            &forward_maps,
+            &backward_maps,
+        )?);
+    }
+
+    if args.is_present("print-map") {
+        add_maps(
+            &repo,
+            &mut results,
+            args.is_present("include-dropped"),
+            json,
+            &forward_maps,
+        )?;
+    }
+    persist_maps(&repo, &forward_maps, &backward_maps);
+
+    if json {
+        print_json(&serde_json::json!(results));
+    }
+    return Ok(0);
+}
+
+fn cmd_unapply(args: &clap::ArgMatches) -> josh::JoshResult<i32> {
+    let job = positional_job(&args);
+    let repo = git2::Repository::open_from_env()?;
+    let (forward_maps, backward_maps) =
+        load_maps(&repo, args.is_present("progress"));
+    let filterobj = build_filter(&job, &options(&args));
+    let src = resolve_src(&repo, &job.src)?;
+
+    let result = unapply(
+        &repo,
+        &*filterobj,
+        &src,
+        &job.target,
+        &forward_maps,
+        &backward_maps,
+    )?;
+    persist_maps(&repo, &forward_maps, &backward_maps);
+
+    let (status, detail) = unapply_result(&result);
+    if args.is_present("json") {
+        print_json(&serde_json::json!({
+            "src": src,
+            "target": job.target,
+            "result": status,
+            "detail": detail,
+        }));
+    } else if status != "done" {
+        println!("{}: {}", status, detail.unwrap_or("".to_owned()));
+    }
+    return Ok(if status == "done" { 0 } else { 1 });
+}
+
+fn cmd_query(args: &clap::ArgMatches) -> josh::JoshResult<i32> {
+    let job = positional_job(&args);
+    let repo = git2::Repository::open_from_env()?;
+    let (forward_maps, backward_maps) =
+        load_maps(&repo, args.is_present("progress"));
+    let filterobj = build_filter(&job, &options(&args));
+    let src = resolve_src(&repo, &job.src)?;
+
+    filter_ref(
+        &repo,
+        &*filterobj,
+        &src,
+        &job.target,
+        &forward_maps,
+        &backward_maps,
+    )?;
+    let query = args.value_of("query").unwrap_or("");
+    let result = render_query(
+        &repo,
+        &job.target,
+        &query,
+        &forward_maps,
+        &backward_maps,
+    )?;
+    persist_maps(&repo, &forward_maps, &backward_maps);
+
+    if args.is_present("json") {
+        print_json(&serde_json::json!({
+            "query": query,
+            "result": result,
+        }));
+    } else {
+        print!("{}", result);
+    }
+    return Ok(0);
+}
+
+fn cmd_permissions(args: &clap::ArgMatches) -> josh::JoshResult<i32> {
+    let job = positional_job(&args);
+    let repo = git2::Repository::open_from_env()?;
+    let (forward_maps, backward_maps) =
+        load_maps(&repo, args.is_present("progress"));
+    let filterobj = build_filter(&job, &options(&args));
+    let regex = regex::Regex::new(args.value_of("regex").unwrap_or(""))?;
+
+    let check = josh::housekeeping::check_permissions(
+        &repo,
+        &*filterobj,
+        repo.revparse_single(&job.src)?.peel_to_commit()?.id(),
+        &regex,
+        forward_maps.clone(),
+        backward_maps.clone(),
+    )?;
+    persist_maps(&repo, &forward_maps, &backward_maps);
+
+    print_permissions(&check, args.is_present("json"));
+    return Ok(if check.allowed { 0 } else { 1 });
+}
+
+fn cmd_cache(args: &clap::ArgMatches) -> josh::JoshResult<i32> {
+    let repo = git2::Repository::open_from_env()?;
+    if args.is_present("clear") {
+        for name in ["josh_forward_maps", "josh_backward_maps"].iter() {
+            let path = repo.path().join(name);
+            if path.exists() {
+                std::fs::remove_file(&path)?;
+            }
+        }
+        return Ok(0);
+    }
+
+    let (forward_maps, _) = load_maps(&repo, false);
+    let stats: std::collections::BTreeMap<_, _> =
+        forward_maps.read().unwrap().stats().into_iter().collect();
+    if args.is_present("json") {
+        print_json(&serde_json::json!(stats));
+    } else {
+        for (filter_spec, count) in stats.iter() {
+            println!("{}\t{}", count, filter_spec);
+        }
+    }
+    return Ok(0);
+}
+
+fn cmd_info(args: &clap::ArgMatches) -> josh::JoshResult<i32> {
+    let repo = git2::Repository::open_from_env()?;
+    let (forward_maps, backward_maps) = load_maps(&repo, false);
+    let filterobj = josh::filters::parse(args.value_of("spec").unwrap_or(""));
+    let commit = repo
+        .revparse_single(args.value_of("rev").unwrap_or(""))?
+        .peel_to_commit()?;
+    let paths: Vec<String> = args
+        .values_of("path")
+        .map(|x| x.map(|x| x.to_owned()).collect())
+        .unwrap_or(vec![]);
+
+    let info = josh::housekeeping::commit_info(
+        &repo,
+        &*filterobj,
+        &commit,
+        &paths,
+        forward_maps,
+        backward_maps,
+    )?;
+
+    if args.is_present("json") {
+        print_json(&info);
+    } else {
+        println!("filter: {}", info["filter"].as_str().unwrap_or(""));
+        println!(
+            "original: {}",
+            info["original"]["commit"].as_str().unwrap_or("")
+        );
+        println!(
+            "transformed: {}",
+            info["transformed"]["commit"].as_str().unwrap_or("")
+        );
+        for p in info["paths"].as_array().unwrap_or(&vec![]).iter() {
+            let or_none = |x: &serde_json::Value| {
+                x.as_str().unwrap_or("(none)").to_owned()
+            };
+            if let Some(error) = p["error"].as_str() {
+                println!("path {}: error: {}", or_none(&p["path"]), error);
+                continue;
+            }
+            println!(
+                "path {}: original {}, filtered {}",
+                or_none(&p["path"]),
+                or_none(&p["original"]),
+                or_none(&p["filtered"])
+            );
+        }
+    }
+    return Ok(0);
+}
+
+fn run_subcommand(args: Vec<String>) -> josh::JoshResult<i32> {
+    let matches = app().get_matches_from(args);
+    return match matches.subcommand() {
+        ("filter", Some(args)) => cmd_filter(&args),
+        ("unapply", Some(args)) => cmd_unapply(&args),
+        ("query", Some(args)) => cmd_query(&args),
+        ("permissions", Some(args)) => cmd_permissions(&args),
+        ("cache", Some(args)) => cmd_cache(&args),
+        ("info", Some(args)) => cmd_info(&args),
+        _ => Err(josh::josh_error("unknown subcommand")),
+    };
+}
+
+// The form used before there were subcommands, where all operations are
+// selected with flags
+fn legacy_app<'a, 'b>() -> clap::App<'a, 'b> {
+    return clap::App::new("josh-filter")
         .arg(clap::Arg::with_name("input_ref").takes_value(true))
         .arg(clap::Arg::with_name("spec").takes_value(true))
         .arg(clap::Arg::with_name("file").long("file").takes_value(true))
@@ -28,6 +838,9 @@ fn run_filter(args: Vec<String>) -> josh::JoshResult<i32> {
                 .takes_value(true),
         )
         .arg(clap::Arg::with_name("squash").long("squash"))
+        .arg(clap::Arg::with_name("prune").long("prune"))
+        .arg(clap::Arg::with_name("print-map").long("print-map"))
+        .arg(clap::Arg::with_name("include-dropped").long("include-dropped"))
         .arg(
             clap::Arg::with_name("query")
                 .long("query")
@@ -41,13 +854,36 @@ fn run_filter(args: Vec<String>) -> josh::JoshResult<i32> {
                 .takes_value(true),
         )
         .arg(clap::Arg::with_name("infofile").long("infofile"))
+        .arg(clap::Arg::with_name("progress").long("progress"))
         .arg(clap::Arg::with_name("version").long("version"))
         .arg(
             clap::Arg::with_name("trace")
                 .long("trace")
                 .takes_value(true),
-        )
-        .get_matches_from(args);
+        );
+}
+
+// A first argument named like a subcommand can still be the input ref of
+// the legacy form, as in "josh-filter info :/sub1" with a branch "info".
+// That is the case when the arguments are valid for the legacy form with a
+// filter spec and the name resolves in the repo.
+fn is_legacy_form(args: &[String]) -> bool {
+    let matches = josh::ok_or!(legacy_app().get_matches_from_safe(args), {
+        return false;
+    });
+    if !matches.value_of("spec").unwrap_or("").starts_with(':') {
+        return false;
+    }
+    let input_ref = josh::some_or!(matches.value_of("input_ref"), {
+        return false;
+    });
+    return git2::Repository::open_from_env()
+        .map(|repo| repo.revparse_single(&input_ref).is_ok())
+        .unwrap_or(false);
+}
+
+fn run_legacy(args: Vec<String>) -> josh::JoshResult<i32> {
+    let args = legacy_app().get_matches_from(args);
 
     if args.is_present("version") {
         let v = option_env!("GIT_DESCRIBE")
@@ -56,188 +892,124 @@ fn run_filter(args: Vec<String>) -> josh::JoshResult<i32> {
         return Ok(0);
     }
 
+    let jobs = if let Some(file) = args.value_of("file") {
+        parse_file(&std::fs::read_to_string(file).map_err(|e| {
+            josh::josh_error(&format!("cannot read {}: {}", file, e))
+        })?)?
+    } else {
+        vec![positional_job(&args)]
+    };
+
     let repo = git2::Repository::open_from_env()?;
-    let forward_maps = Arc::new(RwLock::new(josh::filter_cache::try_load(
-        &repo.path().join("josh_forward_maps"),
-    )));
-    let backward_maps = Arc::new(RwLock::new(josh::filter_cache::try_load(
-        &repo.path().join("josh_backward_maps"),
-    )));
+    let (forward_maps, backward_maps) =
+        load_maps(&repo, args.is_present("progress"));
+
+    let reverse = args.is_present("reverse");
+    let options = Options {
+        check_permissions: args.is_present("check-permission"),
+        ..options(&args)
+    };
 
-    let input_ref = args.value_of("input_ref").unwrap_or("");
-    let specstr = args.value_of("spec").unwrap_or("");
-    let update_target = args.value_of("update").unwrap_or("refs/JOSH_HEAD");
-    let srcstr = format!("{}:{}", input_ref, update_target);
-
-    let filestr = args
-        .value_of("file")
-        .and_then(|f| read_to_string(f).ok())
-        .unwrap_or(format!("[{}]{}", srcstr, specstr));
-
-    for caps in FILE_REGEX.captures_iter(&filestr) {
-        let from_to = caps.name("src").unwrap().as_str().trim().to_owned();
-        let mut splitted = from_to.splitn(2, ":");
-
-        let src = splitted
-            .next()
-            .ok_or(josh::josh_error("from_to must contain \":\""))?
-            .to_owned();
-        let target = splitted
-            .next()
-            .ok_or(josh::josh_error("from_to must contain \":\""))?
-            .to_owned();
-
-        let filter_spec = caps.name("spec").unwrap().as_str().trim().to_owned();
-
-        let mut filterobj = josh::filters::parse(&filter_spec);
-
-        let pres = filterobj.prefixes();
-
-        if args.is_present("infofile") {
-            for (p, v) in pres.iter() {
-                filterobj = josh::build_chain(
-                    filterobj,
-                    josh::filters::parse(&format!(
-                        ":info={},commit=#sha1,tree=#tree,src={},filter={}",
-                        p,
-                        &src,
-                        v.replace(":", "<colon>").replace(",", "<comma>")
-                    )),
-                );
+    let mut allowed = true;
+    for job in jobs.iter() {
+        if job.src.contains('*') {
+            if reverse || options.check_permissions || args.is_present("query")
+            {
+                return Err(josh::josh_error(
+                    "globs can only be used for filtering",
+                ));
+            }
+            let mut results = filter_job(
+                &repo,
+                &job,
+                &options,
+                args.is_present("prune"),
+                &forward_maps,
+                &backward_maps,
+            )?;
+            if args.is_present("print-map") {
+                add_maps(
+                    &repo,
+                    &mut results,
+                    args.is_present("include-dropped"),
+                    false,
+                    &forward_maps,
+                )?;
             }
+            continue;
         }
 
-        let reverse = args.is_present("reverse");
-        let check_permissions = args.is_present("check-permission");
-
-        if args.is_present("squash") {
-            filterobj = josh::build_chain(
-                josh::filters::parse(&format!(":cutoff={}", &src)),
-                filterobj,
-            );
-        }
-
-        if check_permissions {
-            filterobj =
-                josh::build_chain(josh::filters::parse(":DIRS"), filterobj);
-            filterobj =
-                josh::build_chain(filterobj, josh::filters::parse(":FOLD"));
-        }
+        let filterobj = build_filter(&job, &options);
+        let src = resolve_src(&repo, &job.src)?;
 
         let t = if reverse {
-            "refs/JOSH_TMP".to_owned()
+            "refs/JOSH_TMP"
         } else {
-            target.clone()
+            &job.target
         };
-        let src = repo
-            .revparse_ext(&src)?
-            .1
-            .ok_or(josh::josh_error("reference not found"))?
-            .name()
-            .unwrap()
-            .to_string();
-
-        josh::apply_filter_to_refs(
+        filter_ref(
             &repo,
             &*filterobj,
-            &[(src.clone(), t.clone())],
-            &mut forward_maps.write().unwrap(),
-            &mut backward_maps.write().unwrap(),
+            &src,
+            &t,
+            &forward_maps,
+            &backward_maps,
         )?;
 
-        let mut all_dirs = vec![];
-
-        if check_permissions {
-            let result_tree = repo.find_reference(&t)?.peel_to_tree()?;
-
-            result_tree.walk(git2::TreeWalkMode::PreOrder, |_, entry| {
-                let name = entry.name().unwrap();
-                if name.starts_with("JOSH_ORIG_PATH_") {
-                    let dirname = format!(
-                        "{}",
-                        josh::from_ns(&name.replacen("JOSH_ORIG_PATH_", "", 1))
-                    );
-                    all_dirs.push(dirname);
-                }
-                git2::TreeWalkResult::Ok
-            })?;
-        }
-
-        let mut dedup = vec![];
-
-        for w in all_dirs.as_slice().windows(2) {
-            if let [a, b, ..] = w {
-                if !b.starts_with(a) {
-                    dedup.push(a.to_owned());
-                }
-            }
+        if args.is_present("print-map") {
+            print_map(&history_map(
+                &repo,
+                &src,
+                &filterobj.filter_spec(),
+                args.is_present("include-dropped"),
+                &forward_maps,
+            )?);
         }
 
         if let Some(cp) = args.value_of("check-permission") {
-            let permission_regex = regex::Regex::new(cp)?;
-
-            let mut allowed = dedup.len() != 0;
-            for d in dedup.iter() {
-                let m = permission_regex.is_match(&d);
-                if !m {
-                    allowed = false;
-                    println!("missing permission for: {}", &d);
-                }
-            }
-            println!("Allowed = {:?}", allowed);
+            let check = josh::housekeeping::PermissionCheck::new(
+                josh::filters::original_dirs(
+                    &repo.find_reference(&t)?.peel_to_tree()?,
+                )?,
+                &regex::Regex::new(cp)?,
+            );
+            print_permissions(&check, false);
+            allowed = allowed && check.allowed;
         }
 
         if let Some(query) = args.value_of("query") {
-            let kv_store = Arc::new(RwLock::new(serde_json::from_str(
-                &std::fs::read_to_string(".git/josh_kv.json")
-                    .unwrap_or("{}".to_string()),
-            )?));
             print!(
                 "{}",
-                josh::query::render(
+                render_query(
                     &repo,
-                    &update_target.to_string(),
+                    &job.target,
                     &query,
-                    kv_store.clone(),
-                    forward_maps.clone(),
-                    backward_maps.clone(),
+                    &forward_maps,
+                    &backward_maps
                 )?
             );
         }
 
         if reverse {
-            let new = repo.revparse_single(&target).unwrap().id();
-            let old = repo.revparse_single("JOSH_TMP").unwrap().id();
-
-            match josh::unapply_filter(
+            match unapply(
                 &repo,
-                backward_maps.clone(),
                 &*filterobj,
-                old,
-                new,
+                &src,
+                &job.target,
+                &forward_maps,
+                &backward_maps,
             )? {
-                josh::UnapplyFilter::Done(rewritten) => {
-                    repo.reference(&src, rewritten, true, "unapply_filter")?;
-                }
+                josh::UnapplyFilter::Done(_) => {}
                 _ => {
-                    /* debug!("rewritten ERROR"); */
                     return Ok(1);
                 }
             }
         }
     }
 
-    let bm = backward_maps.read().unwrap();
+    persist_maps(&repo, &forward_maps, &backward_maps);
 
-    josh::filter_cache::persist(&*bm, &repo.path().join("josh_backward_maps"))
-        .ok();
-    josh::filter_cache::persist(
-        &forward_maps.read().unwrap(),
-        &repo.path().join("josh_forward_maps"),
-    )
-    .ok();
-
-    return Ok(0);
+    return Ok(if allowed { 0 } else { 1 });
 }
 
 fn main() {
@@ -249,10 +1021,23 @@ fn main() {
         args
     };
 
-    std::process::exit(if let Err(e) = run_filter(args) {
-        println!("ERROR: {:?}", e);
-        1
+    let is_subcommand = args
+        .get(1)
+        .map(|x| SUBCOMMANDS.contains(&x.as_str()) || x == "help")
+        .unwrap_or(false)
+        && !is_legacy_form(&args);
+
+    let result = if is_subcommand {
+        run_subcommand(args)
     } else {
-        0
+        run_legacy(args)
+    };
+
+    std::process::exit(match result {
+        Ok(code) => code,
+        Err(e) => {
+            println!("ERROR: {:?}", e);
+            1
+        }
     })
 }
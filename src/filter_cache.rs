@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::{Arc, RwLock};
 
 const FORMAT_VERSION: u64 = 2;
@@ -8,13 +8,31 @@ pub struct JoshOid(git2::Oid);
 
 pub type OidMap = HashMap<JoshOid, JoshOid>;
 
+// Layout of the persisted maps before the rewrite config key was added.
+// bincode can't leave out trailing fields, so those are loaded separately.
+#[derive(serde::Deserialize)]
+struct FilterCacheWithoutRewrite {
+    maps: HashMap<String, OidMap>,
+    version: u64,
+}
+
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct FilterCache {
     maps: HashMap<String, OidMap>,
     version: u64,
 
+    // RewriteConfig::cache_key of the config the maps were built with
+    #[serde(default)]
+    rewrite: String,
+
     #[serde(skip)]
     upsteam: Option<Arc<RwLock<FilterCache>>>,
+
+    #[serde(skip)]
+    progress: Option<Arc<crate::progress::Progress>>,
+
+    #[serde(skip)]
+    rewrite_config: Option<Arc<crate::scratch::RewriteConfig>>,
 }
 
 impl serde::ser::Serialize for JoshOid {
@@ -107,14 +125,113 @@ impl FilterCache {
         return false;
     }
 
+    // All filter specs with an entry for from, including the ones of the
+    // upstream cache. On the backward maps this finds the original commits
+    // of a filtered commit.
+    pub fn lookup(&self, from: git2::Oid) -> BTreeMap<String, git2::Oid> {
+        let mut found = BTreeMap::new();
+        if let Some(upsteam) = self.upsteam.clone() {
+            if let Ok(upsteam) = upsteam.read() {
+                found = upsteam.lookup(from);
+            }
+        }
+        for (filter_spec, m) in self.maps.iter() {
+            if let Some(JoshOid(oid)) = m.get(&JoshOid(from)) {
+                found.insert(filter_spec.to_string(), *oid);
+            }
+        }
+        return found;
+    }
+
+    // Every commit mentioned in the maps, original or filtered
+    pub fn oids(&self) -> std::collections::HashSet<git2::Oid> {
+        let mut oids = std::collections::HashSet::new();
+        for m in self.maps.values() {
+            for (JoshOid(from), JoshOid(to)) in m.iter() {
+                oids.insert(*from);
+                oids.insert(*to);
+            }
+        }
+        oids.remove(&git2::Oid::zero());
+        return oids;
+    }
+
+    // Original and filtered commits for the history of head, see HistoryMap
+    pub fn history<'a>(
+        &'a self,
+        repo: &'a git2::Repository,
+        filter_spec: &str,
+        head: git2::Oid,
+    ) -> crate::JoshResult<HistoryMap<'a>> {
+        let mut walk = repo.revwalk()?;
+        walk.set_sorting(git2::Sort::TOPOLOGICAL)?;
+        walk.push(head)?;
+        return Ok(HistoryMap {
+            cache: self,
+            repo: &repo,
+            filter_spec: filter_spec.to_owned(),
+            walk: walk,
+        });
+    }
+
     pub fn new() -> FilterCache {
         return FilterCache {
             maps: HashMap::new(),
             upsteam: None,
+            progress: None,
+            rewrite_config: None,
+            rewrite: String::new(),
             version: FORMAT_VERSION,
         };
     }
 
+    pub fn set_progress(&mut self, progress: Arc<crate::progress::Progress>) {
+        self.progress = Some(progress);
+    }
+
+    pub fn progress(&self) -> Option<Arc<crate::progress::Progress>> {
+        return self.progress.clone();
+    }
+
+    // Signing settings change the ids of filtered commits, so maps built
+    // with a different config are dropped
+    pub fn set_rewrite_config(
+        &mut self,
+        config: Arc<crate::scratch::RewriteConfig>,
+    ) {
+        let key = config.cache_key();
+        if key != self.rewrite {
+            if !self.maps.is_empty() {
+                tracing::info!("rewrite config changed, dropping maps");
+            }
+            self.maps.clear();
+            self.rewrite = key;
+        }
+        self.rewrite_config = Some(config);
+    }
+
+    // Config used to create filtered commits, the one of the upstream cache
+    // if there is one. Loaded from the repo on first use otherwise.
+    pub fn rewrite_config(
+        &mut self,
+        repo: &git2::Repository,
+    ) -> Arc<crate::scratch::RewriteConfig> {
+        if let Some(config) = &self.rewrite_config {
+            return config.clone();
+        }
+        if let Some(upsteam) = self.upsteam.clone() {
+            if let Ok(upsteam) = upsteam.read() {
+                if let Some(config) = &upsteam.rewrite_config {
+                    self.rewrite_config = Some(config.clone());
+                    return config.clone();
+                }
+            }
+        }
+        let config = Arc::new(crate::scratch::RewriteConfig::from_repo(&repo));
+        self.set_rewrite_config(config.clone());
+        return config;
+    }
+
     pub fn merge(&mut self, other: &FilterCache) {
         for (filter_spec, om) in other.maps.iter() {
             let m = self
@@ -139,13 +256,102 @@ impl FilterCache {
     }
 }
 
+// Why a commit has no commit of its own in the filtered history
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DropReason {
+    // The commit was not filtered yet
+    NotCached,
+    // Nothing of the commit's tree is part of the view
+    Empty,
+    // The commit doesn't change the view, its filtered commit is the one of
+    // a parent
+    Unchanged,
+}
+
+impl DropReason {
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            DropReason::NotCached => "not-cached",
+            DropReason::Empty => "empty",
+            DropReason::Unchanged => "unchanged",
+        };
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MapEntry {
+    pub original: git2::Oid,
+    // Zero for dropped commits
+    pub filtered: git2::Oid,
+    pub dropped: Option<DropReason>,
+}
+
+// Iterator over the history of a commit, newest first, returning the
+// filtered commit of each original commit as recorded in a FilterCache
+pub struct HistoryMap<'a> {
+    cache: &'a FilterCache,
+    repo: &'a git2::Repository,
+    filter_spec: String,
+    walk: git2::Revwalk<'a>,
+}
+
+impl<'a> HistoryMap<'a> {
+    fn entry(&self, original: git2::Oid) -> crate::JoshResult<MapEntry> {
+        let dropped = |reason| MapEntry {
+            original: original,
+            filtered: git2::Oid::zero(),
+            dropped: Some(reason),
+        };
+        if !self.cache.has(&self.repo, &self.filter_spec, original) {
+            return Ok(dropped(DropReason::NotCached));
+        }
+        let filtered = self.cache.get(&self.filter_spec, original);
+        if filtered == git2::Oid::zero() {
+            return Ok(dropped(DropReason::Empty));
+        }
+        let commit = self.repo.find_commit(original)?;
+        if commit
+            .parent_ids()
+            .any(|x| self.cache.get(&self.filter_spec, x) == filtered)
+        {
+            return Ok(dropped(DropReason::Unchanged));
+        }
+        return Ok(MapEntry {
+            original: original,
+            filtered: filtered,
+            dropped: None,
+        });
+    }
+}
+
+impl<'a> Iterator for HistoryMap<'a> {
+    type Item = crate::JoshResult<MapEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        return Some(match self.walk.next()? {
+            Ok(original) => self.entry(original),
+            Err(e) => Err(e.into()),
+        });
+    }
+}
+
 pub fn try_load(path: &std::path::Path) -> FilterCache {
     let file_size = std::fs::metadata(&path)
         .map(|x| x.len() / (1024 * 1024))
         .unwrap_or(0);
     tracing::info!("trying to load: {:?}, size: {} MiB", &path, file_size);
     if let Ok(f) = std::fs::File::open(path) {
-        if let Ok(m) = bincode::deserialize_from::<_, FilterCache>(f) {
+        let loaded =
+            bincode::deserialize_from::<_, FilterCache>(f).or_else(|_| {
+                let f = std::fs::File::open(path)?;
+                bincode::deserialize_from::<_, FilterCacheWithoutRewrite>(f)
+                    .map(|m| FilterCache {
+                        maps: m.maps,
+                        version: m.version,
+                        ..FilterCache::new()
+                    })
+            });
+        if let Ok(m) = loaded {
             tracing::info!("mapfile loaded from: {:?}", &path);
             if m.version == FORMAT_VERSION {
                 return m;
@@ -207,6 +413,9 @@ pub fn new_downstream(u: &Arc<RwLock<FilterCache>>) -> FilterCache {
     return FilterCache {
         maps: HashMap::new(),
         upsteam: Some(u.clone()),
+        progress: None,
+        rewrite_config: None,
+        rewrite: String::new(),
         version: FORMAT_VERSION,
     };
 }
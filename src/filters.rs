@@ -32,6 +32,7 @@ fn create_filtered_commit(
     original_commmit: &git2::Commit,
     filtered_parent_ids: Vec<git2::Oid>,
     filtered_tree: &git2::Tree,
+    config: &scratch::RewriteConfig,
 ) -> super::JoshResult<git2::Oid> {
     let filtered_parent_commits: std::result::Result<Vec<_>, _> =
         filtered_parent_ids
@@ -62,6 +63,7 @@ fn create_filtered_commit(
         &original_commmit,
         &selected_filtered_parent_commits,
         &filtered_tree,
+        &config,
     );
 }
 
@@ -93,6 +95,7 @@ pub trait Filter {
                 Some(&commit),
                 &self.filter_spec(),
             ),
+            &forward_maps.rewrite_config(&repo),
         );
     }
 
@@ -246,6 +249,141 @@ fn striped_tree(
     return Ok(result_id);
 }
 
+// Original directories recorded by the :DIRS filter in tree, taken from
+// the JOSH_ORIG_PATH_ markers wherever the view has put them
+pub fn original_dirs(
+    tree: &git2::Tree,
+) -> super::JoshResult<std::collections::BTreeSet<String>> {
+    let mut dirs = std::collections::BTreeSet::new();
+    tree.walk(git2::TreeWalkMode::PreOrder, |_, entry| {
+        let name = entry.name().unwrap_or("");
+        if name.starts_with("JOSH_ORIG_PATH_") {
+            dirs.insert(super::from_ns(&name.replacen(
+                "JOSH_ORIG_PATH_",
+                "",
+                1,
+            )));
+        }
+        git2::TreeWalkResult::Ok
+    })?;
+    return Ok(dirs);
+}
+
+// tree with all workspace.josh files below it removed, zero if nothing
+// else is left
+fn without_workspace_files(
+    repo: &git2::Repository,
+    tree: &git2::Tree,
+) -> super::JoshResult<git2::Oid> {
+    let mut result = tree.clone();
+    for entry in tree.iter() {
+        let name = entry.name().ok_or(super::josh_error("no name"))?;
+        let oid = if entry.kind() == Some(git2::ObjectType::Tree) {
+            without_workspace_files(&repo, &repo.find_tree(entry.id())?)?
+        } else if name == "workspace.josh" {
+            git2::Oid::zero()
+        } else {
+            continue;
+        };
+        let r = replace_child(&repo, &Path::new(name), oid, &result)?;
+        result = repo.find_tree(r)?;
+    }
+    if result.id() == empty_tree_id() {
+        return Ok(git2::Oid::zero());
+    }
+    return Ok(result.id());
+}
+
+// Reverse of striped_tree: takes the original tree and only applies the
+// changes to workspace.josh files. The JOSH_ORIG_PATH_ markers are removed
+// and any modification of them is rejected.
+fn unstriped_tree(
+    repo: &git2::Repository,
+    root: &str,
+    tree: &git2::Tree,
+    parent_tree: &git2::Tree,
+) -> super::JoshResult<git2::Oid> {
+    let marker = format!("JOSH_ORIG_PATH_{}", super::to_ns(&root));
+    let mut result = parent_tree.clone();
+    let mut has_marker = false;
+
+    for entry in tree.iter() {
+        let name = entry.name().ok_or(super::josh_error("no name"))?;
+        let path = format!("{}/{}", root, name);
+
+        if name.starts_with("JOSH_ORIG_PATH_") {
+            let content = repo.find_blob(entry.id()).map(|x| x.size());
+            if root == "" || name != marker || content.ok() != Some(0) {
+                return Err(super::josh_error(&format!(
+                    "DIRS filter: generated file modified: {}",
+                    path.trim_start_matches('/')
+                )));
+            }
+            has_marker = true;
+            continue;
+        }
+
+        if entry.kind() == Some(git2::ObjectType::Tree) {
+            let parent_subtree = if let Some(st) = parent_tree.get_name(name) {
+                repo.find_tree(st.id())?
+            } else {
+                empty_tree(&repo)
+            };
+            let r = replace_child(
+                &repo,
+                &Path::new(name),
+                unstriped_tree(
+                    &repo,
+                    &path,
+                    &repo.find_tree(entry.id())?,
+                    &parent_subtree,
+                )?,
+                &result,
+            )?;
+            result = repo.find_tree(r)?;
+        } else if name == "workspace.josh" {
+            let r =
+                replace_child(&repo, &Path::new(name), entry.id(), &result)?;
+            result = repo.find_tree(r)?;
+        } else {
+            return Err(super::josh_error(&format!(
+                "DIRS filter: file not part of the view: {}",
+                path.trim_start_matches('/')
+            )));
+        }
+    }
+
+    for entry in parent_tree.iter() {
+        let name = entry.name().ok_or(super::josh_error("no name"))?;
+        if tree.get_name(name).is_some() {
+            continue;
+        }
+        // Every directory of the original tree is in the view with at least
+        // its marker, so one that is gone entirely was deleted. Only the
+        // workspace.josh files below it are part of the view, other files
+        // are kept.
+        let oid = if entry.kind() == Some(git2::ObjectType::Tree) {
+            without_workspace_files(&repo, &repo.find_tree(entry.id())?)?
+        } else if name == "workspace.josh" {
+            git2::Oid::zero()
+        } else {
+            continue;
+        };
+        let r = replace_child(&repo, &Path::new(name), oid, &result)?;
+        result = repo.find_tree(r)?;
+    }
+
+    if root != "" && !has_marker && parent_tree.id() != empty_tree_id() {
+        return Err(super::josh_error(&format!(
+            "DIRS filter: generated file modified: {}/{}",
+            root.trim_start_matches('/'),
+            marker
+        )));
+    }
+
+    return Ok(result.id());
+}
+
 impl Filter for DirsFilter {
     fn apply_to_parents(
         &self,
@@ -284,11 +422,11 @@ impl Filter for DirsFilter {
 
     fn unapply(
         &self,
-        _repo: &git2::Repository,
-        _tree: &git2::Tree,
-        _parent_tree: &git2::Tree,
+        repo: &git2::Repository,
+        tree: &git2::Tree,
+        parent_tree: &git2::Tree,
     ) -> super::JoshResult<git2::Oid> {
-        Ok(empty_tree_id())
+        return unstriped_tree(&repo, "", &tree, &parent_tree);
     }
 
     fn filter_spec(&self) -> String {
@@ -414,6 +552,7 @@ impl Filter for FoldFilter {
                 Some(&commit),
                 &self.filter_spec(),
             ),
+            &forward_maps.rewrite_config(&repo),
         );
     }
 
@@ -524,11 +663,17 @@ impl Filter for CutoffFilter {
         &self,
         repo: &git2::Repository,
         commit: &git2::Commit,
-        _forward_maps: &mut FilterCache,
+        forward_maps: &mut FilterCache,
         _backward_maps: &mut FilterCache,
         _meta: &mut HashMap<String, String>,
     ) -> super::JoshResult<git2::Oid> {
-        return scratch::rewrite(&repo, &commit, &vec![], &commit.tree()?);
+        return scratch::rewrite(
+            &repo,
+            &commit,
+            &vec![],
+            &commit.tree()?,
+            &forward_maps.rewrite_config(&repo),
+        );
     }
 
     fn apply_to_tree(
@@ -884,11 +1029,60 @@ impl Filter for InfoFileFilter {
 
     fn unapply(
         &self,
-        _repo: &git2::Repository,
+        repo: &git2::Repository,
         tree: &git2::Tree,
-        _parent_tree: &git2::Tree,
+        parent_tree: &git2::Tree,
     ) -> super::JoshResult<git2::Oid> {
-        Ok(tree.id())
+        let path = Path::new(&self.values["prefix"]).join(".joshinfo");
+        let modified = || {
+            super::josh_error(&format!("generated file modified: {:?}", path))
+        };
+
+        let content = ok_or!(tree.get_path(&path).map(|x| x.id()), {
+            return Err(modified());
+        });
+        let content = repo.find_blob(content).map_err(|_| modified())?;
+        let content =
+            std::str::from_utf8(content.content()).map_err(|_| modified())?;
+
+        let mut found = std::collections::BTreeMap::new();
+        for line in content.lines() {
+            let mut split = line.splitn(2, ": ");
+            if let (Some(k), Some(v)) = (split.next(), split.next()) {
+                found.insert(k.to_string(), v.to_string());
+            } else {
+                return Err(modified());
+            }
+        }
+
+        let mut expected = std::collections::BTreeMap::new();
+        for (k, v) in self.values.iter() {
+            if k == "prefix" {
+                continue;
+            }
+            expected.insert(
+                k.to_string(),
+                v.replace("<colon>", ":").replace("<comma>", ","),
+            );
+        }
+
+        if found.len() != expected.len() {
+            return Err(modified());
+        }
+        for (k, v) in expected.iter() {
+            let f = some_or!(found.get(k), {
+                return Err(modified());
+            });
+            if v != "#sha1" && v != "#tree" && f != v {
+                return Err(modified());
+            }
+        }
+
+        let original = parent_tree
+            .get_path(&path)
+            .map(|x| x.id())
+            .unwrap_or(git2::Oid::zero());
+        replace_subtree(&repo, &path, original, &tree)
     }
 
     fn filter_spec(&self) -> String {
@@ -981,6 +1175,37 @@ impl Filter for CombineFilter {
         tree: &git2::Tree,
         parent_tree: &git2::Tree,
     ) -> super::JoshResult<git2::Oid> {
+        // Where two prefixes overlap the content of the nested one can't be
+        // assigned to either of them, so it must not be changed
+        let mut overlapping = vec![];
+        for (i, a) in self.prefixes.iter().enumerate() {
+            for b in self.prefixes.iter().skip(i + 1) {
+                if a.starts_with(&b) || b.starts_with(&a) {
+                    overlapping.push((a, b));
+                }
+            }
+        }
+        let mut nested = vec![];
+        if !overlapping.is_empty() {
+            let parent_view = repo.find_tree(self.apply_to_tree(
+                &repo,
+                &parent_tree,
+                git2::Oid::zero(),
+            )?)?;
+            for (a, b) in overlapping {
+                let inner = if a.starts_with(&b) { a } else { b };
+                if tree.get_path(&inner).map(|x| x.id()).ok()
+                    != parent_view.get_path(&inner).map(|x| x.id()).ok()
+                {
+                    return Err(super::josh_error(&format!(
+                        "overlapping prefixes: {:?} and {:?}",
+                        a, b
+                    )));
+                }
+                nested.push(inner.clone());
+            }
+        }
+
         let mut base_wo = tree.id();
 
         for prefix in self.prefixes.iter() {
@@ -997,28 +1222,54 @@ impl Filter for CombineFilter {
                 .unapply(repo, &repo.find_tree(base_wo)?, parent_tree)?;
 
         for (other, prefix) in self.others.iter().zip(self.prefixes.iter()) {
-            let r = ok_or!(tree.get_path(&prefix).map(|x| x.id()), {
+            if nested.contains(prefix) {
+                continue;
+            }
+            let mut r = ok_or!(tree.get_path(&prefix).map(|x| x.id()), {
                 continue;
             });
             if r == empty_tree_id() {
                 continue;
             }
+            // Unchanged nested prefixes are replaced by what this prefix has
+            // there, so they don't show up as change
+            for inner in nested.iter() {
+                let rel = ok_or!(inner.strip_prefix(&prefix), {
+                    continue;
+                });
+                let own = repo.find_tree(other.apply_to_tree(
+                    &repo,
+                    &parent_tree,
+                    git2::Oid::zero(),
+                )?)?;
+                r = replace_subtree(
+                    &repo,
+                    &rel,
+                    own.get_path(&rel)
+                        .map(|x| x.id())
+                        .unwrap_or(git2::Oid::zero()),
+                    &repo.find_tree(r)?,
+                )?;
+            }
             let r = repo.find_tree(r)?;
             let ua = other.unapply(&repo, &r, &parent_tree)?;
 
-            let merged = repo
-                .merge_trees(
-                    &parent_tree,
-                    &repo.find_tree(res)?,
-                    &repo.find_tree(ua)?,
-                    Some(
-                        git2::MergeOptions::new()
-                            .file_favor(git2::FileFavor::Theirs),
-                    ),
-                )?
-                .write_tree_to(&repo)?;
+            let mut merged = repo.merge_trees(
+                &parent_tree,
+                &repo.find_tree(res)?,
+                &repo.find_tree(ua)?,
+                None,
+            )?;
 
-            res = merged;
+            if merged.has_conflicts() {
+                return Err(super::josh_error(&format!(
+                    "conflicting changes in {:?}: {}",
+                    prefix,
+                    super::conflict_paths(&merged)?.join(", ")
+                )));
+            }
+
+            res = merged.write_tree_to(&repo)?;
         }
 
         return Ok(res);
@@ -1222,6 +1473,7 @@ impl Filter for WorkspaceFilter {
                 Some(&commit),
                 &self.filter_spec(),
             ),
+            &forward_maps.rewrite_config(&repo),
         );
     }
 
@@ -1445,6 +1697,84 @@ fn replace_subtree(
     }
 }
 
+const PATH_MARKER: &str = ".josh-path-marker";
+
+// Replaces path in tree with a marker blob that can be found again after
+// filtering. For directories the marker is placed inside, so the returned
+// bool tells whether the last component of the found path has to be dropped.
+fn mark_path<'a>(
+    repo: &'a git2::Repository,
+    tree: &git2::Tree,
+    path: &str,
+) -> super::JoshResult<(git2::Tree<'a>, git2::Oid, bool)> {
+    let path = Path::new(path.trim_matches('/'));
+    let marker =
+        repo.blob(format!("{}: {:?}\n", PATH_MARKER, path).as_bytes())?;
+
+    let is_dir = path == Path::new("")
+        || tree
+            .get_path(&path)
+            .map(|x| x.kind() == Some(git2::ObjectType::Tree))
+            .unwrap_or(false);
+
+    let marked = if is_dir {
+        replace_subtree(&repo, &path.join(PATH_MARKER), marker, &tree)?
+    } else {
+        replace_subtree(&repo, &path, marker, &tree)?
+    };
+    return Ok((repo.find_tree(marked)?, marker, is_dir));
+}
+
+fn find_marker(
+    tree: &git2::Tree,
+    marker: git2::Oid,
+    is_dir: bool,
+) -> Option<String> {
+    let mut found = None;
+    tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+        if entry.id() == marker {
+            found = Some(if is_dir {
+                root.trim_matches('/').to_owned()
+            } else {
+                format!("{}{}", root, entry.name().unwrap_or(""))
+            });
+            return git2::TreeWalkResult::Abort;
+        }
+        git2::TreeWalkResult::Ok
+    })
+    .ok();
+    return found;
+}
+
+// Where path of original_tree ends up after applying filter, None if the
+// filter drops it
+pub fn filtered_path(
+    repo: &git2::Repository,
+    filter: &dyn Filter,
+    original_tree: &git2::Tree,
+    commit_id: git2::Oid,
+    path: &str,
+) -> super::JoshResult<Option<String>> {
+    let (marked, marker, is_dir) = mark_path(&repo, &original_tree, &path)?;
+    let filtered =
+        repo.find_tree(filter.apply_to_tree(&repo, &marked, commit_id)?)?;
+    return Ok(find_marker(&filtered, marker, is_dir));
+}
+
+// Where path of filtered_tree ends up in the original tree when pushing
+pub fn original_path(
+    repo: &git2::Repository,
+    filter: &dyn Filter,
+    filtered_tree: &git2::Tree,
+    original_tree: &git2::Tree,
+    path: &str,
+) -> super::JoshResult<Option<String>> {
+    let (marked, marker, is_dir) = mark_path(&repo, &filtered_tree, &path)?;
+    let original =
+        repo.find_tree(filter.unapply(&repo, &marked, &original_tree)?)?;
+    return Ok(find_marker(&original, marker, is_dir));
+}
+
 fn apply_filter_cached(
     repo: &git2::Repository,
     filter: &dyn Filter,
@@ -1465,13 +1795,26 @@ fn apply_filter_cached(
         walk
     };
 
+    // Topological sorting needs to look at all commits before returning the
+    // first one anyway, so collecting them here gives the total for free
+    let walk = walk.collect::<Result<Vec<_>, _>>()?;
+    let progress = forward_maps.progress();
+
     let mut in_commit_count = 0;
     let mut out_commit_count = 0;
     let mut empty_tree_count = 0;
-    for original_commit_id in walk {
+    for original_commit_id in walk.iter() {
         in_commit_count += 1;
 
-        let original_commit = repo.find_commit(original_commit_id?)?;
+        if let Some(progress) = &progress {
+            progress.report(
+                &filter.filter_spec(),
+                in_commit_count,
+                walk.len(),
+            )?;
+        }
+
+        let original_commit = repo.find_commit(*original_commit_id)?;
 
         let filtered_commit = ok_or!(
             filter.apply_to_commit(
@@ -1487,6 +1830,11 @@ fn apply_filter_cached(
             }
         );
 
+        // Don't record the result if it was cut short by a cancellation
+        if let Some(progress) = &progress {
+            progress.check()?;
+        }
+
         if filtered_commit == git2::Oid::zero() {
             empty_tree_count += 1;
         }
@@ -8,6 +8,55 @@ use tracing::{info, span, Level};
 
 pub type KnownViews = BTreeMap<String, BTreeSet<String>>;
 
+// Branch patterns to build in addition to the default branch and
+// josh.branches, by upstream repo and filter spec
+pub type KnownBranches = BTreeMap<String, BTreeMap<String, BTreeSet<String>>>;
+
+// Views to keep warm in addition to the ones found by
+// discover_filter_candidates, for example:
+//
+//   [[prebuild]]
+//   repos = ["org/*.git"]
+//   filters = [":/docs", ":/libs/*", ":workspace=**"]
+//   branches = ["release/*"]
+//
+// In repos and filters "*" matches anything except "/", "**" matches
+// anything. Filter patterns are expanded to the subdirectories and
+// workspaces that exist in the repo. The optional branches are built for
+// the views of the entry only, like the patterns of josh.branches.
+#[derive(serde::Deserialize, Default, Debug)]
+pub struct PrebuildConfig {
+    #[serde(default)]
+    pub prebuild: Vec<PrebuildEntry>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct PrebuildEntry {
+    pub repos: Vec<String>,
+    pub filters: Vec<String>,
+    #[serde(default)]
+    pub branches: Vec<String>,
+}
+
+pub fn load_prebuild_config(path: &Path) -> JoshResult<PrebuildConfig> {
+    let s = std::fs::read_to_string(path).map_err(|e| {
+        josh_error(&format!("can't read prebuild config {:?}: {}", path, e))
+    })?;
+    return toml::from_str(&s).map_err(|e| {
+        josh_error(&format!("invalid prebuild config {:?}: {}", path, e))
+    });
+}
+
+fn glob_match(pattern: &str, s: &str) -> bool {
+    let re = regex::escape(pattern)
+        .replace("\\*\\*", ".*")
+        .replace("\\*", "[^/]*")
+        .replace("\\?", "[^/]");
+    return regex::Regex::new(&format!("^{}$", re))
+        .map(|re| re.is_match(s))
+        .unwrap_or(false);
+}
+
 pub fn default_from_to(
     repo: &git2::Repository,
     namespace: &str,
@@ -40,45 +89,182 @@ pub fn default_from_to(
         &repo,
         &crate::to_filtered_ref(&upstream_repo, &filter_spec),
         &upstream_repo,
+        &[],
     ));
 
     return refs;
 }
 
-pub fn memorize_from_to(
+// Parts of a ref pattern before and after its single "*"
+fn split_glob(pattern: &str) -> JoshResult<(&str, &str)> {
+    let mut splitted = pattern.splitn(2, '*');
+    let prefix = splitted.next().unwrap_or("");
+    let suffix = splitted.next().unwrap_or("");
+    if !pattern.contains('*') || suffix.contains('*') {
+        return Err(josh_error(&format!(
+            "{:?} must contain exactly one \"*\"",
+            pattern
+        )));
+    }
+    return Ok((prefix, suffix));
+}
+
+// The part of refname matched by the "*" of a pattern split by split_glob
+fn glob_matched<'a>(refname: &'a str, glob: (&str, &str)) -> Option<&'a str> {
+    let (prefix, suffix) = glob;
+    if refname.len() < prefix.len() + suffix.len()
+        || !refname.starts_with(prefix)
+        || !refname.ends_with(suffix)
+    {
+        return None;
+    }
+    return Some(&refname[prefix.len()..refname.len() - suffix.len()]);
+}
+
+/**
+ * Source and target refs for every ref matching src_glob. Both src_glob
+ * and target_pattern contain a single "*", the part of a source matched by
+ * it replaces the "*" of target_pattern. For example "refs/tags/v*" and
+ * "refs/josh/tags/app-v*".
+ */
+pub fn glob_from_to(
     repo: &git2::Repository,
-    namespace: &str,
-    upstream_repo: &str,
-) -> Vec<(String, String)> {
+    src_glob: &str,
+    target_pattern: &str,
+) -> JoshResult<Vec<(String, String)>> {
+    let src = split_glob(&src_glob)?;
+    let (target_prefix, target_suffix) = split_glob(&target_pattern)?;
+
     let mut refs = vec![];
-    let glob = format!(
-        "refs/josh/upstream/{}/refs/heads/master",
-        &to_ns(upstream_repo)
-    );
-    for refname in repo.references_glob(&glob).unwrap().names() {
-        let refname = refname.unwrap();
-        let to_ref = format!("refs/{}/heads/master", &namespace);
+    for refname in repo.references_glob(&src_glob)?.names() {
+        let refname = refname?;
+        let matched = some_or!(glob_matched(&refname, src), {
+            continue;
+        });
+        refs.push((
+            refname.to_owned(),
+            format!("{}{}{}", target_prefix, matched, target_suffix),
+        ));
+    }
+    return Ok(refs);
+}
 
-        refs.push((refname.to_owned(), to_ref.clone()));
+/**
+ * Refs matching target_pattern whose source in src_glob, as mapped by
+ * glob_from_to, doesn't exist anymore. Returned as pairs of the missing
+ * source and the target.
+ */
+pub fn stale_targets(
+    repo: &git2::Repository,
+    src_glob: &str,
+    target_pattern: &str,
+) -> JoshResult<Vec<(String, String)>> {
+    let (src_prefix, src_suffix) = split_glob(&src_glob)?;
+    let target = split_glob(&target_pattern)?;
+
+    let mut refs = vec![];
+    for refname in repo.references_glob(&target_pattern)?.names() {
+        let refname = refname?;
+        let matched = some_or!(glob_matched(&refname, target), {
+            continue;
+        });
+        let src = format!("{}{}{}", src_prefix, matched, src_suffix);
+        if repo.find_reference(&src).is_err() {
+            refs.push((src, refname.to_owned()));
+        }
     }
+    return Ok(refs);
+}
 
-    return refs;
+/**
+ * The default branch of upstream_repo as recorded in
+ * refs/josh/upstream/<repo>/HEAD, "refs/heads/master" if unknown
+ */
+pub fn default_branch(repo: &git2::Repository, upstream_repo: &str) -> String {
+    let prefix = format!("refs/josh/upstream/{}/", &to_ns(upstream_repo));
+    if let Ok(head) = repo.find_reference(&format!("{}HEAD", prefix)) {
+        if let Some(target) = head.symbolic_target() {
+            if target.starts_with(&prefix) {
+                return target[prefix.len()..].to_owned();
+            }
+        }
+    }
+    return "refs/heads/master".to_owned();
 }
 
-fn run_command(path: &Path, cmd: &str) -> String {
-    let shell = shell::Shell {
-        cwd: path.to_owned(),
-    };
+/**
+ * Patterns of additional branches to filter in the background, read from
+ * the josh.branches git config
+ */
+fn configured_branches(repo: &git2::Repository) -> Vec<String> {
+    let mut branches = vec![];
+    let config = ok_or!(repo.config().and_then(|mut x| x.snapshot()), {
+        return branches;
+    });
+    if let Ok(entries) = config.entries(Some("josh.branches")) {
+        for entry in &entries {
+            let entry = ok_or!(entry, {
+                continue;
+            });
+            if let Some(value) = entry.value() {
+                branches.push(branch_pattern(value));
+            }
+        }
+    }
+    return branches;
+}
 
-    let output = "";
+// Branch names without "refs/" are taken relative to "refs/heads/"
+fn branch_pattern(branch: &str) -> String {
+    if branch.starts_with("refs/") {
+        return branch.to_owned();
+    }
+    return format!("refs/heads/{}", branch);
+}
 
-    let (stdout, stderr) = shell.command(cmd);
-    let output = format!(
-        "{}\n\n{}:\nstdout:\n{}\n\nstderr:{}\n",
-        output, cmd, stdout, stderr
-    );
+// The extra branch patterns of a view in known_branches
+fn view_branches(
+    known_branches: &KnownBranches,
+    upstream_repo: &str,
+    filter_spec: &str,
+) -> Vec<String> {
+    return known_branches
+        .get(upstream_repo)
+        .and_then(|x| x.get(filter_spec))
+        .map(|x| x.iter().cloned().collect())
+        .unwrap_or_default();
+}
+
+pub fn memorize_from_to(
+    repo: &git2::Repository,
+    namespace: &str,
+    upstream_repo: &str,
+    branches: &[String],
+) -> Vec<(String, String)> {
+    let mut refs = vec![];
+    let prefix = format!("refs/josh/upstream/{}/", &to_ns(upstream_repo));
+
+    let mut patterns = vec![default_branch(&repo, &upstream_repo)];
+    patterns.append(&mut configured_branches(&repo));
+    patterns.extend(branches.iter().map(|x| branch_pattern(x)));
+
+    for pattern in patterns.iter() {
+        let glob = format!("{}{}", prefix, pattern);
+        for refname in repo.references_glob(&glob).unwrap().names() {
+            let refname = refname.unwrap();
+            let to_ref = format!(
+                "refs/{}/{}",
+                &namespace,
+                refname[prefix.len()..].trim_start_matches("refs/")
+            );
 
-    return output;
+            if !refs.iter().any(|(_, x)| x == &to_ref) {
+                refs.push((refname.to_owned(), to_ref.clone()));
+            }
+        }
+    }
+
+    return refs;
 }
 
 super::regex_parsed!(
@@ -96,9 +282,9 @@ super::regex_parsed!(
 pub fn discover_repos(repo: &git2::Repository) -> JoshResult<Vec<String>> {
     let _trace_s = span!(Level::TRACE, "discover_repos");
 
-    let refname = format!("refs/josh/upstream/*.git/refs/heads/master");
+    let refname = format!("refs/josh/upstream/*.git/refs/heads/*");
 
-    let mut repos = vec![];
+    let mut repos = BTreeSet::new();
 
     for reference in repo.references_glob(&refname)? {
         let r = reference?;
@@ -108,10 +294,10 @@ pub fn discover_repos(repo: &git2::Repository) -> JoshResult<Vec<String>> {
             .ns;
         let name = super::from_ns(&name);
 
-        repos.push(name);
+        repos.insert(name);
     }
 
-    return Ok(repos);
+    return Ok(repos.into_iter().collect());
 }
 
 /**
@@ -124,17 +310,19 @@ pub fn discover_filter_candidates(
     let mut known_filters = KnownViews::new();
     let _trace_s = span!(Level::TRACE, "discover_filter_candidates");
 
-    let refname = format!("refs/josh/upstream/*.git/refs/heads/master");
-
-    for reference in repo.references_glob(&refname)? {
-        let r = reference?;
-        let name = r.name().ok_or(josh_error("reference without name"))?;
-        let name = UpstreamRef::from_str(name)
-            .ok_or(josh_error("not a ns"))?
-            .ns;
-        let name = super::from_ns(&name);
+    for name in discover_repos(&repo)? {
+        let r = ok_or!(
+            repo.find_reference(&format!(
+                "refs/josh/upstream/{}/{}",
+                to_ns(&name),
+                default_branch(&repo, &name)
+            )),
+            {
+                continue;
+            }
+        );
 
-        let hs = find_all_workspaces_and_subdirectories(&r.peel_to_tree()?)?;
+        let hs = find_all_workspaces_and_subdirectories(&r.peel_to_tree()?, 3)?;
 
         for i in hs {
             known_filters
@@ -144,7 +332,7 @@ pub fn discover_filter_candidates(
         }
     }
 
-    let refname = format!("josh/filtered/*.git/*/refs/heads/master");
+    let refname = format!("refs/josh/filtered/*.git/*/heads/*");
     for reference in repo.references_glob(&refname)? {
         let r = reference?;
         let name = r.name().ok_or(josh_error("reference without name"))?;
@@ -160,8 +348,97 @@ pub fn discover_filter_candidates(
     return Ok(known_filters);
 }
 
+/**
+ * Add the views listed in the prebuild config to known_filters and their
+ * branch patterns to known_branches
+ */
+pub fn add_prebuild_candidates(
+    repo: &git2::Repository,
+    config: &PrebuildConfig,
+    known_filters: &mut KnownViews,
+    known_branches: &mut KnownBranches,
+) -> JoshResult<()> {
+    let _trace_s = span!(Level::TRACE, "add_prebuild_candidates");
+
+    for upstream_repo in discover_repos(&repo)? {
+        let entries: Vec<_> = config
+            .prebuild
+            .iter()
+            .filter(|e| e.repos.iter().any(|p| glob_match(p, &upstream_repo)))
+            .collect();
+
+        if entries.is_empty() {
+            continue;
+        }
+
+        let tree = repo
+            .revparse_single(&format!(
+                "refs/josh/upstream/{}/{}",
+                to_ns(&upstream_repo),
+                default_branch(&repo, &upstream_repo)
+            ))?
+            .peel_to_tree()?;
+        let existing =
+            find_all_workspaces_and_subdirectories(&tree, std::usize::MAX)?;
+
+        let known = known_filters
+            .entry(upstream_repo.clone())
+            .or_insert_with(BTreeSet::new);
+        let branches = known_branches
+            .entry(upstream_repo.clone())
+            .or_insert_with(BTreeMap::new);
+
+        for entry in entries.iter() {
+            let mut views = vec![];
+            for filter_spec in entry.filters.iter() {
+                if filter_spec.contains('*') || filter_spec.contains('?') {
+                    for f in existing.iter() {
+                        if glob_match(&filter_spec, &f) {
+                            views.push(f.clone());
+                        }
+                    }
+                } else {
+                    views.push(filters::parse(&filter_spec).filter_spec());
+                }
+            }
+            for view in views {
+                branches
+                    .entry(view.clone())
+                    .or_insert_with(BTreeSet::new)
+                    .extend(entry.branches.iter().cloned());
+                known.insert(view);
+            }
+        }
+    }
+
+    return Ok(());
+}
+
+/**
+ * Heuristic filter candidates merged with the ones from the prebuild config
+ * at config_path, if given, together with the extra branches to build
+ */
+pub fn prebuild_candidates(
+    repo: &git2::Repository,
+    config_path: Option<&Path>,
+) -> JoshResult<(KnownViews, KnownBranches)> {
+    let mut known_filters = discover_filter_candidates(&repo)?;
+    let mut known_branches = KnownBranches::new();
+    if let Some(config_path) = config_path {
+        let config = load_prebuild_config(&config_path)?;
+        add_prebuild_candidates(
+            &repo,
+            &config,
+            &mut known_filters,
+            &mut known_branches,
+        )?;
+    }
+    return Ok((known_filters, known_branches));
+}
+
 fn find_all_workspaces_and_subdirectories(
     tree: &git2::Tree,
+    max_depth: usize,
 ) -> JoshResult<std::collections::HashSet<String>> {
     let mut hs = std::collections::HashSet::new();
     tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
@@ -172,7 +449,7 @@ fn find_all_workspaces_and_subdirectories(
             return 0;
         }
         let v = format!(":/{}", root.trim_matches('/'));
-        if v.chars().filter(|x| *x == '/').count() < 3 {
+        if v.chars().filter(|x| *x == '/').count() < max_depth {
             hs.insert(v);
         }
 
@@ -181,27 +458,108 @@ fn find_all_workspaces_and_subdirectories(
     return Ok(hs);
 }
 
+/**
+ * Resolve rev in the refs of upstream_repo. rev can be a ref name relative
+ * to the upstream namespace, a tag or branch name, a sha or any of those
+ * with a suffix like "~2". "HEAD" stands for headref, empty for headref
+ * itself. Only commits reachable from the refs of upstream_repo are found.
+ */
+pub fn resolve_rev<'a>(
+    repo: &'a git2::Repository,
+    upstream_repo: &str,
+    headref: &str,
+    rev: &str,
+) -> JoshResult<git2::Commit<'a>> {
+    let ns = format!("refs/josh/upstream/{}", &to_ns(&upstream_repo));
+    let rev = if rev == "" { "HEAD" } else { rev };
+
+    let candidates = if rev.starts_with("HEAD") {
+        vec![rev.replacen("HEAD", &format!("{}/{}", ns, headref), 1)]
+    } else {
+        vec![
+            format!("{}/{}", ns, rev),
+            format!("{}/refs/tags/{}", ns, rev),
+            format!("{}/refs/heads/{}", ns, rev),
+            rev.to_owned(),
+        ]
+    };
+
+    for candidate in candidates.iter() {
+        let commit = ok_or!(
+            repo.revparse_single(&candidate)
+                .and_then(|x| x.peel_to_commit()),
+            {
+                continue;
+            }
+        );
+        if reachable_from_upstream(&repo, &upstream_repo, commit.id())? {
+            return Ok(commit);
+        }
+    }
+    return Err(josh_error(&format!(
+        "revision {:?} not found in {:?}",
+        rev, upstream_repo
+    )));
+}
+
+// Whether commit is reachable from one of the refs of upstream_repo
+pub fn reachable_from_upstream(
+    repo: &git2::Repository,
+    upstream_repo: &str,
+    commit: git2::Oid,
+) -> JoshResult<bool> {
+    let ns = format!("refs/josh/upstream/{}", &to_ns(&upstream_repo));
+    for reference in repo.references_glob(&format!("{}/*", ns))? {
+        let tip = ok_or!(reference?.peel_to_commit(), {
+            continue;
+        });
+        if tip.id() == commit || repo.graph_descendant_of(tip.id(), commit)? {
+            return Ok(true);
+        }
+    }
+    return Ok(false);
+}
+
 pub fn get_info(
     repo: &git2::Repository,
     filter: &dyn filters::Filter,
     upstream_repo: &str,
     headref: &str,
+    rev: &str,
+    paths: &[String],
     forward_maps: Arc<RwLock<filter_cache::FilterCache>>,
     backward_maps: Arc<RwLock<filter_cache::FilterCache>>,
 ) -> JoshResult<String> {
     let _trace_s = span!(Level::TRACE, "get_info");
 
+    let commit = resolve_rev(&repo, &upstream_repo, &headref, &rev)?;
+    let info = commit_info(
+        &repo,
+        &*filter,
+        &commit,
+        &paths,
+        forward_maps,
+        backward_maps,
+    )?;
+
+    return Ok(serde_json::to_string(&info)?);
+}
+
+/**
+ * Original and filtered commit, the prefixes of the filter and where paths
+ * end up in either direction, as returned by get_info
+ */
+pub fn commit_info(
+    repo: &git2::Repository,
+    filter: &dyn filters::Filter,
+    commit: &git2::Commit,
+    paths: &[String],
+    forward_maps: Arc<RwLock<filter_cache::FilterCache>>,
+    backward_maps: Arc<RwLock<filter_cache::FilterCache>>,
+) -> JoshResult<serde_json::Value> {
     let mut bm = filter_cache::new_downstream(&backward_maps);
     let mut fm = filter_cache::new_downstream(&forward_maps);
 
-    let obj = repo.revparse_single(&format!(
-        "refs/josh/upstream/{}/{}",
-        &to_ns(&upstream_repo),
-        &headref
-    ))?;
-
-    let commit = obj.peel_to_commit()?;
-
     let mut meta = std::collections::HashMap::new();
     meta.insert("sha1".to_owned(), "".to_owned());
     let transformed =
@@ -237,21 +595,250 @@ pub fn get_info(
         })
     };
 
-    let s = json!({
+    // Each path is looked up in both directions: "original" is where the
+    // path of the filtered tree comes from, "filtered" is where the path
+    // of the original tree ends up
+    let original_tree = commit.tree()?;
+    let filtered_tree = repo
+        .find_commit(transformed)
+        .and_then(|x| x.tree())
+        .unwrap_or(empty_tree(&repo));
+    let mut translated = vec![];
+    for path in paths.iter() {
+        let original = filters::original_path(
+            &repo,
+            &*filter,
+            &filtered_tree,
+            &original_tree,
+            &path,
+        );
+        let filtered = filters::filtered_path(
+            &repo,
+            &*filter,
+            &original_tree,
+            commit.id(),
+            &path,
+        );
+        // A path that can't be translated is reported with the reason
+        // instead of looking like it was dropped by the filter
+        let error = original
+            .as_ref()
+            .err()
+            .or(filtered.as_ref().err())
+            .map(|JoshError(e)| e.clone());
+        let mut entry = json!({
+            "path": path,
+            "original": original.unwrap_or(None),
+            "filtered": filtered.unwrap_or(None),
+        });
+        if let Some(error) = error {
+            entry["error"] = json!(error);
+        }
+        translated.push(entry);
+    }
+
+    return Ok(json!({
+        "filter": filter.filter_spec(),
+        "prefixes": filter
+            .prefixes()
+            .into_iter()
+            .collect::<BTreeMap<_, _>>(),
         "original": {
             "commit": commit.id().to_string(),
             "tree": commit.tree_id().to_string(),
             "parents": parent_ids(&commit),
         },
         "transformed": t,
-    });
+        "paths": translated,
+    }));
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PermissionCheck {
+    pub allowed: bool,
+    // Original directories of the view without those containing another one
+    pub dirs: Vec<String>,
+    // Those of dirs not matched by the permission regex
+    pub missing: Vec<String>,
+}
+
+impl PermissionCheck {
+    // A directory containing another one of dirs is left out, it is
+    // covered by the check of that one. A view without any directories is
+    // not allowed.
+    pub fn new(
+        dirs: BTreeSet<String>,
+        regex: &regex::Regex,
+    ) -> PermissionCheck {
+        let dirs: Vec<String> = dirs
+            .iter()
+            .filter(|dir| {
+                let prefix = format!("{}/", dir);
+                // The first entry after the prefix is inside of dir if any is
+                !dirs
+                    .range(prefix.clone()..)
+                    .next()
+                    .map(|x| x.starts_with(&prefix))
+                    .unwrap_or(false)
+            })
+            .cloned()
+            .collect();
+        let missing: Vec<String> = dirs
+            .iter()
+            .filter(|x| !regex.is_match(&x))
+            .cloned()
+            .collect();
+        return PermissionCheck {
+            allowed: !dirs.is_empty() && missing.is_empty(),
+            dirs: dirs,
+            missing: missing,
+        };
+    }
+}
+
+/**
+ * Checks that the original directories in the view of filter, in any commit
+ * of the history of commit, match regex
+ */
+pub fn check_permissions(
+    repo: &git2::Repository,
+    filter: &dyn filters::Filter,
+    commit: git2::Oid,
+    regex: &regex::Regex,
+    forward_maps: Arc<RwLock<filter_cache::FilterCache>>,
+    backward_maps: Arc<RwLock<filter_cache::FilterCache>>,
+) -> JoshResult<PermissionCheck> {
+    let _trace_s = span!(Level::TRACE, "check_permissions", ?commit);
+
+    let dirs_filter = build_chain(
+        build_chain(
+            filters::parse(":DIRS"),
+            filters::parse(&filter.filter_spec()),
+        ),
+        filters::parse(":FOLD"),
+    );
+
+    let mut bm = filter_cache::new_downstream(&backward_maps);
+    let mut fm = filter_cache::new_downstream(&forward_maps);
+    let filtered = dirs_filter.apply_to_commit(
+        &repo,
+        &repo.find_commit(commit)?,
+        &mut fm,
+        &mut bm,
+        &mut std::collections::HashMap::new(),
+    )?;
+    filter_cache::try_merge_both(forward_maps, backward_maps, &fm, &bm);
+
+    let dirs = if let Ok(filtered) = repo.find_commit(filtered) {
+        filters::original_dirs(&filtered.tree()?)?
+    } else {
+        BTreeSet::new()
+    };
+    return Ok(PermissionCheck::new(dirs, &regex));
+}
+
+/**
+ * The counterpart of sha in every known view of upstream_repo. Views that
+ * don't have it cached yet are built on demand until the deadline, the ones
+ * left are returned as second element. Views in which the commit doesn't
+ * appear are left out. None if sha is not reachable from upstream_repo.
+ */
+pub fn find_filtered_commits(
+    repo: &git2::Repository,
+    upstream_repo: &str,
+    sha: git2::Oid,
+    prebuild_config: Option<&Path>,
+    deadline: std::time::Instant,
+    forward_maps: Arc<RwLock<filter_cache::FilterCache>>,
+    backward_maps: Arc<RwLock<filter_cache::FilterCache>>,
+) -> JoshResult<Option<(BTreeMap<String, git2::Oid>, Vec<String>)>> {
+    let _trace_s = span!(Level::TRACE, "find_filtered_commits", ?upstream_repo);
+
+    if !reachable_from_upstream(&repo, &upstream_repo, sha)? {
+        return Ok(None);
+    }
+    let commit = repo.find_commit(sha)?;
+    let (known_filters, _) = prebuild_candidates(&repo, prebuild_config)?;
 
-    return Ok(serde_json::to_string(&s)?);
+    // Cached views are found without walking history, so only building
+    // views is stopped at the deadline
+    let token = progress::CancellationToken::new();
+    let cancel = token.clone();
+    let mut bm = filter_cache::new_downstream(&backward_maps);
+    let mut fm = filter_cache::new_downstream(&forward_maps);
+    fm.set_progress(Arc::new(progress::Progress::new(
+        Some(Box::new(move |_: &str, _: usize, _: usize| {
+            if std::time::Instant::now() > deadline {
+                cancel.cancel();
+            }
+        })),
+        token.clone(),
+    )));
+
+    let mut found = BTreeMap::new();
+    let mut not_built = vec![];
+    for filter_spec in known_filters
+        .get(&from_ns(&upstream_repo))
+        .cloned()
+        .unwrap_or(BTreeSet::new())
+    {
+        let filtered = filters::parse(&filter_spec).apply_to_commit(
+            &repo,
+            &commit,
+            &mut fm,
+            &mut bm,
+            &mut std::collections::HashMap::new(),
+        );
+        let filtered = match filtered {
+            Ok(filtered) => filtered,
+            Err(_) if token.is_cancelled() => {
+                not_built.push(filter_spec);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        fm.set(&filter_spec, sha, filtered);
+        if filtered != git2::Oid::zero() {
+            bm.set(&filter_spec, filtered, sha);
+            found.insert(filter_spec, filtered);
+        }
+    }
+
+    backward_maps.write().unwrap().merge(&bm);
+    forward_maps.write().unwrap().merge(&fm);
+
+    return Ok(Some((found, not_built)));
+}
+
+/**
+ * The original commit of a filtered commit sha in upstream_repo for every
+ * view it was found in. Views that leave sha as it is, like ":nop", don't
+ * make it a filtered commit and are skipped.
+ */
+pub fn find_original_commits(
+    repo: &git2::Repository,
+    upstream_repo: &str,
+    sha: git2::Oid,
+    backward_maps: Arc<RwLock<filter_cache::FilterCache>>,
+) -> JoshResult<BTreeMap<String, git2::Oid>> {
+    let found = backward_maps.read()?.lookup(sha);
+    let mut originals = BTreeMap::new();
+    for (filter_spec, original) in found {
+        if original == git2::Oid::zero() || original == sha {
+            continue;
+        }
+        if !reachable_from_upstream(&repo, &upstream_repo, original)? {
+            continue;
+        }
+        originals.insert(filter_spec, original);
+    }
+    return Ok(originals);
 }
 
 pub fn refresh_known_filters(
     repo: &git2::Repository,
     known_filters: &KnownViews,
+    known_branches: &KnownBranches,
     forward_maps: Arc<RwLock<filter_cache::FilterCache>>,
     backward_maps: Arc<RwLock<filter_cache::FilterCache>>,
 ) -> JoshResult<usize> {
@@ -275,15 +862,17 @@ pub fn refresh_known_filters(
                 &repo,
                 &to_filtered_ref(&upstream_repo, &filter_spec),
                 &upstream_repo,
+                &view_branches(&known_branches, &upstream_repo, &filter_spec),
             );
 
-            updated_count += scratch::apply_filter_to_refs(
-                &repo,
-                &*filters::parse(&filter_spec),
-                &refs,
-                &mut fm,
-                &mut bm,
-            )?;
+            updated_count +=
+                scratch::updated_count(&scratch::apply_filter_to_refs(
+                    &repo,
+                    &*filters::parse(&filter_spec),
+                    &refs,
+                    &mut fm,
+                    &mut bm,
+                )?);
         }
         info!("updated {} refs for {:?}", updated_count, upstream_repo);
 
@@ -301,26 +890,93 @@ pub fn refresh_known_filters(
     return Ok(total);
 }
 
+// Same as refresh_known_filters, but processes the filters on multiple threads.
+pub fn refresh_known_filters_parallel(
+    repo_path: &Path,
+    known_filters: &KnownViews,
+    known_branches: &KnownBranches,
+    forward_maps: Arc<RwLock<filter_cache::FilterCache>>,
+    backward_maps: Arc<RwLock<filter_cache::FilterCache>>,
+    threads: usize,
+) -> JoshResult<usize> {
+    let mut jobs = vec![];
+    for (upstream_repo, e) in known_filters.iter() {
+        for filter_spec in e.iter() {
+            let branches =
+                view_branches(&known_branches, &upstream_repo, &filter_spec);
+            jobs.push((upstream_repo.clone(), filter_spec.clone(), branches));
+        }
+    }
+
+    let (updates, total) = scratch::run_parallel(
+        &repo_path,
+        jobs,
+        threads,
+        forward_maps,
+        backward_maps,
+        None,
+        || {
+            |repo, (upstream_repo, filter_spec, branches), fm, bm| {
+                tracing::trace!(
+                    "background rebuild: {:?} {:?}",
+                    upstream_repo,
+                    filter_spec
+                );
+
+                let refs = memorize_from_to(
+                    &repo,
+                    &to_filtered_ref(&upstream_repo, &filter_spec),
+                    &upstream_repo,
+                    &branches,
+                );
+
+                scratch::apply_filter_to_refs(
+                    &repo,
+                    &*filters::parse(&filter_spec),
+                    &refs,
+                    fm,
+                    bm,
+                )
+            }
+        },
+    )?;
+    let updated_count: usize =
+        updates.iter().map(|x| scratch::updated_count(x)).sum();
+    info!("updated {} refs using {} threads", updated_count, threads);
+    return Ok(total);
+}
+
 pub fn spawn_thread(
     repo_path: std::path::PathBuf,
     forward_maps: Arc<RwLock<filter_cache::FilterCache>>,
     backward_maps: Arc<RwLock<filter_cache::FilterCache>>,
-    do_gc: bool,
+    threads: usize,
+    prebuild_config: Option<std::path::PathBuf>,
 ) -> std::thread::JoinHandle<()> {
-    let mut gc_timer = std::time::Instant::now();
     let mut persist_timer =
         std::time::Instant::now() - std::time::Duration::from_secs(60 * 5);
     std::thread::spawn(move || {
         let mut total = 0;
         loop {
             let repo = git2::Repository::init_bare(&repo_path).unwrap();
-            let known_filters =
-                housekeeping::discover_filter_candidates(&repo).unwrap();
-            total += refresh_known_filters(
-                &repo,
+            let (known_filters, known_branches) = ok_or!(
+                prebuild_candidates(&repo, prebuild_config.as_deref()),
+                {
+                    tracing::error!("prebuild_candidates failed");
+                    (
+                        housekeeping::discover_filter_candidates(&repo)
+                            .unwrap(),
+                        KnownBranches::new(),
+                    )
+                }
+            );
+            total += refresh_known_filters_parallel(
+                &repo.path(),
                 &known_filters,
+                &known_branches,
                 forward_maps.clone(),
                 backward_maps.clone(),
+                threads,
             )
             .unwrap_or(0);
             if total > 1000
@@ -340,28 +996,6 @@ pub fn spawn_thread(
                 total = 0;
                 persist_timer = std::time::Instant::now();
             }
-            info!(
-                "{}",
-                run_command(&repo.path(), &"git count-objects -v")
-                    .replace("\n", "  ")
-            );
-            if do_gc
-                && gc_timer.elapsed() > std::time::Duration::from_secs(60 * 60)
-            {
-                info!(
-                    "\n----------\n{}\n----------",
-                    run_command(&repo.path(), &"git repack -adkbn")
-                );
-                info!(
-                    "\n----------\n{}\n----------",
-                    run_command(&repo.path(), &"git count-objects -vH")
-                );
-                info!(
-                    "\n----------\n{}\n----------",
-                    run_command(&repo.path(), &"git prune --expire=2w")
-                );
-                gc_timer = std::time::Instant::now();
-            }
             std::thread::sleep(std::time::Duration::from_secs(60));
         }
     })
@@ -0,0 +1,179 @@
+use super::*;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const FORMAT_VERSION: u64 = 1;
+
+// Namespace of keys shared by all repos
+pub const GLOBAL: &str = "";
+
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct KvFile {
+    version: u64,
+    namespaces: BTreeMap<String, BTreeMap<String, serde_json::Value>>,
+}
+
+// Values read by queries with the josh-kv helper, kept in one namespace per
+// upstream repo. When backed by a file, changes are written to it with the
+// PendingWrite returned by set and remove, which doesn't need the store.
+pub struct KvStore {
+    path: Option<PathBuf>,
+    file: KvFile,
+    // Counts the changes, so a write never replaces the file with an older
+    // state than the one it already has
+    generation: u64,
+    written: Arc<Mutex<u64>>,
+}
+
+// The content of the store after a change, to be written to its file
+pub struct PendingWrite {
+    path: Option<PathBuf>,
+    content: String,
+    generation: u64,
+    written: Arc<Mutex<u64>>,
+}
+
+impl PendingWrite {
+    pub fn write(self) -> JoshResult<()> {
+        let path = some_or!(&self.path, {
+            return Ok(());
+        });
+        let mut written = self.written.lock()?;
+        if *written >= self.generation {
+            return Ok(());
+        }
+        let af =
+            atomicwrites::AtomicFile::new(path, atomicwrites::AllowOverwrite);
+        af.write(|f| {
+            use std::io::Write;
+            f.write_all(self.content.as_bytes())
+        })?;
+        *written = self.generation;
+        return Ok(());
+    }
+}
+
+pub type SharedKvStore = std::sync::Arc<std::sync::RwLock<KvStore>>;
+
+impl KvStore {
+    pub fn new() -> KvStore {
+        return KvStore {
+            path: None,
+            file: KvFile {
+                version: FORMAT_VERSION,
+                namespaces: BTreeMap::new(),
+            },
+            generation: 0,
+            written: Arc::new(Mutex::new(0)),
+        };
+    }
+
+    // A missing file gives an empty store. Files holding a plain object of
+    // keys, as written by hand for josh-filter, are read into the global
+    // namespace.
+    pub fn load(path: &Path) -> JoshResult<KvStore> {
+        let mut store = KvStore::new();
+        store.path = Some(path.to_owned());
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(store);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let value: serde_json::Value =
+            serde_json::from_str(&content).map_err(|e| {
+                josh_error(&format!("cannot parse {:?}: {}", path, e))
+            })?;
+        if value.get("namespaces").is_some() {
+            store.file = serde_json::from_value(value).map_err(|e| {
+                josh_error(&format!("cannot parse {:?}: {}", path, e))
+            })?;
+            if store.file.version != FORMAT_VERSION {
+                return Err(josh_error(&format!(
+                    "{:?}: unsupported version {}",
+                    path, store.file.version
+                )));
+            }
+        } else {
+            let global = serde_json::from_value(value).map_err(|e| {
+                josh_error(&format!("cannot parse {:?}: {}", path, e))
+            })?;
+            store.file.namespaces.insert(GLOBAL.to_owned(), global);
+        }
+        return Ok(store);
+    }
+
+    fn changed(&mut self) -> JoshResult<PendingWrite> {
+        self.generation += 1;
+        return Ok(PendingWrite {
+            path: self.path.clone(),
+            content: serde_json::to_string_pretty(&self.file)?,
+            generation: self.generation,
+            written: self.written.clone(),
+        });
+    }
+
+    pub fn get(
+        &self,
+        namespace: &str,
+        key: &str,
+    ) -> Option<&serde_json::Value> {
+        return self.file.namespaces.get(namespace)?.get(key);
+    }
+
+    // Looks in the namespace first and falls back to the global one
+    pub fn lookup(
+        &self,
+        namespace: &str,
+        key: &str,
+    ) -> Option<&serde_json::Value> {
+        return self.get(&namespace, &key).or(self.get(GLOBAL, &key));
+    }
+
+    pub fn keys(&self, namespace: &str) -> Vec<String> {
+        return self
+            .file
+            .namespaces
+            .get(namespace)
+            .map(|x| x.keys().cloned().collect())
+            .unwrap_or(vec![]);
+    }
+
+    pub fn set(
+        &mut self,
+        namespace: &str,
+        key: &str,
+        value: serde_json::Value,
+    ) -> JoshResult<PendingWrite> {
+        self.file
+            .namespaces
+            .entry(namespace.to_owned())
+            .or_insert(BTreeMap::new())
+            .insert(key.to_owned(), value);
+        return self.changed();
+    }
+
+    // Returns None if there was no such key
+    pub fn remove(
+        &mut self,
+        namespace: &str,
+        key: &str,
+    ) -> JoshResult<Option<PendingWrite>> {
+        let removed = if let Some(ns) = self.file.namespaces.get_mut(namespace)
+        {
+            let removed = ns.remove(key).is_some();
+            if ns.is_empty() {
+                self.file.namespaces.remove(namespace);
+            }
+            removed
+        } else {
+            false
+        };
+        if !removed {
+            return Ok(None);
+        }
+        return Ok(Some(self.changed()?));
+    }
+}
@@ -36,6 +36,9 @@ use tracing;
 pub mod filter_cache;
 pub mod filters;
 pub mod housekeeping;
+pub mod kv_store;
+pub mod maintenance;
+pub mod progress;
 pub mod query;
 pub mod scratch;
 pub mod shell;
@@ -60,6 +63,19 @@ fn empty_tree(repo: &git2::Repository) -> git2::Tree {
     repo.find_tree(empty_tree_id()).unwrap()
 }
 
+pub fn conflict_paths(index: &git2::Index) -> JoshResult<Vec<String>> {
+    let mut paths = vec![];
+    for conflict in index.conflicts()? {
+        let conflict = conflict?;
+        if let Some(entry) =
+            conflict.our.or(conflict.their).or(conflict.ancestor)
+        {
+            paths.push(String::from_utf8_lossy(&entry.path).to_string());
+        }
+    }
+    return Ok(paths);
+}
+
 const FRAGMENT: &percent_encoding::AsciiSet = &percent_encoding::CONTROLS
     .add(b'/')
     .add(b'*')
@@ -0,0 +1,617 @@
+use super::*;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, span, warn, Level};
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Step {
+    CountObjects,
+    Repack,
+    Prune,
+}
+
+impl Step {
+    pub fn from_str(s: &str) -> JoshResult<Step> {
+        return match s {
+            "count-objects" => Ok(Step::CountObjects),
+            "repack" => Ok(Step::Repack),
+            "prune" => Ok(Step::Prune),
+            _ => Err(josh_error(&format!("unknown maintenance step: {}", s))),
+        };
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MaintenanceConfig {
+    // How often each step runs, steps not listed don't run at all
+    pub schedule: Vec<(Step, Duration)>,
+    // Loose objects that no ref reaches are only pruned once they are older
+    pub prune_expire: Duration,
+    // Globs of refs that must still resolve after repack and prune.
+    // A ref that doesn't is reported as error of the step.
+    pub keep_refs: Vec<String>,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> MaintenanceConfig {
+        return MaintenanceConfig {
+            schedule: vec![(Step::CountObjects, Duration::from_secs(60))],
+            prune_expire: Duration::from_secs(14 * 24 * 60 * 60),
+            keep_refs: vec![
+                "refs/namespaces/*".to_owned(),
+                "refs/josh/filtered/*".to_owned(),
+                "refs/josh/upstream/*".to_owned(),
+            ],
+        };
+    }
+}
+
+impl MaintenanceConfig {
+    // Parses a schedule like "count-objects=60,repack=3600,prune=3600",
+    // intervals are in seconds
+    pub fn with_schedule(mut self, s: &str) -> JoshResult<MaintenanceConfig> {
+        let mut schedule = vec![];
+        for entry in s.split(',').filter(|x| !x.is_empty()) {
+            let mut splitted = entry.splitn(2, '=');
+            let step = Step::from_str(splitted.next().unwrap_or("").trim())?;
+            let seconds = splitted
+                .next()
+                .ok_or(josh_error(&format!(
+                    "maintenance schedule: missing interval for {}",
+                    entry
+                )))?
+                .trim()
+                .parse::<u64>()
+                .map_err(|_| {
+                    josh_error(&format!(
+                        "maintenance schedule: invalid interval for {}",
+                        entry
+                    ))
+                })?;
+            schedule.push((step, Duration::from_secs(seconds)));
+        }
+        self.schedule = schedule;
+        return Ok(self);
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct StepStatus {
+    pub step: Step,
+    // Seconds since the epoch
+    pub started: u64,
+    pub duration_ms: u64,
+    pub success: bool,
+    pub output: serde_json::Value,
+    pub error: Option<String>,
+}
+
+pub type MaintenanceStatus = Arc<RwLock<BTreeMap<Step, StepStatus>>>;
+
+fn dir_size(path: &Path, filter: &dyn Fn(&Path) -> bool) -> (u64, u64) {
+    let mut count = 0;
+    let mut size = 0;
+    if let Ok(entries) = std::fs::read_dir(path) {
+        for entry in entries.filter_map(|x| x.ok()) {
+            if !filter(&entry.path()) {
+                continue;
+            }
+            if let Ok(metadata) = entry.metadata() {
+                if metadata.is_file() {
+                    count += 1;
+                    size += metadata.len();
+                }
+            }
+        }
+    }
+    return (count, size);
+}
+
+// Same numbers as "git count-objects -v", sizes are in bytes
+fn count_objects(repo_path: &Path) -> JoshResult<serde_json::Value> {
+    let objects = repo_path.join("objects");
+    let mut loose_count = 0;
+    let mut loose_size = 0;
+    for entry in std::fs::read_dir(&objects)?.filter_map(|x| x.ok()) {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.len() == 2 && name.chars().all(|x| x.is_ascii_hexdigit()) {
+            let (c, s) = dir_size(&entry.path(), &|_| true);
+            loose_count += c;
+            loose_size += s;
+        }
+    }
+
+    let is_pack =
+        |p: &Path| p.extension().map(|x| x == "pack").unwrap_or(false);
+    let (packs, pack_size) = dir_size(&objects.join("pack"), &is_pack);
+
+    return Ok(json!({
+        "count": loose_count,
+        "size": loose_size,
+        "packs": packs,
+        "size-pack": pack_size,
+    }));
+}
+
+// Object ids listed in a version 2 pack index: after the header and the
+// fanout table, whose last entry is the number of objects, come the ids
+fn pack_index_oids(idx: &Path) -> JoshResult<Vec<git2::Oid>> {
+    let data = std::fs::read(idx)?;
+    let invalid = || josh_error(&format!("invalid pack index {:?}", idx));
+    if data.len() < 8 + 256 * 4
+        || data[..8] != [0xff, b't', b'O', b'c', 0, 0, 0, 2]
+    {
+        return Err(invalid());
+    }
+    let mut count = [0u8; 4];
+    count.copy_from_slice(&data[8 + 255 * 4..8 + 256 * 4]);
+    let count = u32::from_be_bytes(count) as usize;
+    let start = 8 + 256 * 4;
+    if data.len() < start + count * 20 {
+        return Err(invalid());
+    }
+    let mut oids = vec![];
+    for i in 0..count {
+        oids.push(git2::Oid::from_bytes(
+            &data[start + i * 20..start + (i + 1) * 20],
+        )?);
+    }
+    return Ok(oids);
+}
+
+// Names ("pack-<hash>") of the packs in pack_dir, and whether each has a
+// .keep file
+fn list_packs(pack_dir: &Path) -> JoshResult<Vec<(String, bool)>> {
+    let mut packs = vec![];
+    for entry in std::fs::read_dir(&pack_dir)?.filter_map(|x| x.ok()) {
+        let path = entry.path();
+        if path.extension().map(|x| x != "pack").unwrap_or(true) {
+            continue;
+        }
+        let name = some_or!(path.file_stem().and_then(|x| x.to_str()), {
+            continue;
+        });
+        packs.push((
+            name.to_owned(),
+            pack_dir.join(format!("{}.keep", name)).exists(),
+        ));
+    }
+    return Ok(packs);
+}
+
+// Writes the objects of pb into a new pack in the object database of repo.
+// Returns the name of the pack, None if pb is empty.
+fn write_pack(
+    repo: &git2::Repository,
+    pb: &mut git2::PackBuilder,
+) -> JoshResult<Option<String>> {
+    if pb.object_count() == 0 {
+        return Ok(None);
+    }
+    let pack_dir = repo.path().join("objects").join("pack");
+    let before = list_packs(&pack_dir)?;
+
+    let odb = repo.odb()?;
+    let mut writer = odb.packwriter()?;
+    let mut written = Ok(());
+    let mut trailer = vec![];
+    pb.foreach(|data| {
+        use std::io::Write;
+        trailer.extend_from_slice(data);
+        let start = trailer.len().saturating_sub(20);
+        trailer.drain(..start);
+        written = writer.write_all(data);
+        written.is_ok()
+    })?;
+    written?;
+    writer.commit()?;
+
+    // The pack is named by the indexer, it's the new one ending with the
+    // checksum that was written last
+    for (name, _) in list_packs(&pack_dir)? {
+        if before.iter().any(|(x, _)| *x == name) {
+            continue;
+        }
+        let data = std::fs::read(pack_dir.join(format!("{}.pack", name)))?;
+        if data.ends_with(&trailer) {
+            return Ok(Some(name));
+        }
+    }
+    return Err(josh_error("written pack not found"));
+}
+
+// Removes a pack, the index first so it isn't found anymore while the
+// pack itself is removed
+fn remove_pack(pack_dir: &Path, name: &str) -> JoshResult<()> {
+    for ext in ["idx", "pack", "bitmap", "rev"].iter() {
+        let path = pack_dir.join(format!("{}.{}", name, ext));
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    return Ok(());
+}
+
+// Ids and paths of the loose objects of the repo
+fn loose_objects(repo_path: &Path) -> JoshResult<Vec<(git2::Oid, PathBuf)>> {
+    let mut objects = vec![];
+    for dir in
+        std::fs::read_dir(repo_path.join("objects"))?.filter_map(|x| x.ok())
+    {
+        let prefix = dir.file_name().to_string_lossy().to_string();
+        if prefix.len() != 2 || !prefix.chars().all(|x| x.is_ascii_hexdigit()) {
+            continue;
+        }
+        for entry in std::fs::read_dir(dir.path())?.filter_map(|x| x.ok()) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Ok(oid) = git2::Oid::from_str(&format!("{}{}", prefix, name))
+            {
+                objects.push((oid, entry.path()));
+            }
+        }
+    }
+    return Ok(objects);
+}
+
+// Marks the first line of the .keep files of packs written by write_keep_pack
+const KEEP_MARKER: &str = "josh maintenance";
+
+/**
+ * Writes the objects needed by the filter cache maps and the request
+ * namespaces that no other ref reaches into a pack with a .keep file, so
+ * repack and prune don't drop them. Replaces the pack of the previous run.
+ */
+fn write_keep_pack(
+    repo_path: &Path,
+    maps: &[Arc<RwLock<filter_cache::FilterCache>>],
+) -> JoshResult<usize> {
+    let _trace_s = span!(Level::TRACE, "maintenance: write_keep_pack");
+    let repo = git2::Repository::open(repo_path)?;
+    let odb = repo.odb()?;
+
+    let mut keep = std::collections::HashSet::new();
+    for m in maps.iter() {
+        keep.extend(m.read()?.oids());
+    }
+    let mut walk = repo.revwalk()?;
+    for reference in repo.references()? {
+        let reference = reference?;
+        let target = some_or!(reference.target(), {
+            continue;
+        });
+        if reference
+            .name()
+            .unwrap_or("")
+            .starts_with("refs/namespaces/")
+        {
+            keep.insert(target);
+        } else if let Ok(commit) = reference.peel_to_commit() {
+            walk.hide(commit.id())?;
+        }
+    }
+    keep.retain(|x| odb.exists(*x));
+
+    let mut pb = repo.packbuilder()?;
+    for oid in keep.iter() {
+        if odb.read_header(*oid)?.1 == git2::ObjectType::Commit {
+            walk.push(*oid)?;
+        } else {
+            pb.insert_recursive(*oid, None)?;
+        }
+    }
+    pb.insert_walk(&mut walk)?;
+
+    let pack_dir = repo_path.join("objects").join("pack");
+    let written = write_pack(&repo, &mut pb)?;
+    if let Some(name) = &written {
+        std::fs::write(
+            pack_dir.join(format!("{}.keep", name)),
+            format!("{}\n", KEEP_MARKER),
+        )?;
+    }
+
+    // Removing the .keep of the previous pack lets the next repack fold it
+    // into the main pack
+    for entry in std::fs::read_dir(&pack_dir)?.filter_map(|x| x.ok()) {
+        let path = entry.path();
+        if path.extension().map(|x| x != "keep").unwrap_or(true)
+            || path.file_stem().and_then(|x| x.to_str()) == written.as_deref()
+        {
+            continue;
+        }
+        let content = std::fs::read_to_string(&path).unwrap_or_default();
+        if content.lines().next() == Some(KEEP_MARKER) {
+            std::fs::remove_file(&path)?;
+        }
+    }
+
+    return Ok(keep.len());
+}
+
+/**
+ * Packs all objects, also unreachable ones, into one new pack and removes
+ * the packs and loose objects it replaces. Packs with a .keep file stay as
+ * they are. Objects written while this runs are left alone.
+ */
+fn repack(repo_path: &Path) -> JoshResult<serde_json::Value> {
+    let _trace_s = span!(Level::TRACE, "maintenance: repack");
+    let repo = git2::Repository::open(repo_path)?;
+    let pack_dir = repo_path.join("objects").join("pack");
+    let packs = list_packs(&pack_dir)?;
+
+    let mut kept = std::collections::HashSet::new();
+    for (name, _) in packs.iter().filter(|(_, keep)| *keep) {
+        kept.extend(pack_index_oids(&pack_dir.join(format!("{}.idx", name)))?);
+    }
+
+    let mut pb = repo.packbuilder()?;
+
+    // Reachable objects first, in history order, which gives better deltas
+    let mut walk = repo.revwalk()?;
+    for reference in repo.references()? {
+        if let Ok(commit) = reference?.peel_to_commit() {
+            if !kept.contains(&commit.id()) {
+                walk.push(commit.id())?;
+            }
+        }
+    }
+    for oid in kept.iter() {
+        if repo.find_commit(*oid).is_ok() {
+            walk.hide(*oid).ok();
+        }
+    }
+    pb.insert_walk(&mut walk)?;
+
+    let mut objects = vec![];
+    repo.odb()?.foreach(|oid| {
+        if !kept.contains(oid) {
+            objects.push(*oid);
+        }
+        true
+    })?;
+    for oid in objects.iter() {
+        pb.insert_object(*oid, None)?;
+    }
+
+    let written = write_pack(&repo, &mut pb)?;
+    let packed = pb.object_count();
+
+    let mut removed_packs = 0;
+    for (name, keep) in packs.iter() {
+        if !keep && Some(name) != written.as_ref() {
+            remove_pack(&pack_dir, &name)?;
+            removed_packs += 1;
+        }
+    }
+
+    let objects: std::collections::HashSet<_> = objects.into_iter().collect();
+    let mut removed_loose = 0;
+    for (oid, path) in loose_objects(&repo_path)? {
+        if objects.contains(&oid) || kept.contains(&oid) {
+            std::fs::remove_file(&path)?;
+            removed_loose += 1;
+        }
+    }
+
+    return Ok(json!({
+        "packed_objects": packed,
+        "removed_packs": removed_packs,
+        "removed_loose_objects": removed_loose,
+    }));
+}
+
+// All objects reachable from the refs of the repo
+fn reachable_objects(
+    repo: &git2::Repository,
+) -> JoshResult<std::collections::HashSet<git2::Oid>> {
+    let mut reachable = std::collections::HashSet::new();
+    let mut walk = repo.revwalk()?;
+    for reference in repo.references()? {
+        let reference = reference?;
+        let mut object = some_or!(reference.target(), {
+            continue;
+        });
+        // Annotated tags and what they point to
+        while let Ok(tag) = repo.find_tag(object) {
+            reachable.insert(object);
+            object = tag.target_id();
+        }
+        reachable.insert(object);
+        if repo.find_commit(object).is_ok() {
+            walk.push(object)?;
+        } else if let Ok(tree) = repo.find_tree(object) {
+            add_tree(&tree, &mut reachable)?;
+        }
+    }
+    for oid in walk {
+        let commit = repo.find_commit(oid?)?;
+        reachable.insert(commit.id());
+        if reachable.insert(commit.tree_id()) {
+            add_tree(&commit.tree()?, &mut reachable)?;
+        }
+    }
+    return Ok(reachable);
+}
+
+fn add_tree(
+    tree: &git2::Tree,
+    reachable: &mut std::collections::HashSet<git2::Oid>,
+) -> JoshResult<()> {
+    tree.walk(git2::TreeWalkMode::PreOrder, |_, entry| {
+        // Submodule commits are not part of this repo
+        if entry.kind() == Some(git2::ObjectType::Commit) {
+            return git2::TreeWalkResult::Ok;
+        }
+        if !reachable.insert(entry.id())
+            && entry.kind() == Some(git2::ObjectType::Tree)
+        {
+            return git2::TreeWalkResult::Skip;
+        }
+        git2::TreeWalkResult::Ok
+    })?;
+    return Ok(());
+}
+
+/**
+ * Removes loose objects older than config.prune_expire that no ref
+ * reaches. Packed objects are left alone, repack takes care of those.
+ */
+fn prune(
+    repo_path: &Path,
+    config: &MaintenanceConfig,
+) -> JoshResult<serde_json::Value> {
+    let _trace_s = span!(Level::TRACE, "maintenance: prune");
+    let repo = git2::Repository::open(repo_path)?;
+    let now = SystemTime::now();
+    let mut expired = vec![];
+    for (oid, path) in loose_objects(&repo_path)? {
+        let modified = std::fs::metadata(&path)?.modified()?;
+        let age = now.duration_since(modified).unwrap_or_default();
+        if age > config.prune_expire {
+            expired.push((oid, path));
+        }
+    }
+
+    // Only walk the history if there is anything to prune
+    let mut pruned = 0;
+    if !expired.is_empty() {
+        let reachable = reachable_objects(&repo)?;
+        for (oid, path) in expired {
+            if !reachable.contains(&oid) {
+                std::fs::remove_file(&path)?;
+                pruned += 1;
+            }
+        }
+    }
+
+    return Ok(json!({
+        "pruned_objects": pruned,
+    }));
+}
+
+fn check_kept_refs(
+    repo_path: &Path,
+    config: &MaintenanceConfig,
+) -> JoshResult<usize> {
+    let repo = git2::Repository::open(repo_path)?;
+    let mut kept = 0;
+    let mut missing = vec![];
+    for glob in config.keep_refs.iter() {
+        for reference in repo.references_glob(&glob)? {
+            let reference = reference?;
+            let name = reference.name().unwrap_or("").to_owned();
+            match reference.peel(git2::ObjectType::Any) {
+                Ok(_) => kept += 1,
+                Err(_) => missing.push(name),
+            }
+        }
+    }
+    if !missing.is_empty() {
+        return Err(josh_error(&format!(
+            "objects missing for refs: {}",
+            missing.join(", ")
+        )));
+    }
+    return Ok(kept);
+}
+
+pub fn run_step(
+    repo_path: &Path,
+    config: &MaintenanceConfig,
+    step: Step,
+    maps: &[Arc<RwLock<filter_cache::FilterCache>>],
+) -> StepStatus {
+    let _trace_s = span!(Level::TRACE, "maintenance: run_step", ?step);
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0);
+    let timer = Instant::now();
+
+    let result = match step {
+        Step::CountObjects => count_objects(&repo_path),
+        Step::Repack => write_keep_pack(&repo_path, &maps).and_then(|kept| {
+            let mut output = repack(&repo_path)?;
+            output["kept_objects"] = json!(kept);
+            output["kept_refs"] = json!(check_kept_refs(&repo_path, &config)?);
+            Ok(output)
+        }),
+        Step::Prune => write_keep_pack(&repo_path, &maps).and_then(|kept| {
+            let mut output = prune(&repo_path, &config)?;
+            output["kept_objects"] = json!(kept);
+            output["kept_refs"] = json!(check_kept_refs(&repo_path, &config)?);
+            Ok(output)
+        }),
+    };
+
+    let status = StepStatus {
+        step: step,
+        started: started,
+        duration_ms: timer.elapsed().as_millis() as u64,
+        success: result.is_ok(),
+        output: result.clone().unwrap_or(json!({})),
+        error: result.err().map(|JoshError(x)| x),
+    };
+    if status.success {
+        info!("maintenance: {:?}", status);
+    } else {
+        warn!("maintenance: {:?}", status);
+    }
+    return status;
+}
+
+// Runs all steps of the schedule once, in the order given
+pub fn run_all(
+    repo_path: &Path,
+    config: &MaintenanceConfig,
+    maps: &[Arc<RwLock<filter_cache::FilterCache>>],
+) -> Vec<StepStatus> {
+    return config
+        .schedule
+        .iter()
+        .map(|(step, _)| run_step(&repo_path, &config, *step, &maps))
+        .collect();
+}
+
+// Runs the steps on their schedule in a thread of their own, so filtering
+// can continue while for example a repack is running. Each step first runs
+// one interval after startup.
+pub fn spawn_thread(
+    repo_path: PathBuf,
+    config: MaintenanceConfig,
+    status: MaintenanceStatus,
+    maps: Vec<Arc<RwLock<filter_cache::FilterCache>>>,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let started = Instant::now();
+        let mut last_run: BTreeMap<Step, Instant> = BTreeMap::new();
+        loop {
+            for (step, interval) in config.schedule.iter() {
+                let due = last_run.get(step).unwrap_or(&started).elapsed()
+                    >= *interval;
+                if !due {
+                    continue;
+                }
+                let s = run_step(&repo_path, &config, *step, &maps);
+                last_run.insert(*step, Instant::now());
+                if let Ok(mut status) = status.write() {
+                    status.insert(*step, s);
+                }
+            }
+            std::thread::sleep(Duration::from_secs(10));
+        }
+    })
+}
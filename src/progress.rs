@@ -0,0 +1,82 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// Shared flag to stop a running filter operation from another thread
+#[derive(Clone, Default, Debug)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        return CancellationToken::default();
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        return self.0.load(Ordering::SeqCst);
+    }
+}
+
+// Cancels the token when dropped, for example when the future holding it
+// is aborted
+pub struct CancelOnDrop(pub CancellationToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+pub trait ProgressObserver: Send + Sync {
+    // Called for every commit walked while filtering history.
+    // total is the number of commits in the walk, including those that are
+    // already cached
+    fn progress(&self, filter_spec: &str, walked: usize, total: usize);
+}
+
+impl<F> ProgressObserver for F
+where
+    F: Fn(&str, usize, usize) + Send + Sync,
+{
+    fn progress(&self, filter_spec: &str, walked: usize, total: usize) {
+        self(filter_spec, walked, total);
+    }
+}
+
+// Attached to the forward map FilterCache via FilterCache::set_progress to
+// observe and cancel all filtering done using that cache
+pub struct Progress {
+    observer: Option<Box<dyn ProgressObserver>>,
+    token: CancellationToken,
+}
+
+impl Progress {
+    pub fn new(
+        observer: Option<Box<dyn ProgressObserver>>,
+        token: CancellationToken,
+    ) -> Progress {
+        return Progress { observer, token };
+    }
+
+    pub fn report(
+        &self,
+        filter_spec: &str,
+        walked: usize,
+        total: usize,
+    ) -> super::JoshResult<()> {
+        self.check()?;
+        if let Some(observer) = &self.observer {
+            observer.progress(filter_spec, walked, total);
+        }
+        return Ok(());
+    }
+
+    pub fn check(&self) -> super::JoshResult<()> {
+        if self.token.is_cancelled() {
+            return Err(super::josh_error("filtering cancelled"));
+        }
+        return Ok(());
+    }
+}
@@ -1,88 +1,215 @@
-use std::sync::{Arc, RwLock};
-struct BlobHelper {
-    repo_path: std::path::PathBuf,
-    headref: String,
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    // Compiled templates by blob oid, so the same template isn't parsed
+    // again for every request
+    static ref TEMPLATES: RwLock<HashMap<git2::Oid, handlebars::Template>> =
+        RwLock::new(HashMap::new());
+
+    // Registry with the helpers that don't need a repository, shared by
+    // all renders
+    static ref HANDLEBARS: handlebars::Handlebars<'static> = {
+        let mut handlebars = handlebars::Handlebars::new();
+        handlebars.register_helper("concat", Box::new(concat_helper));
+        handlebars.register_helper("toml-parse", Box::new(toml_helper));
+        handlebars
+    };
 }
 
-impl BlobHelper {
-    fn josh_helper(
-        &self,
-        params: &[handlebars::PathAndJson],
-    ) -> super::JoshResult<serde_json::Value> {
-        let path = if let [f, ..] = params {
-            f.render()
-        } else {
-            return Err(super::josh_error("missing pattern"));
-        };
+const MAX_CACHED_TEMPLATES: usize = 1000;
 
-        let repo = git2::Repository::init(&self.repo_path)?;
-        let tree = repo.find_reference(&self.headref)?.peel_to_tree()?;
+// Errors caused by QueryLimits start with this, so callers can tell them
+// apart from other failures and report them to the client
+pub const LIMIT_EXCEEDED: &str = "query limit exceeded";
 
-        let blob = tree
-            .get_path(&std::path::PathBuf::from(path))?
-            .to_object(&repo)?
-            .peel_to_blob()?;
-        return Ok(json!(String::from_utf8(blob.content().to_vec())?));
+// Bounds for rendering a single query. Templates come from the repository,
+// so anyone able to push can make the server run them.
+#[derive(Debug, Clone)]
+pub struct QueryLimits {
+    // Wall time for rendering, checked on every helper call and while
+    // josh-filter walks history
+    pub timeout: Duration,
+    pub max_helper_calls: usize,
+    // Size of the rendered output in bytes
+    pub max_output: usize,
+    // Names of the git-* and josh-* helpers templates may use, all of
+    // them if None
+    pub helpers: Option<Vec<String>>,
+}
+
+impl Default for QueryLimits {
+    fn default() -> QueryLimits {
+        return QueryLimits {
+            timeout: Duration::from_secs(30),
+            max_helper_calls: 10000,
+            max_output: 16 * 1024 * 1024,
+            helpers: None,
+        };
     }
 }
 
-impl handlebars::HelperDef for BlobHelper {
-    fn call_inner<'reg: 'rc, 'rc>(
-        &self,
-        h: &handlebars::Helper,
-        _: &handlebars::Handlebars,
-        _: &handlebars::Context,
-        _rc: &mut handlebars::RenderContext,
-    ) -> Result<
-        Option<handlebars::ScopedJson<'reg, 'rc>>,
-        handlebars::RenderError,
-    > {
-        return Ok(Some(handlebars::ScopedJson::Derived(
-            self.josh_helper(h.params().as_slice())
-                .map_err(|_| handlebars::RenderError::new("josh"))?,
-        )));
+fn limit_error(what: &str) -> super::JoshError {
+    return super::josh_error(&format!("{}: {}", LIMIT_EXCEEDED, what));
+}
+
+fn timeout_message(limits: &QueryLimits) -> String {
+    return format!("rendering took longer than {}s", limits.timeout.as_secs());
+}
+
+// Deadline and output size of the built-in queries, which build their
+// output without going through LimitedWriter. The size counts the data
+// put into the output, before it is formatted as JSON.
+struct Budget<'a> {
+    limits: &'a QueryLimits,
+    deadline: Instant,
+    output: std::cell::Cell<usize>,
+}
+
+impl<'a> Budget<'a> {
+    fn new(limits: &'a QueryLimits) -> Budget<'a> {
+        return Budget {
+            limits: limits,
+            deadline: Instant::now() + limits.timeout,
+            output: std::cell::Cell::new(0),
+        };
+    }
+
+    fn check_time(&self) -> super::JoshResult<()> {
+        if Instant::now() > self.deadline {
+            return Err(limit_error(&timeout_message(&self.limits)));
+        }
+        return Ok(());
+    }
+
+    fn add_output(&self, size: usize) -> super::JoshResult<()> {
+        self.output.set(self.output.get() + size);
+        if self.output.get() > self.limits.max_output {
+            return Err(output_limit_error(&self.limits));
+        }
+        return self.check_time();
     }
 }
 
-struct FindFilesHelper {
-    repo_path: std::path::PathBuf,
+// State shared by all helpers while rendering one query: the caller's
+// repository handle and the resolved head of the queried ref
+struct RenderContext<'a> {
+    repo: &'a git2::Repository,
     headref: String,
+    head: git2::Oid,
+    tree: git2::Oid,
+    kv_store: super::kv_store::SharedKvStore,
+    kv_namespace: String,
+    forward_maps: Arc<RwLock<super::filter_cache::FilterCache>>,
+    backward_maps: Arc<RwLock<super::filter_cache::FilterCache>>,
+    limits: QueryLimits,
+    deadline: Instant,
+    helper_calls: AtomicUsize,
+    // Size of the data returned by helpers that can produce a lot of it
+    output: AtomicUsize,
+    // The first limit hit by a helper. Handlebars only passes on its own
+    // error type, so the reason is kept here for render to report.
+    exceeded: Mutex<Option<String>>,
 }
 
-impl FindFilesHelper {
-    fn josh_helper(
-        &self,
-        params: &[handlebars::PathAndJson],
-    ) -> super::JoshResult<serde_json::Value> {
-        let filename = if let [f, ..] = params {
-            regex::Regex::new(&f.render())?
-        } else {
-            return Err(super::josh_error("missing pattern"));
-        };
-        let repo = git2::Repository::init(&self.repo_path)?;
-        let tree = repo.find_reference(&self.headref)?.peel_to_tree()?;
-
-        let mut names = vec![];
-
-        tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
-            let name = entry.name().unwrap();
-            if filename.is_match(name) {
-                let path = std::path::PathBuf::from(root).join(name);
-                names.push(json!({
-                "path": path.to_string_lossy(),
-                "name": path.file_name().unwrap().to_str(),
-                "base": path.parent().unwrap().to_string_lossy(),
-                "sha1": format!("{}", entry.id()),
-                }));
+impl<'a> RenderContext<'a> {
+    fn exceed(&self, what: &str) -> super::JoshError {
+        let super::JoshError(msg) = limit_error(&what);
+        if let Ok(mut exceeded) = self.exceeded.lock() {
+            exceeded.get_or_insert(msg.clone());
+        }
+        return super::josh_error(&msg);
+    }
+
+    fn check_time(&self) -> super::JoshResult<()> {
+        if Instant::now() > self.deadline {
+            return Err(self.exceed(&timeout_message(&self.limits)));
+        }
+        return Ok(());
+    }
+
+    fn add_output(&self, size: usize) -> super::JoshResult<()> {
+        let output = self.output.fetch_add(size, Ordering::SeqCst) + size;
+        if output > self.limits.max_output {
+            return Err(self.exceed(&format!(
+                "output larger than {} bytes",
+                self.limits.max_output
+            )));
+        }
+        return self.check_time();
+    }
+
+    fn check_call(&self, name: &str) -> super::JoshResult<()> {
+        if let Some(helpers) = &self.limits.helpers {
+            if !helpers.iter().any(|x| x == name) {
+                return Err(
+                    self.exceed(&format!("helper {:?} is not allowed", name))
+                );
             }
-            git2::TreeWalkResult::Ok
-        })?;
+        }
+        let calls = self.helper_calls.fetch_add(1, Ordering::SeqCst) + 1;
+        if calls > self.limits.max_helper_calls {
+            return Err(self.exceed(&format!(
+                "more than {} helper calls",
+                self.limits.max_helper_calls
+            )));
+        }
+        return self.check_time();
+    }
+}
 
-        return Ok(json!(names));
+// Collects the rendered output, failing once it grows beyond max bytes
+struct LimitedWriter {
+    buf: Vec<u8>,
+    max: usize,
+    exceeded: bool,
+}
+
+impl handlebars::Output for LimitedWriter {
+    fn write(&mut self, data: &str) -> std::io::Result<()> {
+        if self.buf.len() + data.len() > self.max {
+            self.exceeded = true;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                LIMIT_EXCEEDED,
+            ));
+        }
+        self.buf.extend_from_slice(data.as_bytes());
+        return Ok(());
     }
 }
 
-impl handlebars::HelperDef for FindFilesHelper {
+fn output_limit_error(limits: &QueryLimits) -> super::JoshError {
+    return limit_error(&format!(
+        "output larger than {} bytes",
+        limits.max_output
+    ));
+}
+
+fn check_output(
+    output: String,
+    limits: &QueryLimits,
+) -> super::JoshResult<String> {
+    if output.len() > limits.max_output {
+        return Err(output_limit_error(&limits));
+    }
+    return Ok(output);
+}
+
+type HelperFn = fn(
+    &RenderContext,
+    &handlebars::Helper,
+) -> super::JoshResult<serde_json::Value>;
+
+struct JoshHelper<'a> {
+    ctx: &'a RenderContext<'a>,
+    name: &'static str,
+    f: HelperFn,
+}
+
+impl<'a> handlebars::HelperDef for JoshHelper<'a> {
     fn call_inner<'reg: 'rc, 'rc>(
         &self,
         h: &handlebars::Helper,
@@ -94,141 +221,567 @@ impl handlebars::HelperDef for FindFilesHelper {
         handlebars::RenderError,
     > {
         return Ok(Some(handlebars::ScopedJson::Derived(
-            self.josh_helper(h.params().as_slice())
+            self.ctx
+                .check_call(&self.name)
+                .and_then(|_| (self.f)(self.ctx, &h))
                 .map_err(|_| handlebars::RenderError::new("josh"))?,
         )));
     }
 }
 
-struct FilterHelper {
-    repo_path: std::path::PathBuf,
-    headref: String,
-    forward_maps:
-        std::sync::Arc<std::sync::RwLock<super::filter_cache::FilterCache>>,
-    backward_maps:
-        std::sync::Arc<std::sync::RwLock<super::filter_cache::FilterCache>>,
+fn first_param(
+    h: &handlebars::Helper,
+    missing: &str,
+) -> super::JoshResult<String> {
+    return h
+        .param(0)
+        .map(|x| x.render())
+        .ok_or(super::josh_error(missing));
 }
 
-impl FilterHelper {
-    fn josh_helper(
-        &self,
-        params: &[handlebars::PathAndJson],
-    ) -> super::JoshResult<serde_json::Value> {
-        let filter_spec = if let [f, ..] = params {
-            f.render()
-        } else {
-            return Err(super::josh_error("missing spec"));
-        };
-        let repo = git2::Repository::init(&self.repo_path)?;
-        let original_commit =
-            repo.find_reference(&self.headref)?.peel_to_commit()?;
-        let filterobj = super::filters::parse(&filter_spec);
-        let filter_commit = filterobj.apply_to_commit(
-            &repo,
-            &original_commit,
-            &mut self.forward_maps.write().unwrap(),
-            &mut self.backward_maps.write().unwrap(),
-            &mut std::collections::HashMap::new(),
-        )?;
-        return Ok(json!({ "sha1": format!("{}", filter_commit) }));
+// The commit named by the optional "rev" argument of a helper, HEAD if
+// not given
+fn rev_commit<'a>(
+    ctx: &RenderContext,
+    repo: &'a git2::Repository,
+    h: &handlebars::Helper,
+) -> super::JoshResult<git2::Commit<'a>> {
+    let rev = hash_str(&h.hash(), "rev");
+    if rev == "" {
+        return Ok(repo.find_commit(ctx.head)?);
     }
+    return resolve_commit(&repo, &ctx.headref, &rev);
 }
 
-impl handlebars::HelperDef for FilterHelper {
-    fn call_inner<'reg: 'rc, 'rc>(
-        &self,
-        h: &handlebars::Helper,
-        _: &handlebars::Handlebars,
-        _: &handlebars::Context,
-        _rc: &mut handlebars::RenderContext,
-    ) -> Result<
-        Option<handlebars::ScopedJson<'reg, 'rc>>,
-        handlebars::RenderError,
-    > {
-        return Ok(Some(handlebars::ScopedJson::Derived(
-            self.josh_helper(h.params().as_slice())
-                .map_err(|_| handlebars::RenderError::new("josh"))?,
-        )));
+fn rev_tree<'a>(
+    ctx: &RenderContext,
+    repo: &'a git2::Repository,
+    h: &handlebars::Helper,
+) -> super::JoshResult<git2::Tree<'a>> {
+    if hash_str(&h.hash(), "rev") == "" {
+        return Ok(repo.find_tree(ctx.tree)?);
     }
+    return Ok(rev_commit(&ctx, &repo, &h)?.tree()?);
 }
 
-struct KvHelper {
-    kv_store: Arc<RwLock<std::collections::HashMap<String, serde_json::Value>>>,
+fn git_blob(
+    ctx: &RenderContext,
+    h: &handlebars::Helper,
+) -> super::JoshResult<serde_json::Value> {
+    let path = first_param(&h, "missing pattern")?;
+    let repo = ctx.repo;
+    let blob = rev_tree(&ctx, &repo, &h)?
+        .get_path(&std::path::PathBuf::from(path))?
+        .to_object(&repo)?
+        .peel_to_blob()?;
+    return Ok(json!(String::from_utf8(blob.content().to_vec())?));
 }
 
-impl KvHelper {
-    fn josh_helper(
-        &self,
-        params: &[handlebars::PathAndJson],
-    ) -> super::JoshResult<serde_json::Value> {
-        let key = if let [f, ..] = params {
-            f.render()
-        } else {
-            return Err(super::josh_error("missing spec"));
+fn git_find(
+    ctx: &RenderContext,
+    h: &handlebars::Helper,
+) -> super::JoshResult<serde_json::Value> {
+    let filename = regex::Regex::new(&first_param(&h, "missing pattern")?)?;
+    let repo = ctx.repo;
+    let tree = rev_tree(&ctx, &repo, &h)?;
+
+    let mut names = vec![];
+
+    tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+        let name = entry.name().unwrap();
+        if filename.is_match(name) {
+            let path = std::path::PathBuf::from(root).join(name);
+            names.push(json!({
+            "path": path.to_string_lossy(),
+            "name": path.file_name().unwrap().to_str(),
+            "base": path.parent().unwrap().to_string_lossy(),
+            "sha1": format!("{}", entry.id()),
+            }));
+        }
+        git2::TreeWalkResult::Ok
+    })?;
+    return Ok(json!(names));
+}
+
+fn josh_filter(
+    ctx: &RenderContext,
+    h: &handlebars::Helper,
+) -> super::JoshResult<serde_json::Value> {
+    let filter_spec = first_param(&h, "missing spec")?;
+    let repo = ctx.repo;
+    let original_commit = rev_commit(&ctx, &repo, &h)?;
+    let filterobj = super::filters::parse(&filter_spec);
+
+    // Filter into caches of our own, so the shared ones are only locked
+    // for the merge at the end, and stop the history walk at the deadline
+    let token = super::progress::CancellationToken::new();
+    let cancel = token.clone();
+    let deadline = ctx.deadline;
+    let mut fm = super::filter_cache::new_downstream(&ctx.forward_maps);
+    let mut bm = super::filter_cache::new_downstream(&ctx.backward_maps);
+    fm.set_progress(Arc::new(super::progress::Progress::new(
+        Some(Box::new(move |_: &str, _: usize, _: usize| {
+            if Instant::now() > deadline {
+                cancel.cancel();
+            }
+        })),
+        token,
+    )));
+
+    let filter_commit = filterobj.apply_to_commit(
+        &repo,
+        &original_commit,
+        &mut fm,
+        &mut bm,
+        &mut std::collections::HashMap::new(),
+    );
+    ctx.check_time()?;
+    let filter_commit = filter_commit?;
+    super::filter_cache::try_merge_both(
+        ctx.forward_maps.clone(),
+        ctx.backward_maps.clone(),
+        &fm,
+        &bm,
+    );
+    return Ok(json!({ "sha1": format!("{}", filter_commit) }));
+}
+
+fn josh_kv(
+    ctx: &RenderContext,
+    h: &handlebars::Helper,
+) -> super::JoshResult<serde_json::Value> {
+    let key = first_param(&h, "missing spec")?;
+
+    if let Some(v) = ctx.kv_store.read()?.lookup(&ctx.kv_namespace, &key) {
+        return Ok(v.to_owned());
+    } else {
+        return Ok(json!(""));
+    }
+}
+
+// "refs/namespaces/<name>/" if headref is inside a namespace
+fn namespace_prefix(headref: &str) -> String {
+    if headref.starts_with("refs/namespaces/") {
+        let name = headref.splitn(4, '/').nth(2).unwrap_or("");
+        return format!("refs/namespaces/{}/", name);
+    }
+    return "".to_owned();
+}
+
+// Resolves a revision for the helpers. "HEAD" stands for the ref the query
+// runs on, other names are looked up as ref, branch or tag in the
+// namespace of that ref before trying them as sha. Only commits reachable
+// from a ref of the namespace are found, so templates can't read other
+// refs of the repo.
+fn resolve_commit<'a>(
+    repo: &'a git2::Repository,
+    headref: &str,
+    rev: &str,
+) -> super::JoshResult<git2::Commit<'a>> {
+    let rev = if rev == "" { "HEAD" } else { rev };
+    let ns = namespace_prefix(&headref);
+
+    let candidates = if rev.starts_with("HEAD") {
+        vec![rev.replacen("HEAD", &headref, 1)]
+    } else {
+        vec![
+            format!("{}{}", ns, rev),
+            format!("{}refs/heads/{}", ns, rev),
+            format!("{}refs/tags/{}", ns, rev),
+            rev.to_owned(),
+        ]
+    };
+    let commit = some_or!(
+        candidates.iter().find_map(|x| repo
+            .revparse_single(&x)
+            .and_then(|x| x.peel_to_commit())
+            .ok()),
+        {
+            return Err(super::josh_error(&format!("{:?} not found", rev)));
+        }
+    );
+
+    let tips = if ns == "" {
+        vec![repo.find_reference(&headref)?.peel_to_commit()?.id()]
+    } else {
+        repo.references_glob(&format!("{}*", ns))?
+            .filter_map(|x| x.ok()?.peel_to_commit().ok())
+            .map(|x| x.id())
+            .collect()
+    };
+    for tip in tips {
+        if tip == commit.id() || repo.graph_descendant_of(tip, commit.id())? {
+            return Ok(commit);
+        }
+    }
+    return Err(super::josh_error(&format!(
+        "{:?} is not reachable from the queried refs",
+        rev
+    )));
+}
+
+// "from..to" or just "to"
+fn parse_range<'a>(
+    repo: &'a git2::Repository,
+    headref: &str,
+    range: &str,
+) -> super::JoshResult<(Option<git2::Commit<'a>>, git2::Commit<'a>)> {
+    let mut split = range.splitn(2, "..");
+    let first = split.next().unwrap_or("");
+    return Ok(if let Some(to) = split.next() {
+        (
+            Some(resolve_commit(&repo, &headref, &first)?),
+            resolve_commit(&repo, &headref, &to)?,
+        )
+    } else {
+        (None, resolve_commit(&repo, &headref, &first)?)
+    });
+}
+
+fn hash_str(
+    hash: &BTreeMap<&str, handlebars::PathAndJson>,
+    key: &str,
+) -> String {
+    return hash.get(key).map(|x| x.render()).unwrap_or("".to_owned());
+}
+
+// Diff of commit against its first parent, or the empty tree for root
+// commits
+fn first_parent_diff<'a>(
+    repo: &'a git2::Repository,
+    commit: &git2::Commit,
+) -> super::JoshResult<git2::Diff<'a>> {
+    let parent_tree = commit.parent(0).and_then(|x| x.tree()).ok();
+    return Ok(repo.diff_tree_to_tree(
+        parent_tree.as_ref(),
+        Some(&commit.tree()?),
+        None,
+    )?);
+}
+
+// Diff for a range as accepted by parse_range, with renames detected.
+// Without a start of the range the end is compared with its first parent.
+fn range_diff<'a>(
+    repo: &'a git2::Repository,
+    headref: &str,
+    range: &str,
+) -> super::JoshResult<(Option<git2::Oid>, git2::Oid, git2::Diff<'a>)> {
+    let (from, to) = parse_range(&repo, &headref, &range)?;
+    let (from_id, mut diff) = if let Some(from) = from {
+        (
+            Some(from.id()),
+            repo.diff_tree_to_tree(
+                Some(&from.tree()?),
+                Some(&to.tree()?),
+                None,
+            )?,
+        )
+    } else {
+        (to.parent_id(0).ok(), first_parent_diff(&repo, &to)?)
+    };
+    diff.find_similar(Some(git2::DiffFindOptions::new().renames(true)))?;
+    return Ok((from_id, to.id(), diff));
+}
+
+fn diff_files(
+    diff: &git2::Diff,
+    check_time: &dyn Fn() -> super::JoshResult<()>,
+) -> super::JoshResult<Vec<serde_json::Value>> {
+    let mut files = vec![];
+    for (i, delta) in diff.deltas().enumerate() {
+        check_time()?;
+        let (_, insertions, deletions) = git2::Patch::from_diff(&diff, i)
+            .and_then(|x| match x {
+                Some(patch) => patch.line_stats(),
+                None => Ok((0, 0, 0)),
+            })
+            .unwrap_or((0, 0, 0));
+        let path = |f: git2::DiffFile| {
+            f.path().map(|x| x.to_string_lossy().to_string())
         };
+        files.push(json!({
+            "path": path(delta.new_file()).or(path(delta.old_file())),
+            "old_path": path(delta.old_file()),
+            "status": format!("{:?}", delta.status()).to_lowercase(),
+            "binary": delta.flags().is_binary(),
+            "insertions": insertions,
+            "deletions": deletions,
+        }));
+    }
+    return Ok(files);
+}
 
-        if let Some(v) = self.kv_store.read().unwrap().get(&key) {
-            return Ok(v.to_owned());
-        } else {
-            return Ok(json!(""));
+// Unified diff of a range in the format of "git diff", together with the
+// metadata of the changed files
+fn unified_diff(
+    repo: &git2::Repository,
+    headref: &str,
+    range: &str,
+    budget: &Budget,
+) -> super::JoshResult<serde_json::Value> {
+    let (from, to, diff) = range_diff(&repo, &headref, &range)?;
+
+    let mut text = String::new();
+    let mut exceeded = None;
+    let printed = diff.print(git2::DiffFormat::Patch, |_, _, line| {
+        if let Err(e) = budget.add_output(line.content().len() + 1) {
+            exceeded = Some(e);
+            return false;
         }
+        match line.origin() {
+            '+' | '-' | ' ' => text.push(line.origin()),
+            _ => {}
+        }
+        text.push_str(&String::from_utf8_lossy(line.content()));
+        true
+    });
+    if let Some(e) = exceeded {
+        return Err(e);
     }
+    printed?;
+
+    return Ok(json!({
+        "from": from.map(|x| format!("{}", x)),
+        "to": format!("{}", to),
+        "files": diff_files(&diff, &|| budget.check_time())?,
+        "diff": text,
+    }));
 }
 
-impl handlebars::HelperDef for KvHelper {
-    fn call_inner<'reg: 'rc, 'rc>(
-        &self,
-        h: &handlebars::Helper,
-        _: &handlebars::Handlebars,
-        _: &handlebars::Context,
-        _rc: &mut handlebars::RenderContext,
-    ) -> Result<
-        Option<handlebars::ScopedJson<'reg, 'rc>>,
-        handlebars::RenderError,
-    > {
-        return Ok(Some(handlebars::ScopedJson::Derived(
-            self.josh_helper(h.params().as_slice())
-                .map_err(|_| handlebars::RenderError::new("josh"))?,
-        )));
+fn changed_paths(diff: &git2::Diff) -> Vec<String> {
+    return diff
+        .deltas()
+        .filter_map(|d| d.new_file().path().or(d.old_file().path()))
+        .map(|x| x.to_string_lossy().to_string())
+        .collect();
+}
+
+fn git_log(
+    ctx: &RenderContext,
+    h: &handlebars::Helper,
+) -> super::JoshResult<serde_json::Value> {
+    let hash = h.hash();
+    let repo = ctx.repo;
+    let limit = hash
+        .get("limit")
+        .and_then(|x| x.value().as_u64())
+        .unwrap_or(100) as usize;
+    let path = hash_str(&hash, "path");
+    let (from, to) =
+        parse_range(&repo, &ctx.headref, &hash_str(&hash, "range"))?;
+
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL)?;
+    walk.push(to.id())?;
+    if let Some(from) = from {
+        walk.hide(from.id())?;
     }
+
+    let mut commits = vec![];
+    for id in walk {
+        if commits.len() >= limit {
+            break;
+        }
+        ctx.check_time()?;
+        let commit = repo.find_commit(id?)?;
+        let paths = changed_paths(&first_parent_diff(&repo, &commit)?);
+        if path != ""
+            && !paths.iter().any(|x| {
+                std::path::Path::new(x).starts_with(std::path::Path::new(&path))
+            })
+        {
+            continue;
+        }
+        ctx.add_output(
+            commit.message_bytes().len()
+                + paths.iter().map(|x| x.len()).sum::<usize>(),
+        )?;
+        commits.push(json!({
+            "sha1": format!("{}", commit.id()),
+            "author": {
+                "name": commit.author().name().unwrap_or(""),
+                "email": commit.author().email().unwrap_or(""),
+            },
+            "date": commit.author().when().seconds(),
+            "summary": commit.summary().unwrap_or(""),
+            "message": commit.message().unwrap_or(""),
+            "paths": paths,
+        }));
+    }
+    return Ok(json!(commits));
+}
+
+fn git_diff_stat(
+    ctx: &RenderContext,
+    h: &handlebars::Helper,
+) -> super::JoshResult<serde_json::Value> {
+    let repo = ctx.repo;
+    let (_, _, diff) =
+        range_diff(&repo, &ctx.headref, &hash_str(&h.hash(), "range"))?;
+
+    let stats = diff.stats()?;
+    return Ok(json!({
+        "files": diff_files(&diff, &|| ctx.check_time())?,
+        "files_changed": stats.files_changed(),
+        "insertions": stats.insertions(),
+        "deletions": stats.deletions(),
+    }));
+}
+
+fn entry_json(
+    repo: &git2::Repository,
+    path: &std::path::Path,
+    entry: &git2::TreeEntry,
+) -> serde_json::Value {
+    let kind = entry.kind().map(|x| x.str()).unwrap_or("unknown");
+    let size = if entry.kind() == Some(git2::ObjectType::Blob) {
+        repo.find_blob(entry.id()).map(|x| json!(x.size())).ok()
+    } else {
+        None
+    };
+    return json!({
+        "path": path.to_string_lossy(),
+        "name": entry.name().unwrap_or(""),
+        "mode": format!("{:06o}", entry.filemode()),
+        "type": kind,
+        "sha1": format!("{}", entry.id()),
+        "size": size.unwrap_or(json!(null)),
+    });
+}
+
+// Entries of the directory at path, all entries below it if recursive.
+// A path naming a file lists just that file.
+fn list_path(
+    repo: &git2::Repository,
+    tree: &git2::Tree,
+    path: &str,
+    recursive: bool,
+    budget: &Budget,
+) -> super::JoshResult<serde_json::Value> {
+    let path = std::path::Path::new(path.trim_matches('/'));
+
+    let dir = if path == std::path::Path::new("") {
+        tree.clone()
+    } else {
+        let entry = tree.get_path(&path)?;
+        if entry.kind() != Some(git2::ObjectType::Tree) {
+            return Ok(json!([entry_json(&repo, &path, &entry)]));
+        }
+        repo.find_tree(entry.id())?
+    };
+
+    let mut entries = vec![];
+    let mut add = |entry_path: &std::path::Path, entry: &git2::TreeEntry| {
+        budget.add_output(
+            entry_path.as_os_str().len() + entry.name_bytes().len(),
+        )?;
+        entries.push(entry_json(&repo, &entry_path, &entry));
+        return Ok(());
+    };
+    if recursive {
+        let mut exceeded = None;
+        let walked = dir.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+            let entry_path = path.join(root).join(entry.name().unwrap_or(""));
+            if let Err(e) = add(&entry_path, &entry) {
+                exceeded = Some(e);
+                return git2::TreeWalkResult::Abort;
+            }
+            git2::TreeWalkResult::Ok
+        });
+        if let Some(e) = exceeded {
+            return Err(e);
+        }
+        walked?;
+    } else {
+        for entry in dir.iter() {
+            add(&path.join(entry.name().unwrap_or("")), &entry)?;
+        }
+    }
+    return Ok(json!(entries));
 }
 
 handlebars_helper!(concat_helper: |x: str, y: str| format!("{}{}", x, y) );
 
 handlebars_helper!(toml_helper: |x: str| toml::de::from_str::<serde_json::Value>(x).unwrap_or(json!({})) );
 
+fn compiled_template(
+    blob: &git2::Blob,
+) -> super::JoshResult<handlebars::Template> {
+    if let Some(template) = TEMPLATES.read()?.get(&blob.id()) {
+        return Ok(template.clone());
+    }
+    let template =
+        handlebars::Template::compile(std::str::from_utf8(blob.content())?)?;
+    let mut templates = TEMPLATES.write()?;
+    if templates.len() >= MAX_CACHED_TEMPLATES {
+        templates.clear();
+    }
+    templates.insert(blob.id(), template.clone());
+    return Ok(template);
+}
+
 pub fn render(
     repo: &git2::Repository,
     headref: &str,
     query: &str,
-    kv_store: Arc<RwLock<std::collections::HashMap<String, serde_json::Value>>>,
-    backward_maps: std::sync::Arc<
+    kv_store: super::kv_store::SharedKvStore,
+    kv_namespace: &str,
+    forward_maps: std::sync::Arc<
         std::sync::RwLock<super::filter_cache::FilterCache>,
     >,
-    forward_maps: std::sync::Arc<
+    backward_maps: std::sync::Arc<
         std::sync::RwLock<super::filter_cache::FilterCache>,
     >,
+    limits: &QueryLimits,
 ) -> super::JoshResult<String> {
     let mut split = query.splitn(2, "=");
     let cmd = split
         .next()
         .ok_or(super::josh_error(&format!("invalid query {:?}", query)))?;
-    let path = split
-        .next()
-        .ok_or(super::josh_error(&format!("invalid query {:?}", query)))?;
-    let tree = repo.find_reference(&headref)?.peel_to_tree()?;
+    let path = split.next().unwrap_or("");
+    let head = repo.find_reference(&headref)?.peel_to_commit()?;
+    let tree = head.tree()?;
+    let budget = Budget::new(&limits);
+
+    if cmd == "diff" {
+        return check_output(
+            format!(
+                "{}\n",
+                serde_json::to_string_pretty(&unified_diff(
+                    &repo, &headref, &path, &budget
+                )?)?
+            ),
+            &limits,
+        );
+    }
+
+    if cmd == "ls" || cmd == "tree" {
+        return check_output(
+            format!(
+                "{}\n",
+                serde_json::to_string_pretty(&list_path(
+                    &repo,
+                    &tree,
+                    &path,
+                    cmd == "tree",
+                    &budget,
+                )?)?
+            ),
+            &limits,
+        );
+    }
 
     let obj = tree
         .get_path(&std::path::PathBuf::from(path))?
         .to_object(&repo)?;
 
     let template = if let Ok(blob) = obj.peel_to_blob() {
-        let template = std::str::from_utf8(blob.content())?;
         if cmd == "get" {
-            return Ok(template.to_string());
+            budget.add_output(blob.size())?;
+            return Ok(std::str::from_utf8(blob.content())?.to_string());
         }
         if cmd == "render" {
-            template.to_string()
+            compiled_template(&blob)?
         } else {
             return Err(super::josh_error("no such cmd"));
         }
@@ -236,35 +789,64 @@ pub fn render(
         return Ok("".to_string());
     };
 
-    let mut handlebars = handlebars::Handlebars::new();
-    handlebars.register_template_string("template", template)?;
-    handlebars.register_helper("concat", Box::new(concat_helper));
-    handlebars.register_helper("toml-parse", Box::new(toml_helper));
-    handlebars.register_helper(
-        "git-find",
-        Box::new(FindFilesHelper {
-            repo_path: repo.path().to_owned(),
-            headref: headref.to_string(),
-        }),
-    );
-    handlebars.register_helper(
-        "git-blob",
-        Box::new(BlobHelper {
-            repo_path: repo.path().to_owned(),
-            headref: headref.to_string(),
-        }),
-    );
-    handlebars.register_helper(
-        "josh-filter",
-        Box::new(FilterHelper {
-            repo_path: repo.path().to_owned(),
-            headref: headref.to_string(),
-            forward_maps: forward_maps.clone(),
-            backward_maps: backward_maps.clone(),
-        }),
-    );
+    let ctx = RenderContext {
+        repo: &repo,
+        headref: headref.to_owned(),
+        head: head.id(),
+        tree: tree.id(),
+        kv_store: kv_store,
+        kv_namespace: kv_namespace.to_owned(),
+        forward_maps: forward_maps,
+        backward_maps: backward_maps,
+        limits: limits.clone(),
+        deadline: Instant::now() + limits.timeout,
+        helper_calls: AtomicUsize::new(0),
+        output: AtomicUsize::new(0),
+        exceeded: Mutex::new(None),
+    };
 
-    handlebars
-        .register_helper("josh-kv", Box::new(KvHelper { kv_store: kv_store }));
-    return Ok(format!("{}", handlebars.render("template", &json!({}))?));
+    // The git-* and josh-* helpers borrow the caller's repository, which
+    // can't be shared between threads, so they are attached to this render
+    // instead of the registry
+    let data = handlebars::Context::wraps(&json!({}))?;
+    let mut rc = handlebars::RenderContext::new(None);
+    let helpers: [(&'static str, HelperFn); 6] = [
+        ("git-blob", git_blob),
+        ("git-find", git_find),
+        ("git-log", git_log),
+        ("git-diff-stat", git_diff_stat),
+        ("josh-filter", josh_filter),
+        ("josh-kv", josh_kv),
+    ];
+    for (name, f) in helpers.iter() {
+        rc.register_local_helper(
+            name,
+            Box::new(JoshHelper {
+                ctx: &ctx,
+                name: name,
+                f: *f,
+            }),
+        );
+    }
+
+    let mut output = LimitedWriter {
+        buf: vec![],
+        max: limits.max_output,
+        exceeded: false,
+    };
+    let rendered = handlebars::Renderable::render(
+        &template,
+        &HANDLEBARS,
+        &data,
+        &mut rc,
+        &mut output,
+    );
+    if let Some(exceeded) = ctx.exceeded.lock()?.take() {
+        return Err(super::josh_error(&exceeded));
+    }
+    if output.exceeded {
+        return Err(output_limit_error(&limits));
+    }
+    rendered?;
+    return Ok(String::from_utf8(output.buf)?);
 }
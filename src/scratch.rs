@@ -1,13 +1,14 @@
 use git2;
 use tracing;
 
-use self::tracing::{warn, Level};
+use self::tracing::{span, warn, Level};
 use super::empty_tree;
 use super::filter_cache;
 use super::filters;
 use super::UnapplyFilter;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::sync::{Arc, Mutex, RwLock};
 
 fn all_equal(a: git2::Parents, b: &[&git2::Commit]) -> bool {
     let a: Vec<_> = a.collect();
@@ -23,6 +24,149 @@ fn all_equal(a: git2::Parents, b: &[&git2::Commit]) -> bool {
     return true;
 }
 
+// Commit headers that are bound to the exact content of the original commit
+// and become invalid once it is rewritten
+const SIGNATURE_HEADERS: &[&str] = &["gpgsig", "gpgsig-sha256", "mergetag"];
+
+// Controls how signatures are handled when commits are rewritten and
+// how commits synthesized for the upstream repo are created.
+// Read from the git config of the repository:
+//   josh.keepSignature: keep the original signature as "josh-original-*" header
+//   josh.signingKey: key used to sign rewritten commits
+//   josh.signingFormat: "openpgp" (default) or "ssh"
+//   josh.committerName, josh.committerEmail: committer of synthesized commits
+//   josh.mergeMessage: message of "josh-merge" commits, "{filter}" is
+//                      replaced by the filter spec
+// Loaded once per filter run, see FilterCache::rewrite_config.
+#[derive(Debug, Clone)]
+pub struct RewriteConfig {
+    pub keep_signature: bool,
+    pub signing_key: Option<String>,
+    pub signing_format: String,
+    pub committer_name: Option<String>,
+    pub committer_email: Option<String>,
+    pub merge_message: String,
+}
+
+const DEFAULT_MERGE_MESSAGE: &str = "Merge from {filter}";
+
+impl RewriteConfig {
+    pub fn from_repo(repo: &git2::Repository) -> RewriteConfig {
+        let config = ok_or!(repo.config().and_then(|mut x| x.snapshot()), {
+            return RewriteConfig {
+                keep_signature: false,
+                signing_key: None,
+                signing_format: "openpgp".to_owned(),
+                committer_name: None,
+                committer_email: None,
+                merge_message: DEFAULT_MERGE_MESSAGE.to_owned(),
+            };
+        });
+        return RewriteConfig {
+            keep_signature: config
+                .get_bool("josh.keepSignature")
+                .unwrap_or(false),
+            signing_key: config.get_string("josh.signingKey").ok(),
+            signing_format: config
+                .get_string("josh.signingFormat")
+                .unwrap_or("openpgp".to_owned()),
+            committer_name: config.get_string("josh.committerName").ok(),
+            committer_email: config.get_string("josh.committerEmail").ok(),
+            merge_message: config
+                .get_string("josh.mergeMessage")
+                .unwrap_or(DEFAULT_MERGE_MESSAGE.to_owned()),
+        };
+    }
+
+    // The configured committer identity, if any. The time is taken
+    // from the original commit to keep the result deterministic.
+    pub fn committer(
+        &self,
+        when: &git2::Time,
+    ) -> super::JoshResult<Option<git2::Signature<'static>>> {
+        if let (Some(name), Some(email)) =
+            (&self.committer_name, &self.committer_email)
+        {
+            return Ok(Some(git2::Signature::new(&name, &email, &when)?));
+        }
+        return Ok(None);
+    }
+
+    pub fn merge_message(&self, filter_spec: &str) -> String {
+        return self.merge_message.replace("{filter}", filter_spec);
+    }
+
+    // Identifies the settings that change the ids of filtered commits, empty
+    // for the defaults which maps persisted without a key were built with
+    pub fn cache_key(&self) -> String {
+        if !self.keep_signature && self.signing_key.is_none() {
+            return String::new();
+        }
+        return format!(
+            "{:?}",
+            (self.keep_signature, &self.signing_key, &self.signing_format)
+        );
+    }
+}
+
+fn format_signature(signature: &git2::Signature) -> Vec<u8> {
+    let when = signature.when();
+    let offset = when.offset_minutes().abs();
+    let mut s = signature.name_bytes().to_vec();
+    s.extend(b" <");
+    s.extend(signature.email_bytes());
+    s.extend(
+        format!(
+            "> {} {}{:02}{:02}",
+            when.seconds(),
+            if when.offset_minutes() < 0 { '-' } else { '+' },
+            offset / 60,
+            offset % 60
+        )
+        .as_bytes(),
+    );
+    return s;
+}
+
+fn sign_buffer(
+    config: &RewriteConfig,
+    key: &str,
+    buffer: &[u8],
+) -> super::JoshResult<String> {
+    use std::io::Write;
+
+    let mut command = if config.signing_format == "ssh" {
+        let mut command = std::process::Command::new("ssh-keygen");
+        command.args(&["-Y", "sign", "-n", "git", "-f", key]);
+        command
+    } else {
+        let mut command = std::process::Command::new("gpg");
+        command.args(&["--status-fd=2", "-bsau", key]);
+        command
+    };
+
+    let mut child = command
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+
+    child
+        .stdin
+        .take()
+        .ok_or(super::josh_error("sign_buffer: no stdin"))?
+        .write_all(buffer)?;
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(super::josh_error(&format!(
+            "signing commit failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    return Ok(String::from_utf8(output.stdout)?);
+}
+
 // takes everything from base except it's tree and replaces it with the tree
 // given
 pub fn rewrite(
@@ -30,6 +174,19 @@ pub fn rewrite(
     base: &git2::Commit,
     parents: &[&git2::Commit],
     tree: &git2::Tree,
+    config: &RewriteConfig,
+) -> super::JoshResult<git2::Oid> {
+    return rewrite_with_committer(repo, base, parents, tree, None, config);
+}
+
+// Same as rewrite, but replaces the committer if one is given
+pub fn rewrite_with_committer(
+    repo: &git2::Repository,
+    base: &git2::Commit,
+    parents: &[&git2::Commit],
+    tree: &git2::Tree,
+    committer: Option<&git2::Signature>,
+    config: &RewriteConfig,
 ) -> super::JoshResult<git2::Oid> {
     if base.tree()?.id() == tree.id() && all_equal(base.parents(), parents) {
         // Looks like an optimization, but in fact serves to not change the commit in case
@@ -37,14 +194,67 @@ pub fn rewrite(
         return Ok(base.id());
     }
 
-    return Ok(repo.commit(
-        None,
-        &base.author(),
-        &base.committer(),
-        &base.message_raw().unwrap_or("no message"),
-        tree,
-        parents,
-    )?);
+    let mut content = format!("tree {}\n", tree.id()).into_bytes();
+    for parent in parents {
+        content.extend(format!("parent {}\n", parent.id()).as_bytes());
+    }
+
+    // Copy all remaining headers (author, committer, encoding, ...) verbatim,
+    // multi line headers continue with lines starting with a space.
+    let mut keep_continuation = false;
+    for line in base.raw_header_bytes().split(|x| *x == b'\n') {
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with(b" ") {
+            if keep_continuation {
+                content.extend(line);
+                content.push(b'\n');
+            }
+            continue;
+        }
+        let field = line.split(|x| *x == b' ').next().unwrap_or(b"");
+        keep_continuation = false;
+        if field == b"tree" || field == b"parent" {
+            continue;
+        }
+        if let (b"committer", Some(committer)) = (field, committer) {
+            content.extend(b"committer ");
+            content.extend(format_signature(&committer));
+            content.push(b'\n');
+            continue;
+        }
+        if SIGNATURE_HEADERS.iter().any(|x| x.as_bytes() == field) {
+            if !config.keep_signature {
+                continue;
+            }
+            content.extend(b"josh-original-");
+        }
+        content.extend(line);
+        content.push(b'\n');
+        keep_continuation = true;
+    }
+
+    let message = {
+        let mut message = b"\n".to_vec();
+        message.extend(base.message_raw_bytes());
+        message
+    };
+
+    if let Some(key) = &config.signing_key {
+        let signature =
+            sign_buffer(&config, &key, &[&content[..], &message[..]].concat())?;
+        content.extend(b"gpgsig");
+        for line in signature.trim_end().lines() {
+            content.push(b' ');
+            content.extend(line.as_bytes());
+            content.push(b'\n');
+        }
+    }
+
+    content.extend(message);
+
+    return Ok(repo.odb()?.write(git2::ObjectType::Commit, &content)?);
 }
 
 pub fn unapply_filter(
@@ -69,6 +279,8 @@ pub fn unapply_filter(
         walk
     };
 
+    let config = RewriteConfig::from_repo(&repo);
+
     let mut bm = filter_cache::new_downstream(&backward_maps);
     let mut ret = bm.get(&filterobj.filter_spec(), new);
     for rev in walk {
@@ -128,14 +340,45 @@ pub fn unapply_filter(
             }
         };
 
-        ret =
-            rewrite(&repo, &module_commit, &original_parents_refs, &new_tree)?;
+        ret = rewrite_with_committer(
+            &repo,
+            &module_commit,
+            &original_parents_refs,
+            &new_tree,
+            config
+                .committer(&module_commit.committer().when())?
+                .as_ref(),
+            &config,
+        )?;
         bm.set(&filterobj.filter_spec(), module_commit.id(), ret);
     }
 
     return Ok(UnapplyFilter::Done(ret));
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum RefUpdateStatus {
+    // The target reference was created or moved
+    Updated,
+    // The target reference already pointed to the filtered commit
+    Unchanged,
+    // The filtered history is empty, the target reference was left alone
+    Empty,
+    // The source reference does not exist
+    SourceMissing,
+}
+
+#[derive(Debug, Clone)]
+pub struct RefUpdate {
+    pub from_refname: String,
+    pub to_refname: String,
+    pub old: git2::Oid,
+    pub new: git2::Oid,
+    pub status: RefUpdateStatus,
+}
+
+// Computes the filtered commit for from_refsname, without touching any
+// references
 fn transform_commit(
     repo: &git2::Repository,
     filterobj: &dyn filters::Filter,
@@ -143,80 +386,134 @@ fn transform_commit(
     to_refname: &str,
     forward_maps: &mut filter_cache::FilterCache,
     backward_maps: &mut filter_cache::FilterCache,
-) -> super::JoshResult<usize> {
-    let mut updated_count = 0;
-    if let Ok(reference) = repo.revparse_single(&from_refsname) {
-        let original_commit = reference.peel_to_commit()?;
-        let filter_commit = filterobj.apply_to_commit(
-            &repo,
-            &original_commit,
-            forward_maps,
-            backward_maps,
-            &mut HashMap::new(),
-        )?;
-        forward_maps.set(
-            &filterobj.filter_spec(),
-            original_commit.id(),
-            filter_commit,
-        );
-        backward_maps.set(
-            &filterobj.filter_spec(),
-            filter_commit,
-            original_commit.id(),
-        );
+) -> super::JoshResult<RefUpdate> {
+    let previous = repo
+        .revparse_single(&to_refname)
+        .map(|x| x.id())
+        .unwrap_or(git2::Oid::zero());
 
-        let previous = repo
-            .revparse_single(&to_refname)
-            .map(|x| x.id())
-            .unwrap_or(git2::Oid::zero());
-
-        if filter_commit != previous {
-            updated_count += 1;
-            tracing::trace!(
-                "transform_commit: update reference: {:?} -> {:?}, target: {:?}, filter: {:?}",
-                &from_refsname,
-                &to_refname,
-                filter_commit,
-                &filterobj.filter_spec()
-            );
-        }
+    let mut update = RefUpdate {
+        from_refname: from_refsname.to_owned(),
+        to_refname: to_refname.to_owned(),
+        old: previous,
+        new: git2::Oid::zero(),
+        status: RefUpdateStatus::SourceMissing,
+    };
 
-        if filter_commit != git2::Oid::zero() {
-            ok_or!(
-                repo.reference(
-                    &to_refname,
-                    filter_commit,
-                    true,
-                    "apply_filter"
-                )
-                .map(|_| ()),
-                {
-                    tracing::error!(
-                        "can't update reference: {:?} -> {:?}, target: {:?}, filter: {:?}",
-                        &from_refsname,
-                        &to_refname,
-                        filter_commit,
-                        &filterobj.filter_spec()
-                    );
-                }
-            );
-        }
-    } else {
+    let reference = ok_or!(repo.revparse_single(&from_refsname), {
         warn!(
             "transform_commit: Can't find reference {:?}",
             &from_refsname
         );
+        return Ok(update);
+    });
+
+    let original_commit = reference.peel_to_commit()?;
+    let filter_commit = filterobj.apply_to_commit(
+        &repo,
+        &original_commit,
+        forward_maps,
+        backward_maps,
+        &mut HashMap::new(),
+    )?;
+    forward_maps.set(
+        &filterobj.filter_spec(),
+        original_commit.id(),
+        filter_commit,
+    );
+    backward_maps.set(
+        &filterobj.filter_spec(),
+        filter_commit,
+        original_commit.id(),
+    );
+
+    update.new = filter_commit;
+    update.status = if filter_commit == git2::Oid::zero() {
+        RefUpdateStatus::Empty
+    } else if filter_commit == previous {
+        RefUpdateStatus::Unchanged
+    } else {
+        tracing::trace!(
+            "transform_commit: update reference: {:?} -> {:?}, target: {:?}, filter: {:?}",
+            &from_refsname,
+            &to_refname,
+            filter_commit,
+            &filterobj.filter_spec()
+        );
+        RefUpdateStatus::Updated
     };
-    return Ok(updated_count);
+
+    return Ok(update);
+}
+
+// Applies all updates with status Updated in a single transaction.
+// Either all references are changed or none of them.
+pub fn update_refs(
+    repo: &git2::Repository,
+    updates: &[RefUpdate],
+    message: &str,
+) -> super::JoshResult<()> {
+    use std::io::Write;
+
+    // The same target can appear more than once, in that case the last
+    // update wins, just like when applying them one after the other
+    let mut targets = vec![];
+    let mut new = HashMap::new();
+    for u in updates.iter() {
+        if u.status == RefUpdateStatus::Updated
+            || u.status == RefUpdateStatus::Unchanged
+        {
+            new.insert(u.to_refname.clone(), u.new);
+        }
+        if u.status == RefUpdateStatus::Updated
+            && !targets.contains(&u.to_refname)
+        {
+            targets.push(u.to_refname.clone());
+        }
+    }
+
+    let mut input = vec![];
+    for t in targets.iter() {
+        input.extend(format!("update {}\0{}\0\0", t, new[t]).as_bytes());
+    }
+    if input.is_empty() {
+        return Ok(());
+    }
+
+    let mut child = std::process::Command::new("git")
+        .args(&["update-ref", "--stdin", "-z", "-m", message])
+        .env("GIT_DIR", repo.path())
+        .env_remove("GIT_NAMESPACE")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+
+    child
+        .stdin
+        .take()
+        .ok_or(super::josh_error("update_refs: no stdin"))?
+        .write_all(&input)?;
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(super::josh_error(&format!(
+            "can't update references: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    return Ok(());
 }
 
+// Filters all refs first and then updates the targets in one transaction,
+// so a failure leaves all of them untouched.
 pub fn apply_filter_to_refs(
     repo: &git2::Repository,
     filterobj: &dyn filters::Filter,
     refs: &[(String, String)],
     forward_maps: &mut filter_cache::FilterCache,
     backward_maps: &mut filter_cache::FilterCache,
-) -> super::JoshResult<usize> {
+) -> super::JoshResult<Vec<RefUpdate>> {
     tracing::span!(
         Level::TRACE,
         "apply_filter_to_refs",
@@ -224,16 +521,181 @@ pub fn apply_filter_to_refs(
         ?refs,
         filter_spec=?filterobj.filter_spec());
 
-    let mut updated_count = 0;
+    let mut updates = vec![];
     for (k, v) in refs {
-        updated_count += transform_commit(
+        updates.push(transform_commit(
             &repo,
             &*filterobj,
             &k,
             &v,
             forward_maps,
             backward_maps,
-        )?;
+        )?);
+    }
+
+    update_refs(&repo, &updates, "apply_filter")?;
+    return Ok(updates);
+}
+
+pub fn updated_count(updates: &[RefUpdate]) -> usize {
+    return updates
+        .iter()
+        .filter(|x| x.status == RefUpdateStatus::Updated)
+        .count();
+}
+
+// Runs the jobs on a pool of worker threads. Every worker has its own
+// repository handle and downstream caches, which get merged into the shared
+// caches once the worker is done. Every worker calls make_f once and runs its
+// jobs with the returned function, so per worker state like a parsed filter
+// is only set up once. The progress, if any, is shared by all workers, so
+// cancelling it stops all of them.
+// Returns the results of all jobs and the number of cache entries added.
+pub fn run_parallel<T, R, F, G>(
+    repo_path: &std::path::Path,
+    jobs: Vec<T>,
+    threads: usize,
+    forward_maps: Arc<RwLock<filter_cache::FilterCache>>,
+    backward_maps: Arc<RwLock<filter_cache::FilterCache>>,
+    progress: Option<Arc<super::progress::Progress>>,
+    make_f: F,
+) -> super::JoshResult<(Vec<R>, usize)>
+where
+    T: Send + 'static,
+    R: Send + 'static,
+    F: Fn() -> G + Send + Sync + 'static,
+    G: FnMut(
+        &git2::Repository,
+        T,
+        &mut filter_cache::FilterCache,
+        &mut filter_cache::FilterCache,
+    ) -> super::JoshResult<R>,
+{
+    let jobs = Arc::new(Mutex::new(
+        jobs.into_iter().enumerate().rev().collect::<Vec<_>>(),
+    ));
+    let make_f = Arc::new(make_f);
+
+    let workers: Vec<_> = (0..std::cmp::max(threads, 1))
+        .map(|_| {
+            let repo_path = repo_path.to_owned();
+            let jobs = jobs.clone();
+            let make_f = make_f.clone();
+            let forward_maps = forward_maps.clone();
+            let backward_maps = backward_maps.clone();
+            let progress = progress.clone();
+            std::thread::spawn(
+                move || -> super::JoshResult<(Vec<(usize, R)>, usize)> {
+                    let repo = git2::Repository::open(&repo_path)?;
+                    let mut fm = filter_cache::new_downstream(&forward_maps);
+                    let mut bm = filter_cache::new_downstream(&backward_maps);
+                    if let Some(progress) = progress {
+                        fm.set_progress(progress);
+                    }
+                    let mut f = make_f();
+
+                    let mut result = Ok(vec![]);
+                    loop {
+                        let (i, job) = some_or!(jobs.lock()?.pop(), {
+                            break;
+                        });
+                        match f(&repo, job, &mut fm, &mut bm) {
+                            Ok(r) => {
+                                if let Ok(results) = &mut result {
+                                    results.push((i, r));
+                                }
+                            }
+                            Err(e) => {
+                                // Stop the other workers as well
+                                jobs.lock()?.clear();
+                                result = Err(e);
+                                break;
+                            }
+                        }
+                    }
+
+                    let added = fm.stats()["total"] + bm.stats()["total"];
+                    span!(Level::TRACE, "write_lock bm").in_scope(|| {
+                        backward_maps.write().map(|mut x| x.merge(&bm)).ok();
+                    });
+                    span!(Level::TRACE, "write_lock fm").in_scope(|| {
+                        forward_maps.write().map(|mut x| x.merge(&fm)).ok();
+                    });
+                    return result.map(|x| (x, added));
+                },
+            )
+        })
+        .collect();
+
+    let mut results = vec![];
+    let mut total = 0;
+    let mut error = None;
+    for worker in workers {
+        match worker.join() {
+            Ok(Ok((r, added))) => {
+                results.extend(r);
+                total += added;
+            }
+            Ok(Err(e)) => error = Some(e),
+            Err(_) => error = Some(super::josh_error("worker thread panicked")),
+        }
+    }
+
+    if let Some(e) = error {
+        return Err(e);
     }
-    return Ok(updated_count);
+    results.sort_by_key(|(i, _)| *i);
+    return Ok((results.into_iter().map(|(_, r)| r).collect(), total));
+}
+
+// Same as apply_filter_to_refs, but distributes the refs over multiple
+// threads. Every thread parses it's own copy of the filter once.
+// The references are updated in one transaction after all threads are done.
+pub fn apply_filter_to_refs_parallel(
+    repo_path: &std::path::Path,
+    filter_spec: &str,
+    refs: &[(String, String)],
+    forward_maps: Arc<RwLock<filter_cache::FilterCache>>,
+    backward_maps: Arc<RwLock<filter_cache::FilterCache>>,
+    threads: usize,
+    progress: Option<Arc<super::progress::Progress>>,
+) -> super::JoshResult<Vec<RefUpdate>> {
+    tracing::span!(
+        Level::TRACE,
+        "apply_filter_to_refs_parallel",
+        ?repo_path,
+        ?refs,
+        ?filter_spec,
+        ?threads
+    );
+
+    let filter_spec = filter_spec.to_owned();
+    let (updates, _) = run_parallel(
+        repo_path,
+        refs.to_vec(),
+        threads,
+        forward_maps,
+        backward_maps,
+        progress,
+        move || {
+            let filter = filters::parse(&filter_spec);
+            move |repo,
+                  (from_refsname, to_refname): (String, String),
+                  fm,
+                  bm| {
+                transform_commit(
+                    &repo,
+                    &*filter,
+                    &from_refsname,
+                    &to_refname,
+                    fm,
+                    bm,
+                )
+            }
+        },
+    )?;
+
+    let repo = git2::Repository::open(&repo_path)?;
+    update_refs(&repo, &updates, "apply_filter")?;
+    return Ok(updates);
 }
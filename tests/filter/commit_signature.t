@@ -0,0 +1,76 @@
+  $ export TESTTMP=${PWD}
+  $ export PATH=${TESTDIR}/../../target/debug/:${PATH}
+
+  $ cd ${TESTTMP}
+  $ ssh-keygen -q -t ed25519 -N "" -f ${TESTTMP}/key
+  $ echo "* $(cat ${TESTTMP}/key.pub)" > ${TESTTMP}/allowed_signers
+
+  $ git init real_repo 1> /dev/null
+  $ cd real_repo
+  $ git config gpg.format ssh
+  $ git config user.signingkey ${TESTTMP}/key
+  $ git config gpg.ssh.allowedSignersFile ${TESTTMP}/allowed_signers
+
+  $ mkdir sub1
+  $ echo contents1 > sub1/file1
+  $ echo contents1 > file2
+  $ git add .
+  $ git -c i18n.commitEncoding=ISO-8859-1 commit -S -m "add files" 1> /dev/null
+
+  $ git cat-file -p HEAD | grep -v "^ "
+  tree * (glob)
+  author * (glob)
+  committer * (glob)
+  encoding ISO-8859-1
+  gpgsig -----BEGIN SSH SIGNATURE-----
+  
+  add files
+
+  $ josh-filter master --update refs/josh/filtered :/sub1
+  $ git cat-file -p refs/josh/filtered
+  tree * (glob)
+  author * (glob)
+  committer * (glob)
+  encoding ISO-8859-1
+  
+  add files
+
+  $ git config josh.keepSignature true
+  $ josh-filter master --update refs/josh/filtered :prefix=x
+  $ git cat-file -p refs/josh/filtered | grep -v "^ "
+  tree * (glob)
+  author * (glob)
+  committer * (glob)
+  encoding ISO-8859-1
+  josh-original-gpgsig -----BEGIN SSH SIGNATURE-----
+  
+  add files
+  $ git verify-commit refs/josh/filtered
+  [1]
+
+  $ git config josh.keepSignature false
+  $ git config josh.signingKey ${TESTTMP}/key
+  $ git config josh.signingFormat ssh
+  $ josh-filter master --update refs/josh/filtered :prefix=y
+  $ git cat-file -p refs/josh/filtered | grep -v "^ "
+  tree * (glob)
+  author * (glob)
+  committer * (glob)
+  encoding ISO-8859-1
+  gpgsig -----BEGIN SSH SIGNATURE-----
+  
+  add files
+  $ git verify-commit refs/josh/filtered
+  Good "git" signature for * with ED25519 key SHA256:* (glob)
+
+The commit filtered with :/sub1 before was not signed, the changed config
+drops it from the cache
+
+  $ josh-filter master --update refs/josh/filtered :/sub1
+  $ git verify-commit refs/josh/filtered
+  Good "git" signature for * with ED25519 key SHA256:* (glob)
+
+  $ git config josh.signingKey ${TESTTMP}/missing_key
+  $ josh-filter master --update refs/josh/filtered :prefix=z
+  ERROR: JoshError("signing commit failed: *") (glob)
+  [1]
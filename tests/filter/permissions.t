@@ -205,3 +205,66 @@
   
   3 directories, 5 files
 
+
+  $ git checkout master 2> /dev/null
+  $ josh-filter permissions master :workspace=a "^(a|c)"
+  Allowed = true
+  $ josh-filter permissions master :workspace=a "^(a|c/d)" --json
+  {
+    "allowed": true,
+    "dirs": [
+      "a",
+      "c/d/e"
+    ],
+    "missing": []
+  }
+  $ josh-filter permissions master :workspace=a "^(a|c/d/f)" --json
+  {
+    "allowed": false,
+    "dirs": [
+      "a",
+      "c/d/e"
+    ],
+    "missing": [
+      "c/d/e"
+    ]
+  }
+  [1]
+  $ josh-filter permissions master :/b "b"
+  Allowed = true
+  $ josh-filter permissions master :/nothing "b" --json
+  {
+    "allowed": false,
+    "dirs": [],
+    "missing": []
+  }
+  [1]
+
+  $ josh-filter master --update refs/josh/filtered :workspace=a --check-permission "^(a|c/d)"
+  Allowed = true
+  $ josh-filter master --update refs/josh/filtered :workspace=a --check-permission "^a"
+  missing permission for: c/d/e
+  Allowed = false
+  [1]
+
+A directory is only left out for directories inside of it, not for ones
+whose name starts with its name
+
+  $ mkdir b2
+  $ echo contents1 > b2/file_b2
+  $ git add b2
+  $ git commit -m "add b2" 1> /dev/null
+  $ josh-filter permissions master :nop "^(a|b2|c)" --json
+  {
+    "allowed": false,
+    "dirs": [
+      "a",
+      "b",
+      "b2",
+      "c/d/e"
+    ],
+    "missing": [
+      "b"
+    ]
+  }
+  [1]
@@ -0,0 +1,64 @@
+  $ export TESTTMP=${PWD}
+  $ export PATH=${TESTDIR}/../../target/debug/:${PATH}
+
+  $ cd ${TESTTMP}
+  $ git init 1> /dev/null
+
+  $ mkdir sub2
+  $ echo contents2 > sub2/file2
+  $ git add sub2
+  $ git commit -m "add file2" 1> /dev/null
+
+  $ mkdir sub1
+  $ echo contents1 > sub1/file1
+  $ git add sub1
+  $ git commit -m "add file1" 1> /dev/null
+
+  $ echo more >> sub2/file2
+  $ git commit -am "change file2" 1> /dev/null
+
+  $ echo more >> sub1/file1
+  $ git commit -am "change file1" 1> /dev/null
+
+  $ josh-filter filter master :/sub1 --update refs/heads/filtered --print-map > map
+  $ cat map | wc -l
+  \s*2 (re)
+  $ cut -f1 map | xargs git log --no-walk=sorted --pretty=%s
+  change file1
+  add file1
+  $ cut -f2 map | xargs git log --no-walk=sorted --pretty=%s
+  change file1
+  add file1
+  $ git rev-parse filtered > expected
+  $ cut -f2 map | head -n 1 | diff - expected
+
+  $ josh-filter filter master :/sub1 --update refs/heads/filtered --print-map --include-dropped | cut -f2,3
+  * (glob)
+  0000000000000000000000000000000000000000\tunchanged (esc)
+  * (glob)
+  0000000000000000000000000000000000000000\tempty (esc)
+
+  $ josh-filter filter master :/sub1 --update refs/heads/filtered --print-map --include-dropped --json | grep -A3 '"dropped"'
+          "dropped": null,
+          "filtered": "*", (glob)
+          "original": "*" (glob)
+        },
+  --
+          "dropped": "unchanged",
+          "filtered": "0000000000000000000000000000000000000000",
+          "original": "*" (glob)
+        },
+  --
+          "dropped": null,
+          "filtered": "*", (glob)
+          "original": "*" (glob)
+        },
+  --
+          "dropped": "empty",
+          "filtered": "0000000000000000000000000000000000000000",
+          "original": "*" (glob)
+        }
+
+The old form accepts it too
+
+  $ josh-filter master --update refs/heads/filtered :/sub1 --print-map | diff - map
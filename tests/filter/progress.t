@@ -0,0 +1,31 @@
+  $ export TESTTMP=${PWD}
+  $ export PATH=${TESTDIR}/../../target/debug/:${PATH}
+
+  $ cd ${TESTTMP}
+  $ git init libs 1> /dev/null
+  $ cd libs
+
+  $ mkdir sub1
+  $ echo contents1 > sub1/file1
+  $ git add sub1
+  $ git commit -m "add file1" 1> /dev/null
+
+  $ mkdir sub2
+  $ echo contents1 > sub2/file2
+  $ git add sub2
+  $ git commit -m "add file2" 1> /dev/null
+
+  $ echo contents2 > sub1/file2
+  $ git add sub1
+  $ git commit -m "add file2" 1> /dev/null
+
+  $ josh-filter --progress master --update refs/heads/filtered :/sub1 2>&1 | tr '\r' '\n'
+  
+  [###############               ] 1/2 :/sub1
+  [##############################] 2/2 :/sub1
+
+  $ git ls-tree -r --name-only filtered
+  file1
+  file2
+
+  $ josh-filter --progress master --update refs/heads/filtered :/sub1 2>&1
@@ -99,5 +99,12 @@
   filtered: * (glob)
   kv: 
     
+The kv file is found from subdirectories of the work tree as well
+
+  $ (cd sub2 && josh-filter HEAD :nop -q render=sub1/tmpl_file) | grep "kv:"
+  kv: SUCCESS
+  kv: SUCCESS
+  kv: 
+
   $ josh-filter HEAD :/sub1 -q render=file2
   contents2
@@ -0,0 +1,89 @@
+  $ export TESTTMP=${PWD}
+  $ export PATH=${TESTDIR}/../../target/debug/:${PATH}
+
+  $ cd ${TESTTMP}
+  $ git init 1> /dev/null
+
+  $ mkdir sub1
+  $ echo contents1 > sub1/file1
+  $ mkdir sub2
+  $ echo contents2 > sub2/file2
+  $ git add .
+  $ git commit -m "add files" 1> /dev/null
+  $ git tag -a v1 -m "v1"
+
+  $ git checkout -q -b feature/one
+  $ echo contents3 > sub1/file3
+  $ git add sub1
+  $ git commit -m "add file3" 1> /dev/null
+  $ git tag v2
+
+  $ git checkout -q -b other
+  $ echo contents4 > sub2/file4
+  $ git add sub2
+  $ git commit -m "add file4" 1> /dev/null
+
+  $ josh-filter filter 'refs/heads/*' :/sub1 --update 'refs/josh/views/app/*'
+  $ git for-each-ref --format="%(refname) %(subject)" refs/josh/views
+  refs/josh/views/app/feature/one add file3
+  refs/josh/views/app/master add files
+  refs/josh/views/app/other add file3
+
+Tags are filtered just the same
+
+  $ josh-filter filter 'refs/tags/v*' :/sub2 --update 'refs/josh/tags/app-v*' --json
+  [
+    {
+      "commit": "*", (glob)
+      "filter": ":/sub2",
+      "src": "refs/tags/v1",
+      "status": "updated",
+      "target": "refs/josh/tags/app-v1"
+    },
+    {
+      "commit": "*", (glob)
+      "filter": ":/sub2",
+      "src": "refs/tags/v2",
+      "status": "updated",
+      "target": "refs/josh/tags/app-v2"
+    }
+  ]
+  $ git ls-tree --name-only refs/josh/tags/app-v2
+  file2
+
+Targets whose source is gone are only deleted with --prune
+
+  $ git checkout -q master
+  $ git branch -q -D other
+  $ josh-filter filter 'refs/heads/*' :/sub1 --update 'refs/josh/views/app/*'
+  $ git for-each-ref --format="%(refname)" refs/josh/views
+  refs/josh/views/app/feature/one
+  refs/josh/views/app/master
+  refs/josh/views/app/other
+  $ josh-filter filter 'refs/heads/*' :/sub1 --update 'refs/josh/views/app/*' --prune --json | grep -A1 '"src"'
+      "src": "refs/heads/feature/one",
+      "status": "unchanged",
+  --
+      "src": "refs/heads/master",
+      "status": "unchanged",
+  --
+      "src": "refs/heads/other",
+      "status": "deleted",
+  $ git for-each-ref --format="%(refname)" refs/josh/views
+  refs/josh/views/app/feature/one
+  refs/josh/views/app/master
+
+--squash and --infofile use the name of each matched ref
+
+  $ josh-filter filter 'refs/heads/*' c=:/sub1 --update 'refs/josh/squashed/*' --squash --infofile
+  $ git log --pretty=%s refs/josh/squashed/feature/one
+  add file3
+  $ git show refs/josh/squashed/master:c/.joshinfo | grep src
+  src: refs/heads/master
+
+  $ josh-filter filter 'refs/heads/*' :/sub1 --update refs/josh/single
+  ERROR: JoshError("\"refs/josh/single\" must contain exactly one \"*\"")
+  [1]
+  $ josh-filter filter master :/sub1 --prune
+  ERROR: JoshError("--prune needs a glob as input_ref")
+  [1]
@@ -0,0 +1,31 @@
+  $ export TESTTMP=${PWD}
+  $ export PATH=${TESTDIR}/../../target/debug/:${PATH}
+
+  $ cd ${TESTTMP}
+  $ git init libs 1> /dev/null
+  $ cd libs
+
+  $ mkdir sub1
+  $ echo contents1 > sub1/file1
+  $ git add sub1
+  $ git commit -m "add file1" 1> /dev/null
+
+  $ josh-filter master --update refs/heads/filtered :/sub1
+  $ git checkout filtered 1> /dev/null
+  Switched to branch 'filtered'
+
+  $ echo contents2 > file2
+  $ git add file2
+  $ git commit -m "add file2" 1> /dev/null
+
+  $ git config josh.committerName "Josh Proxy"
+  $ git config josh.committerEmail "proxy@example.com"
+  $ josh-filter --reverse master --update refs/heads/filtered :/sub1
+
+  $ git log --pretty="%s: %an <%ae>, %cn <%ce>" master
+  add file2: Josh <josh@example.com>, Josh Proxy <proxy@example.com>
+  add file1: Josh <josh@example.com>, Josh <josh@example.com>
+
+  $ git log --pretty="%s: %an <%ae>, %cn <%ce>" filtered
+  add file2: Josh <josh@example.com>, Josh <josh@example.com>
+  add file1: Josh <josh@example.com>, Josh <josh@example.com>
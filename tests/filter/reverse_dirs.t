@@ -0,0 +1,86 @@
+  $ export TESTTMP=${PWD}
+  $ export PATH=${TESTDIR}/../../target/debug/:${PATH}
+
+  $ cd ${TESTTMP}
+  $ git init real_repo 1> /dev/null
+  $ cd real_repo
+
+  $ mkdir a
+  $ echo "cws = :/c" > a/workspace.josh
+  $ echo contents1 > a/file_a2
+  $ git add a
+
+  $ mkdir -p c/d
+  $ echo contents1 > c/d/file_cd
+  $ git add c
+  $ git commit -m "add dirs" 1> /dev/null
+
+  $ josh-filter master --update refs/heads/dirs :DIRS
+  $ git checkout dirs 1> /dev/null
+  Switched to branch 'dirs'
+  $ git ls-tree -r --name-only HEAD
+  a/JOSH_ORIG_PATH_a
+  a/workspace.josh
+  c/JOSH_ORIG_PATH_c
+  c/d/JOSH_ORIG_PATH_c%2Fd
+
+  $ echo "cdws = :/c/d" > a/workspace.josh
+  $ git add a
+  $ git commit -m "edit workspace" 1> /dev/null
+
+  $ josh-filter --reverse master --update refs/heads/dirs :DIRS
+
+  $ git checkout master
+  Switched to branch 'master'
+  $ git ls-tree -r --name-only HEAD
+  a/file_a2
+  a/workspace.josh
+  c/d/file_cd
+  $ cat a/workspace.josh
+  cdws = :/c/d
+  $ git log --graph --pretty=%s
+  * edit workspace
+  * add dirs
+
+  $ git checkout dirs
+  Switched to branch 'dirs'
+  $ echo foo > c/d/JOSH_ORIG_PATH_c%2Fd
+  $ git commit -a -m "edit marker" 1> /dev/null
+
+  $ josh-filter --reverse master --update refs/heads/dirs :DIRS
+  ERROR: JoshError("DIRS filter: generated file modified: c/d/JOSH_ORIG_PATH_c%2Fd")
+  [1]
+
+  $ git reset --hard HEAD~1 1> /dev/null
+  $ echo foo > c/d/file_new
+  $ git add c
+  $ git commit -m "add file" 1> /dev/null
+
+  $ josh-filter --reverse master --update refs/heads/dirs :DIRS
+  ERROR: JoshError("DIRS filter: file not part of the view: c/d/file_new")
+  [1]
+
+  $ git log --graph --pretty=%s master
+  * edit workspace
+  * add dirs
+
+  $ git reset --hard HEAD~1 1> /dev/null
+  $ git rm -rq a
+  $ git commit -m "remove dir" 1> /dev/null
+
+  $ josh-filter --reverse master --update refs/heads/dirs :DIRS
+
+  $ git log --graph --pretty=%s master
+  * remove dir
+  * edit workspace
+  * add dirs
+  $ git ls-tree -r --name-only master
+  a/file_a2
+  c/d/file_cd
+
+  $ git reset --hard HEAD~1 1> /dev/null
+  $ git rm -q a/JOSH_ORIG_PATH_a
+  $ git commit -m "remove marker" 1> /dev/null
+  $ josh-filter --reverse master --update refs/heads/dirs :DIRS
+  ERROR: JoshError("DIRS filter: generated file modified: a/JOSH_ORIG_PATH_a")
+  [1]
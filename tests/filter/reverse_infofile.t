@@ -0,0 +1,57 @@
+  $ export TESTTMP=${PWD}
+  $ export PATH=${TESTDIR}/../../target/debug/:${PATH}
+
+  $ cd ${TESTTMP}
+  $ git init libs 1> /dev/null
+  $ cd libs
+
+  $ mkdir sub1
+  $ echo contents1 > sub1/file1
+  $ git add sub1
+  $ git commit -m "add file1" 1> /dev/null
+
+  $ mkdir sub2
+  $ echo contents1 > sub2/file2
+  $ git add sub2
+  $ git commit -m "add file2" 1> /dev/null
+
+  $ josh-filter --infofile master --update refs/heads/filtered c=:/sub1
+  $ git checkout filtered 1> /dev/null
+  Switched to branch 'filtered'
+  $ git ls-tree -r --name-only HEAD
+  c/.joshinfo
+  c/file1
+
+  $ echo contents3 > c/file3
+  $ git add c/file3
+  $ git commit -m "add c/file3" 1> /dev/null
+
+  $ josh-filter --infofile --reverse master --update refs/heads/filtered c=:/sub1
+
+  $ git checkout master
+  Switched to branch 'master'
+
+  $ git ls-tree -r --name-only HEAD
+  sub1/file1
+  sub1/file3
+  sub2/file2
+
+  $ git log --graph --pretty=%s
+  * add c/file3
+  * add file2
+  * add file1
+
+  $ git checkout filtered
+  Switched to branch 'filtered'
+  $ echo "src: other" >> c/.joshinfo
+  $ git add c/.joshinfo
+  $ git commit -m "edit .joshinfo" 1> /dev/null
+
+  $ josh-filter --infofile --reverse master --update refs/heads/filtered c=:/sub1
+  ERROR: JoshError("generated file modified: \"c/.joshinfo\"")
+  [1]
+
+  $ git log --graph --pretty=%s master
+  * add c/file3
+  * add file2
+  * add file1
@@ -0,0 +1,116 @@
+  $ export TESTTMP=${PWD}
+  $ export PATH=${TESTDIR}/../../target/debug/:${PATH}
+
+  $ cd ${TESTTMP}
+  $ git init libs 1> /dev/null
+  $ cd libs
+
+  $ mkdir sub1
+  $ echo contents1 > sub1/file1
+  $ mkdir sub2
+  $ echo contents2 > sub2/file2
+  $ git add .
+  $ git commit -m "add files" 1> /dev/null
+  $ echo more >> sub1/file1
+  $ echo more >> sub2/file2
+  $ git commit -am "change files" 1> /dev/null
+
+  $ josh-filter filter master :/sub1 --update refs/heads/filtered
+  $ git log --pretty=%s filtered
+  change files
+  add files
+  $ josh-filter filter master :/sub2 --update refs/heads/filtered2 --json
+  [
+    {
+      "commit": "*", (glob)
+      "filter": ":/sub2",
+      "src": "refs/heads/master",
+      "status": "updated",
+      "target": "refs/heads/filtered2"
+    }
+  ]
+
+  $ cat > syncinfo <<EOF
+  > [master:refs/josh/one] :/sub1
+  > [master:refs/josh/two]
+  > :/sub2
+  > EOF
+  $ josh-filter filter --file syncinfo
+  $ git ls-tree --name-only refs/josh/one
+  file1
+  $ git ls-tree --name-only refs/josh/two
+  file2
+  $ echo ":/sub1" > badfile
+  $ josh-filter filter --file badfile
+  ERROR: JoshError("line 1: filter spec outside of a section")
+  [1]
+
+  $ josh-filter query master :/sub1 get=file1
+  contents1
+  more
+  $ josh-filter query master :/sub1 get=file1 --json
+  {
+    "query": "get=file1",
+    "result": "contents1\nmore\n"
+  }
+
+  $ josh-filter info master :/sub1 --path file1
+  filter: :/sub1
+  original: * (glob)
+  transformed: * (glob)
+  path file1: original sub1/file1, filtered (none)
+  $ josh-filter info master :DIRS --path sub1/file1
+  filter: :DIRS
+  original: * (glob)
+  transformed: * (glob)
+  path sub1/file1: error: DIRS filter: file not part of the view: sub1/file1
+
+  $ josh-filter cache
+  2\t:/sub1 (esc)
+  2\t:/sub2 (esc)
+  4\ttotal (esc)
+  $ josh-filter cache --clear
+  $ josh-filter cache --json
+  {
+    "total": 0
+  }
+
+  $ git checkout -q filtered
+  $ echo contents3 > file3
+  $ git add file3
+  $ git commit -m "add file3" 1> /dev/null
+  $ josh-filter unapply master :/sub1 --update refs/heads/filtered --json
+  {
+    "detail": "*", (glob)
+    "result": "done",
+    "src": "refs/heads/master",
+    "target": "refs/heads/filtered"
+  }
+  $ git log --pretty=%s master
+  add file3
+  change files
+  add files
+  $ git ls-tree --name-only -r master
+  sub1/file1
+  sub1/file3
+  sub2/file2
+
+The old form still works
+
+  $ josh-filter master --update refs/heads/filtered :/sub1 -q get=file3
+  contents3
+
+Branches named like a subcommand can be used with the old form
+
+  $ git branch info master
+  $ git branch cache master
+  $ josh-filter info :/sub1 -q get=file1
+  contents1
+  more
+  $ josh-filter info --update refs/heads/info_filtered :/sub2
+  $ git ls-tree --name-only info_filtered
+  file2
+  $ josh-filter info master :/sub1 --path file1 | tail -1
+  path file1: original sub1/file1, filtered (none)
+  $ josh-filter cache --json | grep total
+    "total": * (glob)
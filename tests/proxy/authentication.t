@@ -81,10 +81,11 @@
   |   |               `-- master
   |   `-- upstream
   |       `-- real_repo.git
+  |           |-- HEAD
   |           `-- refs
   |               `-- heads
   |                   `-- master
   |-- namespaces
   `-- tags
   
-  14 directories, 3 files
+  14 directories, 4 files
@@ -83,10 +83,11 @@
   |   |               `-- master
   |   `-- upstream
   |       `-- real_repo.git
+  |           |-- HEAD
   |           `-- refs
   |               `-- heads
   |                   `-- master
   |-- namespaces
   `-- tags
   
-  16 directories, 4 files
+  16 directories, 5 files
@@ -0,0 +1,80 @@
+  $ . ${TESTDIR}/setup_test_env.sh
+  $ cd ${TESTTMP}
+
+  $ git clone -q http://localhost:8001/real_repo.git
+  warning: You appear to have cloned an empty repository.
+
+  $ cd real_repo
+  $ mkdir sub1
+  $ echo contents1 > sub1/file1
+  $ git add sub1
+  $ git commit -m "add file1" 1> /dev/null
+  $ echo contents2 > sub1/file2
+  $ git add sub1
+  $ git commit -m "add file2" 1> /dev/null
+  $ git push -q
+
+  $ josh-filter master --update refs/heads/filtered :/sub1
+  $ want="want $(git rev-parse filtered) side-band-64k no-progress"
+  $ printf '%04x%s\n0000' $((${#want}+5)) "$want" > ${TESTTMP}/request
+  $ printf '0009done\n' >> ${TESTTMP}/request
+
+A request for the pack of a view that was not filtered yet gets the filter
+progress on side-band 2 before the pack
+
+  $ curl -s -H "Content-Type: application/x-git-upload-pack-request" \
+  >   --data-binary @${TESTTMP}/request \
+  >   http://localhost:8002/real_repo.git:/sub1.git/git-upload-pack \
+  >   > ${TESTTMP}/response
+  $ head -c 8 ${TESTTMP}/response
+  0008NAK
+  $ grep -a filtering ${TESTTMP}/response
+  0023\x02filtering :/sub1: 1/1 commits (esc)
+  $ grep -ac PACK ${TESTTMP}/response
+  1
+
+A request that is too large to be checked up front is passed on to
+upload-pack as it is
+
+  $ have="have $(git rev-parse filtered)"
+  $ for i in $(seq 25000); do
+  >   printf '%04x%s\n' $((${#have}+5)) "$have"
+  > done > ${TESTTMP}/haves
+  $ (printf '%04x%s\n' $((${#want}+5)) "$want"; printf '0000';
+  >   cat ${TESTTMP}/haves; printf '0009done\n') > ${TESTTMP}/large_request
+  $ test $(stat -c %s ${TESTTMP}/large_request) -gt 1048576
+  $ curl -s -H "Content-Type: application/x-git-upload-pack-request" \
+  >   --data-binary @${TESTTMP}/large_request \
+  >   http://localhost:8002/real_repo.git:/sub1.git/git-upload-pack \
+  >   > ${TESTTMP}/response
+  $ head -c 7 ${TESTTMP}/response
+  0031ACK (no-eol)
+  $ grep -ac PACK ${TESTTMP}/response
+  1
+
+  $ cd ${TESTTMP}
+  $ git clone http://localhost:8002/real_repo.git:/sub1.git sub1
+  Cloning into 'sub1'...
+  $ git -C sub1 log --pretty=%s
+  add file2
+  add file1
+
+  $ bash ${TESTDIR}/destroy_test_env.sh
+  remote/scratch/refs
+  |-- heads
+  |-- josh
+  |   |-- filtered
+  |   |   `-- real_repo.git
+  |   |       `-- %3A%2Fsub1
+  |   |           `-- heads
+  |   |               `-- master
+  |   `-- upstream
+  |       `-- real_repo.git
+  |           |-- HEAD
+  |           `-- refs
+  |               `-- heads
+  |                   `-- master
+  |-- namespaces
+  `-- tags
+  
+  12 directories, 3 files
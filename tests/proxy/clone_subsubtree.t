@@ -99,10 +99,11 @@
   |   |               `-- master
   |   `-- upstream
   |       `-- real_repo.git
+  |           |-- HEAD
   |           `-- refs
   |               `-- heads
   |                   `-- master
   |-- namespaces
   `-- tags
   
-  18 directories, 5 files
+  18 directories, 6 files
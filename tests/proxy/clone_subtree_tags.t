@@ -121,6 +121,7 @@
   |   |               `-- master
   |   `-- upstream
   |       `-- real_repo.git
+  |           |-- HEAD
   |           `-- refs
   |               |-- heads
   |               |   `-- master
@@ -129,5 +130,5 @@
   |-- namespaces
   `-- tags
   
-  15 directories, 4 files
+  15 directories, 5 files
 $ cat ${TESTTMP}/josh-proxy.out | grep TAGS
@@ -0,0 +1,81 @@
+  $ . ${TESTDIR}/setup_test_env.sh
+  $ cd ${TESTTMP}
+
+  $ git clone -q http://localhost:8001/real_repo.git
+  warning: You appear to have cloned an empty repository.
+
+  $ cd real_repo
+
+  $ mkdir sub1
+  $ echo contents1 > sub1/file1
+  $ echo contents1 > file2
+  $ git add .
+  $ git commit -m "add files" 1> /dev/null
+  $ for b in a b c d e; do
+  >   git checkout -q -b $b
+  >   echo $b > sub1/file_$b
+  >   git add sub1
+  >   git commit -q -m "add file_$b"
+  > done
+  $ git push -q --all
+
+Restart the proxy filtering the refs on multiple threads
+
+  $ kill $(cat ${TESTTMP}/proxy_pid)
+  $ ${TESTDIR}/../../target/debug/josh-proxy \
+  >   --port=8002 \
+  >   --local=${TESTTMP}/remote/scratch/ \
+  >   --remote=http://localhost:8001 \
+  >   --threads=4 \
+  >   > ${TESTTMP}/josh-proxy.out 2>&1 &
+  $ echo $! > ${TESTTMP}/proxy_pid
+  $ sleep 1
+
+  $ cd ${TESTTMP}
+  $ git clone -q http://localhost:8002/real_repo.git:/sub1.git sub1
+  $ cd sub1
+  $ git branch -r
+    origin/HEAD -> origin/master
+    origin/a
+    origin/b
+    origin/c
+    origin/d
+    origin/e
+    origin/master
+  $ git log --pretty=%s origin/e
+  add file_e
+  add file_d
+  add file_c
+  add file_b
+  add file_a
+  add files
+  $ git ls-tree --name-only origin/c
+  file1
+  file_a
+  file_b
+  file_c
+
+  $ bash ${TESTDIR}/destroy_test_env.sh
+  remote/scratch/refs
+  |-- heads
+  |-- josh
+  |   |-- filtered
+  |   |   `-- real_repo.git
+  |   |       `-- %3A%2Fsub1
+  |   |           `-- heads
+  |   |               `-- master
+  |   `-- upstream
+  |       `-- real_repo.git
+  |           |-- HEAD
+  |           `-- refs
+  |               `-- heads
+  |                   |-- a
+  |                   |-- b
+  |                   |-- c
+  |                   |-- d
+  |                   |-- e
+  |                   `-- master
+  |-- namespaces
+  `-- tags
+  
+  12 directories, 8 files
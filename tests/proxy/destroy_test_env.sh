@@ -1,4 +1,4 @@
 #!/bin/bash
-${TESTDIR}/../../target/debug/josh-proxy -m --local=${TESTTMP}/remote/scratch/ > /dev/null 2>&1
+${TESTDIR}/../../target/debug/josh-proxy -m --threads=4 --local=${TESTTMP}/remote/scratch/ > /dev/null 2>&1
 cd ${TESTTMP}; tree remote/scratch/refs
 #cat ${TESTTMP}/josh-proxy.out
@@ -0,0 +1,87 @@
+  $ . ${TESTDIR}/setup_test_env.sh
+  $ cd ${TESTTMP}
+
+  $ git --git-dir=${TESTTMP}/remote/real/repo2.git symbolic-ref HEAD refs/heads/main
+
+  $ git clone -q http://localhost:8001/real/repo2.git
+  warning: You appear to have cloned an empty repository.
+
+  $ cd repo2
+  $ git checkout -q -b main
+
+  $ mkdir sub1
+  $ echo contents1 > sub1/file1
+  $ git add sub1
+  $ git commit -m "add file1" 1> /dev/null
+
+  $ git checkout -q -b release/1.0
+  $ echo contents2 > sub1/file2
+  $ git add sub1
+  $ git commit -m "add file2" 1> /dev/null
+
+  $ git checkout -q -b feature
+  $ echo contents3 > sub1/file3
+  $ git add sub1
+  $ git commit -m "add file3" 1> /dev/null
+
+  $ git push -q --all
+
+  $ cd ${TESTTMP}
+
+  $ curl -s http://localhost:8002/real/repo2.git@refs/heads/main:/sub1.git?info > /dev/null
+
+  $ git --git-dir=${TESTTMP}/remote/scratch symbolic-ref refs/josh/upstream/real%2Frepo2.git/HEAD
+  refs/josh/upstream/real%2Frepo2.git/refs/heads/main
+
+  $ git --git-dir=${TESTTMP}/remote/scratch config --add josh.branches "release/*"
+  $ josh-proxy -m --local=${TESTTMP}/remote/scratch/ > /dev/null 2>&1
+
+  $ git --git-dir=${TESTTMP}/remote/scratch for-each-ref --format="%(refname)" refs/josh/filtered
+  refs/josh/filtered/real%2Frepo2.git/%3A%2Fsub1/heads/main
+  refs/josh/filtered/real%2Frepo2.git/%3A%2Fsub1/heads/release/1.0
+
+  $ git --git-dir=${TESTTMP}/remote/scratch log --pretty=%s refs/josh/filtered/real%2Frepo2.git/%3A%2Fsub1/heads/release/1.0
+  add file2
+  add file1
+
+The mirrored HEAD is kept on later fetches and refreshed once it is missing
+
+  $ git --git-dir=${TESTTMP}/remote/real/repo2.git symbolic-ref HEAD refs/heads/feature
+  $ curl -s http://localhost:8002/flush
+  Flushed credential cache
+  $ curl -s http://localhost:8002/real/repo2.git@refs/heads/main:/sub1.git?info > /dev/null
+  $ git --git-dir=${TESTTMP}/remote/scratch symbolic-ref refs/josh/upstream/real%2Frepo2.git/HEAD
+  refs/josh/upstream/real%2Frepo2.git/refs/heads/main
+
+  $ git --git-dir=${TESTTMP}/remote/scratch symbolic-ref --delete refs/josh/upstream/real%2Frepo2.git/HEAD
+  $ curl -s http://localhost:8002/flush
+  Flushed credential cache
+  $ curl -s http://localhost:8002/real/repo2.git@refs/heads/main:/sub1.git?info > /dev/null
+  $ git --git-dir=${TESTTMP}/remote/scratch symbolic-ref refs/josh/upstream/real%2Frepo2.git/HEAD
+  refs/josh/upstream/real%2Frepo2.git/refs/heads/feature
+
+  $ bash ${TESTDIR}/destroy_test_env.sh
+  remote/scratch/refs
+  |-- heads
+  |-- josh
+  |   |-- filtered
+  |   |   `-- real%2Frepo2.git
+  |   |       `-- %3A%2Fsub1
+  |   |           `-- heads
+  |   |               |-- feature
+  |   |               |-- main
+  |   |               `-- release
+  |   |                   `-- 1.0
+  |   `-- upstream
+  |       `-- real%2Frepo2.git
+  |           |-- HEAD
+  |           `-- refs
+  |               `-- heads
+  |                   |-- feature
+  |                   |-- main
+  |                   `-- release
+  |                       `-- 1.0
+  |-- namespaces
+  `-- tags
+  
+  14 directories, 7 files
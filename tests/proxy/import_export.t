@@ -316,18 +316,21 @@ Empty roots should not be dropped -> sha1 equal guarantee for "nop"
   |   |               `-- master
   |   `-- upstream
   |       |-- real_repo.git
+  |       |   |-- HEAD
   |       |   `-- refs
   |       |       `-- heads
   |       |           `-- master
   |       |-- repo1.git
+  |       |   |-- HEAD
   |       |   `-- refs
   |       |       `-- heads
   |       |           `-- master
   |       `-- repo2.git
+  |           |-- HEAD
   |           `-- refs
   |               `-- heads
   |                   `-- master
   |-- namespaces
   `-- tags
   
-  28 directories, 8 files
+  28 directories, 11 files
@@ -39,10 +39,31 @@ Get /info/refs to trigger rebuilding and pass credentials
   *\trefs/heads/master (esc) (glob)
 
   $ curl -s http://localhost:8002/real_repo.git@refs/heads/master:/sub1.git?info
-  {"original":{"commit":"*","parents":[{"commit":"*","tree":"*"}],"tree":"*"},"transformed":{"commit":"*","parents":[],"tree":"*"}} (glob)
+  {"filter":":/sub1","original":{"commit":"*","parents":[{"commit":"*","tree":"*"}],"tree":"*"},"paths":[],"prefixes":{},"transformed":{"commit":"*","parents":[],"tree":"*"}} (glob)
 
   $ curl -s http://localhost:8002/real_repo.git@refs/heads/master:/nothing_here.git?info
-  {"original":{"commit":"*","parents":[{"commit":"*","tree":"*"}],"tree":"*"},"transformed":{"commit":"0000000000000000000000000000000000000000","parents":[],"tree":"0000000000000000000000000000000000000000"}} (glob)
+  {"filter":":/nothing_here","original":{"commit":"*","parents":[{"commit":"*","tree":"*"}],"tree":"*"},"paths":[],"prefixes":{},"transformed":{"commit":"0000000000000000000000000000000000000000","parents":[],"tree":"0000000000000000000000000000000000000000"}} (glob)
+
+Any revision of the upstream repo can be described and paths translated
+in both directions
+
+  $ curl -s "http://localhost:8002/real_repo.git:/sub1.git?info&rev=HEAD~1&path=file1&path=sub1/file1&path=&path=sub2"
+  {"filter":":/sub1","original":{"commit":"*","parents":[],"tree":"*"},"paths":[{"filtered":null,"original":"sub1/file1","path":"file1"},{"filtered":"file1","original":"sub1/sub1/file1","path":"sub1/file1"},{"filtered":null,"original":"sub1","path":""},{"filtered":null,"original":"sub1/sub2","path":"sub2"}],"prefixes":{},"transformed":{"commit":"*","parents":[],"tree":"*"}} (glob)
+
+  $ curl -s "http://localhost:8002/real_repo.git:/sub1.git?info&rev=$(git rev-parse HEAD~1)" | grep -c "\"original\":{\"commit\":\"$(git rev-parse HEAD~1)\""
+  1
+
+  $ curl -s "http://localhost:8002/real_repo.git:/sub1.git?info&rev=refs/heads/master~1" | grep -c "\"original\":{\"commit\":\"$(git rev-parse HEAD~1)\""
+  1
+
+  $ curl -s "http://localhost:8002/real_repo.git:prefix=pre.git?info&path=pre/sub1/file1&path=sub2" | grep -o '"paths":[^]]*]'
+  "paths":[{"filtered":"pre/pre/sub1/file1","original":"sub1/file1","path":"pre/sub1/file1"},{"filtered":"pre/sub2","original":null,"path":"sub2"}]
+
+  $ curl -s "http://localhost:8002/real_repo.git:DIRS.git?info&path=sub1/file1" | grep -o '"paths":[^]]*]'
+  "paths":[{"error":"DIRS filter: file not part of the view: sub1/file1","filtered":null,"original":null,"path":"sub1/file1"}]
+
+  $ curl -s "http://localhost:8002/real_repo.git:/sub1.git?info&rev=0000000000000000000000000000000000000001"
+  get_info: error: revision "0000000000000000000000000000000000000001" not found in "/real_repo.git"
 
   $ bash ${TESTDIR}/destroy_test_env.sh
   remote/scratch/refs
@@ -53,15 +74,22 @@ Get /info/refs to trigger rebuilding and pass credentials
   |   |       |-- %3A%2Fsub1
   |   |       |   `-- heads
   |   |       |       `-- master
-  |   |       `-- %3A%2Fsub2
+  |   |       |-- %3A%2Fsub2
+  |   |       |   `-- heads
+  |   |       |       `-- master
+  |   |       |-- %3ADIRS
+  |   |       |   `-- heads
+  |   |       |       `-- master
+  |   |       `-- %3Aprefix=pre
   |   |           `-- heads
   |   |               `-- master
   |   `-- upstream
   |       `-- real_repo.git
+  |           |-- HEAD
   |           `-- refs
   |               `-- heads
   |                   `-- master
   |-- namespaces
   `-- tags
   
-  14 directories, 3 files
+  18 directories, 6 files
@@ -0,0 +1,98 @@
+  $ . ${TESTDIR}/setup_test_env.sh
+  $ cd ${TESTTMP}
+
+  $ git clone -q http://localhost:8001/real_repo.git
+  warning: You appear to have cloned an empty repository.
+
+  $ cd real_repo
+  $ cat > tmpl_file <<EOF
+  > {{ josh-kv "shared" }} {{ josh-kv "own" }}
+  > EOF
+  $ git add tmpl_file
+  $ git commit -m "add tmpl_file" 1> /dev/null
+  $ git push -q
+  $ cd ${TESTTMP}
+
+  $ curl -s http://localhost:8002/@kv/shared -X POST -d '"global"'
+  ok (no-eol)
+  $ curl -s http://localhost:8002/@kv/own -X POST -d '"global"'
+  ok (no-eol)
+  $ curl -s http://localhost:8002/@kv/real_repo.git/own -X PUT -d '"repo"'
+  ok (no-eol)
+  $ curl -s http://localhost:8002/@kv/real/repo2.git/own -X PUT -d '"other repo"'
+  ok (no-eol)
+
+Keys of the repo take precedence over the global ones
+
+  $ curl -s http://localhost:8002/real_repo.git?render=tmpl_file
+  global repo
+
+  $ curl -s http://localhost:8002/@kv/
+  ["own","shared"] (no-eol)
+  $ curl -s http://localhost:8002/@kv/real_repo.git/
+  ["own"] (no-eol)
+  $ curl -s http://localhost:8002/@kv/real/repo2.git/own
+  "other repo" (no-eol)
+
+  $ curl -s -w "%{http_code}\n" http://localhost:8002/@kv/real_repo.git/missing
+  404
+  $ curl -s -w "%{http_code}\n" http://localhost:8002/@kv/bad -X POST -d '{"a":'
+  invalid json: EOF while parsing a value at line 1 column 5
+  400
+  $ curl -s -w "%{http_code}\n" http://localhost:8002/@kv/ -X POST -d '1'
+  missing key
+  405
+
+  $ curl -s http://localhost:8002/@kv/real_repo.git/own -X DELETE
+  ok (no-eol)
+  $ curl -s -w "%{http_code}\n" http://localhost:8002/@kv/real_repo.git/own -X DELETE
+  404
+  $ curl -s http://localhost:8002/real_repo.git?render=tmpl_file
+  global global
+
+The store survives a restart of the proxy
+
+  $ cat ${TESTTMP}/remote/scratch/josh_kv.json
+  {
+    "version": 1,
+    "namespaces": {
+      "": {
+        "own": "global",
+        "shared": "global"
+      },
+      "/real/repo2.git": {
+        "own": "other repo"
+      }
+    }
+  } (no-eol)
+
+  $ kill $(cat ${TESTTMP}/proxy_pid)
+  $ ${TESTDIR}/../../target/debug/josh-proxy \
+  >   --port=8002 \
+  >   --local=${TESTTMP}/remote/scratch/ \
+  >   --remote=http://localhost:8001 \
+  >   > ${TESTTMP}/josh-proxy.out 2>&1 &
+  $ echo $! > ${TESTTMP}/proxy_pid
+  $ sleep 1
+
+  $ curl -s http://localhost:8002/@kv/real/repo2.git/own
+  "other repo" (no-eol)
+
+  $ bash ${TESTDIR}/destroy_test_env.sh
+  remote/scratch/refs
+  |-- heads
+  |-- josh
+  |   |-- filtered
+  |   |   `-- real_repo.git
+  |   |       `-- %3Anop
+  |   |           `-- heads
+  |   |               `-- master
+  |   `-- upstream
+  |       `-- real_repo.git
+  |           |-- HEAD
+  |           `-- refs
+  |               `-- heads
+  |                   `-- master
+  `-- tags
+  
+  11 directories, 3 files
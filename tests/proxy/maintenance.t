@@ -0,0 +1,140 @@
+  $ . ${TESTDIR}/setup_test_env.sh
+  $ cd ${TESTTMP}
+
+  $ git clone -q http://localhost:8001/real_repo.git
+  warning: You appear to have cloned an empty repository.
+
+  $ cd real_repo
+
+  $ mkdir sub1
+  $ echo contents1 > sub1/file1
+  $ git add sub1
+  $ git commit -m "add file1" 1> /dev/null
+
+  $ git push -q
+
+  $ cd ${TESTTMP}
+
+  $ git clone -q http://localhost:8002/real_repo.git:/sub1.git sub1
+
+  $ curl -s http://localhost:8002/maintenance
+  {}
+
+  $ RUST_LOG=off josh-proxy -m --gc --local=${TESTTMP}/remote/scratch/ 2> /dev/null
+  [
+    {
+      "step": "count-objects",
+      "started": *, (glob)
+      "duration_ms": *, (glob)
+      "success": true,
+      "output": {
+        "count": *, (glob)
+        "packs": 0,
+        "size": *, (glob)
+        "size-pack": 0
+      },
+      "error": null
+    },
+    {
+      "step": "repack",
+      "started": *, (glob)
+      "duration_ms": *, (glob)
+      "success": true,
+      "output": {
+        "kept_objects": 2,
+        "kept_refs": 3,
+        "packed_objects": 5,
+        "removed_loose_objects": 5,
+        "removed_packs": 0
+      },
+      "error": null
+    },
+    {
+      "step": "prune",
+      "started": *, (glob)
+      "duration_ms": *, (glob)
+      "success": true,
+      "output": {
+        "kept_objects": 2,
+        "kept_refs": 3,
+        "pruned_objects": 0
+      },
+      "error": null
+    }
+  ]
+
+  $ RUST_LOG=off josh-proxy -m --gc --maintenance=count-objects=0 --local=${TESTTMP}/remote/scratch/ 2> /dev/null | grep -A3 output
+      "output": {
+        "count": 0,
+        "packs": 1,
+        "size": 0,
+
+Commits only known to the filter cache are kept in a pack of their own
+
+  $ kill $(cat ${TESTTMP}/proxy_pid)
+  $ ${TESTDIR}/../../target/debug/josh-proxy --port=8002 --local=${TESTTMP}/remote/scratch/ --remote=http://localhost:8001 --maintenance=repack=2 > ${TESTTMP}/josh-proxy.out 2>&1 &
+  $ echo $! > ${TESTTMP}/proxy_pid
+  $ sleep 1
+
+  $ git clone -q http://localhost:8002/real_repo.git:prefix=pre.git pre
+  $ git --git-dir=${TESTTMP}/remote/scratch update-ref -d "refs/josh/filtered/real_repo.git/%3Aprefix=pre/heads/master"
+  $ sleep 12
+  $ curl -s http://localhost:8002/maintenance | grep '"success"'
+      "success": true,
+  $ cat ${TESTTMP}/remote/scratch/objects/pack/*.keep
+  josh maintenance
+  $ for k in ${TESTTMP}/remote/scratch/objects/pack/*.keep; do git verify-pack -v ${k%.keep}.idx; done | grep -c "$(git -C pre rev-parse HEAD) commit"
+  1
+
+Repack packs all objects into one new pack, also unreachable ones. Without
+the persisted maps knowing the commit of the filtered ref deleted above, its
+pack is not kept anymore
+
+  $ kill $(cat ${TESTTMP}/proxy_pid)
+  $ ${TESTDIR}/../../target/debug/josh-proxy --port=8002 --local=${TESTTMP}/remote/scratch/ --remote=http://localhost:8001 > ${TESTTMP}/josh-proxy.out 2>&1 &
+  $ echo $! > ${TESTTMP}/proxy_pid
+  $ sleep 1
+
+  $ ls ${TESTTMP}/remote/scratch/objects/pack/*.pack | wc -l
+  2
+  $ RUST_LOG=off josh-proxy -m --gc --maintenance=repack=0 --local=${TESTTMP}/remote/scratch/ 2> /dev/null | grep removed_packs
+        "removed_packs": 2
+  $ ls ${TESTTMP}/remote/scratch/objects/pack/*.pack | wc -l
+  1
+  $ git --git-dir=${TESTTMP}/remote/scratch cat-file -t $(git -C pre rev-parse HEAD)
+  commit
+  $ git --git-dir=${TESTTMP}/remote/scratch fsck --no-dangling 2> /dev/null
+
+Prune removes loose objects no ref reaches once they are older than two weeks
+
+  $ old=$(echo old | git --git-dir=${TESTTMP}/remote/scratch hash-object -w --stdin)
+  $ new=$(echo new | git --git-dir=${TESTTMP}/remote/scratch hash-object -w --stdin)
+  $ touch -d "3 weeks ago" ${TESTTMP}/remote/scratch/objects/$(echo $old | cut -c1-2)/$(echo $old | cut -c3-)
+  $ RUST_LOG=off josh-proxy -m --gc --maintenance=prune=0 --local=${TESTTMP}/remote/scratch/ 2> /dev/null | grep pruned_objects
+        "pruned_objects": 1
+  $ git --git-dir=${TESTTMP}/remote/scratch cat-file -e $old 2> /dev/null
+  [1]
+  $ git --git-dir=${TESTTMP}/remote/scratch cat-file -t $new
+  blob
+
+  $ josh-proxy -m --gc --maintenance=gc=0 --local=${TESTTMP}/remote/scratch/ 2>&1 | grep -o "unknown maintenance step: gc"
+  unknown maintenance step: gc
+
+  $ bash ${TESTDIR}/destroy_test_env.sh
+  remote/scratch/refs
+  |-- heads
+  |-- josh
+  |   |-- filtered
+  |   |   `-- real_repo.git
+  |   |       `-- %3A%2Fsub1
+  |   |           `-- heads
+  |   |               `-- master
+  |   `-- upstream
+  |       `-- real_repo.git
+  |           |-- HEAD
+  |           `-- refs
+  |               `-- heads
+  |                   `-- master
+  `-- tags
+  
+  11 directories, 3 files
@@ -0,0 +1,56 @@
+  $ . ${TESTDIR}/setup_test_env.sh
+  $ cd ${TESTTMP}
+
+  $ git clone -q http://localhost:8001/real_repo.git
+  warning: You appear to have cloned an empty repository.
+
+  $ cd real_repo
+
+  $ mkdir sub1
+  $ echo contents1 > sub1/file1
+  $ git add sub1
+  $ git commit -m "add file1" 1> /dev/null
+
+  $ git push -q
+
+  $ cd ${TESTTMP}
+
+  $ git clone -q http://localhost:8002/real_repo.git:/sub1.git sub1
+
+Simulate namespaces leaked by requests that never finished
+
+  $ cd ${TESTTMP}/remote/scratch
+  $ SHA=$(git rev-parse refs/josh/upstream/real_repo.git/refs/heads/master)
+  $ git update-ref refs/namespaces/request_1000_packed/refs/heads/master ${SHA}
+  $ git pack-refs --all
+  $ git update-ref refs/namespaces/request_1000_loose/refs/heads/master ${SHA}
+  $ git update-ref refs/namespaces/request_0fb3b5b9-legacy/HEAD ${SHA}
+  $ git update-ref refs/namespaces/request_$(date +%s)_fresh/HEAD ${SHA}
+  $ git for-each-ref --format="%(refname)" refs/namespaces/ | sed 's/_[0-9]\{10\}_/_NOW_/'
+  refs/namespaces/request_0fb3b5b9-legacy/HEAD
+  refs/namespaces/request_1000_loose/refs/heads/master
+  refs/namespaces/request_1000_packed/refs/heads/master
+  refs/namespaces/request_NOW_fresh/HEAD
+
+  $ josh-proxy -m --local=${TESTTMP}/remote/scratch/ > /dev/null 2>&1
+  $ git for-each-ref --format="%(refname)" refs/namespaces/ | sed 's/_[0-9]\{10\}_/_NOW_/'
+  refs/namespaces/request_NOW_fresh/HEAD
+  $ grep -c request_ packed-refs
+  0
+  [1]
+
+  $ josh-proxy -m --namespace-grace=0 --local=${TESTTMP}/remote/scratch/ > /dev/null 2>&1
+  $ git for-each-ref --format="%(refname)" refs/namespaces/
+  $ ls refs/namespaces
+
+  $ bash ${TESTDIR}/destroy_test_env.sh
+  remote/scratch/refs
+  |-- heads
+  |-- josh
+  |   `-- upstream
+  |       `-- real_repo.git
+  |           `-- HEAD
+  |-- namespaces
+  `-- tags
+  
+  6 directories, 1 file
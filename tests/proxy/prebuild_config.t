@@ -0,0 +1,94 @@
+  $ . ${TESTDIR}/setup_test_env.sh
+  $ cd ${TESTTMP}
+
+  $ git clone -q http://localhost:8001/real/repo2.git
+  warning: You appear to have cloned an empty repository.
+
+  $ cd repo2
+
+  $ mkdir -p sub1/a/b/c sub1/a/b/d sub2 sub3
+  $ echo contents1 > sub1/a/b/c/file1
+  $ echo contents2 > sub1/a/b/d/file2
+  $ echo contents3 > sub2/file3
+  $ echo contents4 > sub3/file4
+  $ git add .
+  $ git commit -m "initial" 1> /dev/null
+
+  $ git push -q
+  $ git push -q origin master:refs/heads/release/1
+  $ git push -q origin master:refs/heads/other
+
+  $ cd ${TESTTMP}
+
+  $ git clone -q http://localhost:8002/real/repo2.git:/sub3.git sub3
+
+  $ cat > prebuild.toml <<EOF
+  > [[prebuild]]
+  > repos = ["real/*.git"]
+  > filters = [":/sub1/a/b/*"]
+  > 
+  > [[prebuild]]
+  > repos = ["real/*.git"]
+  > filters = [":/sub2"]
+  > branches = ["release/*"]
+  > 
+  > [[prebuild]]
+  > repos = ["other/*.git"]
+  > filters = [":/sub2"]
+  > branches = ["other"]
+  > EOF
+
+  $ josh-proxy -m --local=${TESTTMP}/remote/scratch/ --prebuild-config=prebuild.toml > /dev/null 2>&1
+
+  $ git --git-dir=${TESTTMP}/remote/scratch for-each-ref --format="%(refname)" refs/josh/filtered
+  refs/josh/filtered/real%2Frepo2.git/%3A%2Fsub1%2Fa%2Fb%2Fc/heads/master
+  refs/josh/filtered/real%2Frepo2.git/%3A%2Fsub1%2Fa%2Fb%2Fd/heads/master
+  refs/josh/filtered/real%2Frepo2.git/%3A%2Fsub1%2Fa/heads/master
+  refs/josh/filtered/real%2Frepo2.git/%3A%2Fsub1/heads/master
+  refs/josh/filtered/real%2Frepo2.git/%3A%2Fsub2/heads/master
+  refs/josh/filtered/real%2Frepo2.git/%3A%2Fsub2/heads/release/1
+  refs/josh/filtered/real%2Frepo2.git/%3A%2Fsub3/heads/master
+
+  $ echo "[[prebuild]]" > invalid.toml
+  $ josh-proxy -m --local=${TESTTMP}/remote/scratch/ --prebuild-config=invalid.toml 2>/dev/null | grep ERROR
+  ERROR: JoshError("invalid prebuild config \"invalid.toml\": missing field `repos` for key `prebuild` at line 1 column 1")
+
+  $ bash ${TESTDIR}/destroy_test_env.sh
+  remote/scratch/refs
+  |-- heads
+  |-- josh
+  |   |-- filtered
+  |   |   `-- real%2Frepo2.git
+  |   |       |-- %3A%2Fsub1
+  |   |       |   `-- heads
+  |   |       |       `-- master
+  |   |       |-- %3A%2Fsub1%2Fa
+  |   |       |   `-- heads
+  |   |       |       `-- master
+  |   |       |-- %3A%2Fsub1%2Fa%2Fb%2Fc
+  |   |       |   `-- heads
+  |   |       |       `-- master
+  |   |       |-- %3A%2Fsub1%2Fa%2Fb%2Fd
+  |   |       |   `-- heads
+  |   |       |       `-- master
+  |   |       |-- %3A%2Fsub2
+  |   |       |   `-- heads
+  |   |       |       |-- master
+  |   |       |       `-- release
+  |   |       |           `-- 1
+  |   |       `-- %3A%2Fsub3
+  |   |           `-- heads
+  |   |               `-- master
+  |   `-- upstream
+  |       `-- real%2Frepo2.git
+  |           |-- HEAD
+  |           `-- refs
+  |               `-- heads
+  |                   |-- master
+  |                   |-- other
+  |                   `-- release
+  |                       `-- 1
+  |-- namespaces
+  `-- tags
+  
+  24 directories, 11 files
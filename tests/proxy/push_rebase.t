@@ -0,0 +1,152 @@
+  $ . ${TESTDIR}/setup_test_env.sh
+  $ cd ${TESTTMP}
+
+  $ git clone -q http://localhost:8001/real_repo.git 1> /dev/null
+  warning: You appear to have cloned an empty repository.
+  $ cd real_repo
+
+  $ mkdir sub1
+  $ echo contents1 > sub1/file1
+  $ mkdir sub2
+  $ echo contents1 > sub2/file2
+  $ git add .
+  $ git commit -m "add files" 1> /dev/null
+  $ git push 1> /dev/null
+  To http://localhost:8001/real_repo.git
+   * [new branch]      master -> master
+
+  $ cd ${TESTTMP}
+
+  $ git clone -q http://localhost:8002/real_repo.git:/sub1.git
+  $ cd sub1
+
+  $ cd ${TESTTMP}/real_repo
+  $ echo contents2 > sub2/file2
+  $ git commit -a -m "change sub2" 1> /dev/null
+  $ git push 1> /dev/null
+  To http://localhost:8001/real_repo.git
+     *..*  master -> master (glob)
+
+  $ cd ${TESTTMP}/sub1
+  $ echo contents3 > file3
+  $ git add file3
+  $ git commit -m "add file3" 1> /dev/null
+  $ git push origin HEAD:refs/heads/master%josh-rebase
+  remote: josh-proxy        
+  remote: response from upstream:        
+  remote:  To http://localhost:8001/real_repo.git        
+  remote:    *..*  JOSH_PUSH -> master         (glob)
+  remote: 
+  remote: 
+  To http://localhost:8002/real_repo.git:/sub1.git
+   * [new branch]      HEAD -> master%josh-rebase
+
+  $ cd ${TESTTMP}/real_repo
+  $ git pull --rebase 1> /dev/null
+  From http://localhost:8001/real_repo
+     *..*  master     -> origin/master (glob)
+  $ git log --graph --pretty=%s
+  * add file3
+  * change sub2
+  * add files
+  $ git ls-tree -r --name-only HEAD
+  sub1/file1
+  sub1/file3
+  sub2/file2
+
+Changes to the same directory are rebased if they don't conflict
+  $ echo contents4 > sub1/file1
+  $ git commit -a -m "change file1 upstream" 1> /dev/null
+  $ git push 1> /dev/null
+  To http://localhost:8001/real_repo.git
+     *..*  master -> master (glob)
+
+  $ cd ${TESTTMP}/sub1
+  $ curl -s http://localhost:8002/flush
+  Flushed credential cache
+  $ git fetch -q
+  $ git reset --hard origin/master~1 1> /dev/null
+  $ echo contents4 > file4
+  $ git add file4
+  $ git commit -m "add file4" 1> /dev/null
+  $ git push origin HEAD:refs/heads/master%josh-rebase
+  remote: josh-proxy        
+  remote: response from upstream:        
+  remote:  To http://localhost:8001/real_repo.git        
+  remote:    *..*  JOSH_PUSH -> master         (glob)
+  remote: 
+  remote: 
+  To http://localhost:8002/real_repo.git:/sub1.git
+   * [new branch]      HEAD -> master%josh-rebase
+
+  $ cd ${TESTTMP}/real_repo
+  $ git pull --rebase 1> /dev/null
+  From http://localhost:8001/real_repo
+     *..*  master     -> origin/master (glob)
+  $ git log --graph --pretty=%s
+  * add file4
+  * change file1 upstream
+  * add file3
+  * change sub2
+  * add files
+  $ cat sub1/file1
+  contents4
+
+Conflicting changes are rejected
+  $ echo contents5 > sub1/file1
+  $ git commit -a -m "change file1 upstream again" 1> /dev/null
+  $ git push 1> /dev/null
+  To http://localhost:8001/real_repo.git
+     *..*  master -> master (glob)
+
+  $ cd ${TESTTMP}/sub1
+  $ curl -s http://localhost:8002/flush
+  Flushed credential cache
+  $ git fetch -q
+  $ git reset --hard origin/master~1 1> /dev/null
+  $ echo contents6 > file1
+  $ git commit -a -m "change file1" 1> /dev/null
+  $ git push origin HEAD:refs/heads/master%josh-rebase
+  remote: josh-proxy        
+  remote: response from upstream:        
+  remote:  josh_rebase: conflict in commit *: sub1/file1         (glob)
+  remote: 
+  remote: 
+  remote: error: hook declined to update refs/heads/master%josh-rebase        
+  To http://localhost:8002/real_repo.git:/sub1.git
+   ! [remote rejected] HEAD -> master%josh-rebase (hook declined)
+  error: failed to push some refs to 'http://localhost:8002/real_repo.git:/sub1.git'
+  [1]
+
+  $ cd ${TESTTMP}/real_repo
+  $ git pull --rebase 1> /dev/null
+  $ git log --graph --pretty=%s
+  * change file1 upstream again
+  * add file4
+  * change file1 upstream
+  * add file3
+  * change sub2
+  * add files
+
+  $ bash ${TESTDIR}/destroy_test_env.sh
+  remote/scratch/refs
+  |-- heads
+  |-- josh
+  |   |-- filtered
+  |   |   `-- real_repo.git
+  |   |       |-- %3A%2Fsub1
+  |   |       |   `-- heads
+  |   |       |       `-- master
+  |   |       `-- %3A%2Fsub2
+  |   |           `-- heads
+  |   |               `-- master
+  |   `-- upstream
+  |       `-- real_repo.git
+  |           |-- HEAD
+  |           `-- refs
+  |               `-- heads
+  |                   `-- master
+  |-- namespaces
+  `-- tags
+  
+  14 directories, 4 files
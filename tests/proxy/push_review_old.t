@@ -92,13 +92,14 @@
   |   |               `-- master
   |   `-- upstream
   |       `-- real_repo.git
+  |           |-- HEAD
   |           `-- refs
   |               `-- heads
   |                   `-- master
   |-- namespaces
   `-- tags
   
-  12 directories, 2 files
+  12 directories, 3 files
 
   $ cat ${TESTTMP}/josh-proxy.out | grep graph_descendant_of
   [1]
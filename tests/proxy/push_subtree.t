@@ -80,6 +80,7 @@ Make sure all temporary namespace got removed
   |   |               `-- master
   |   `-- upstream
   |       `-- real_repo.git
+  |           |-- HEAD
   |           `-- refs
   |               `-- heads
   |                   |-- master
@@ -87,6 +88,6 @@ Make sure all temporary namespace got removed
   |-- namespaces
   `-- tags
   
-  12 directories, 3 files
+  12 directories, 4 files
 
 $ cat ${TESTTMP}/josh-proxy.out
@@ -122,15 +122,17 @@ Put a double slash in the URL to see that it also works
   |   |               `-- master
   |   `-- upstream
   |       |-- real%2Frepo2.git
+  |       |   |-- HEAD
   |       |   `-- refs
   |       |       `-- heads
   |       |           `-- master
   |       `-- real_repo.git
+  |           |-- HEAD
   |           `-- refs
   |               `-- heads
   |                   `-- master
   |-- namespaces
   `-- tags
   
-  18 directories, 4 files
+  18 directories, 6 files
 
@@ -52,3 +52,72 @@
   changes_contents
   $ curl -s http://localhost:8002/real_repo.git@refs/changes/123/2?get=sub2/on_change
   changes_contents
+
+  $ curl -s http://localhost:8002/real_repo.git?ls
+  [
+    {
+      "mode": "040000",
+      "name": "sub1",
+      "path": "sub1",
+      "sha1": "3d77ff51363c9825cc2a221fc0ba5a883a1a2c72",
+      "size": null,
+      "type": "tree"
+    },
+    {
+      "mode": "040000",
+      "name": "sub2",
+      "path": "sub2",
+      "sha1": "85837e6104d0a81b944c067e16ddc83c7a38739f",
+      "size": null,
+      "type": "tree"
+    },
+    {
+      "mode": "100644",
+      "name": "tmpl_file",
+      "path": "tmpl_file",
+      "sha1": "2a7fff609aee7ccf358c603fc1655dfc3a429be8",
+      "size": 72,
+      "type": "blob"
+    }
+  ]
+  $ curl -s http://localhost:8002/real_repo.git?ls=sub1
+  [
+    {
+      "mode": "100644",
+      "name": "file1",
+      "path": "sub1/file1",
+      "sha1": "a024003ee1acc6bf70318a46e7b6df651b9dc246",
+      "size": 10,
+      "type": "blob"
+    }
+  ]
+  $ curl -s http://localhost:8002/real_repo.git:/sub2.git?ls=file2
+  [
+    {
+      "mode": "100644",
+      "name": "file2",
+      "path": "file2",
+      "sha1": "a024003ee1acc6bf70318a46e7b6df651b9dc246",
+      "size": 10,
+      "type": "blob"
+    }
+  ]
+  $ curl -s http://localhost:8002/real_repo.git:/sub2.git?tree
+  [
+    {
+      "mode": "100644",
+      "name": "file2",
+      "path": "file2",
+      "sha1": "a024003ee1acc6bf70318a46e7b6df651b9dc246",
+      "size": 10,
+      "type": "blob"
+    }
+  ]
+  $ curl -s http://localhost:8002/real_repo.git?tree=/ | grep path
+      "path": "sub1",
+      "path": "sub1/file1",
+      "path": "sub2",
+      "path": "sub2/file2",
+      "path": "tmpl_file",
+  $ curl -s http://localhost:8002/real_repo.git?ls=nothing_here
+  query ERROR "ls=nothing_here" (no-eol)
@@ -0,0 +1,112 @@
+  $ . ${TESTDIR}/setup_test_env.sh
+  $ cd ${TESTTMP}
+
+  $ git clone -q http://localhost:8001/real_repo.git
+  warning: You appear to have cloned an empty repository.
+
+  $ cd real_repo
+
+  $ mkdir sub1
+  $ echo contents1 > sub1/file1
+  $ mkdir tmpl
+  $ cat > tmpl/loop_tmpl <<EOF
+  > {{#each (git-find "") }}{{ git-blob "sub1/file1" }}{{/each}}
+  > EOF
+  $ cat > tmpl/filter_tmpl <<EOF
+  > {{#with (josh-filter ":/sub1") }}{{ sha1 }}{{/with}}
+  > EOF
+  $ cat > tmpl/log_tmpl <<EOF
+  > {{#each (git-log limit=10) }}.{{/each}}
+  > EOF
+  $ git add .
+  $ git commit -m "add templates" 1> /dev/null
+  $ git push -q
+  $ cd ${TESTTMP}
+
+  $ curl -s http://localhost:8002/real_repo.git?render=tmpl/loop_tmpl
+  contents1
+  contents1
+  contents1
+  contents1
+  contents1
+  contents1
+  
+
+Restart the proxy with tighter limits
+
+  $ kill $(cat ${TESTTMP}/proxy_pid)
+  $ ${TESTDIR}/../../target/debug/josh-proxy \
+  >   --port=8002 \
+  >   --local=${TESTTMP}/remote/scratch/ \
+  >   --remote=http://localhost:8001 \
+  >   --query-max-helper-calls=4 \
+  >   --query-max-output=40 \
+  >   --query-helpers=git-find,git-blob,git-log \
+  >   > ${TESTTMP}/josh-proxy.out 2>&1 &
+  $ echo $! > ${TESTTMP}/proxy_pid
+  $ sleep 1
+
+  $ curl -s http://localhost:8002/real_repo.git?render=tmpl/loop_tmpl
+  query ERROR "render=tmpl/loop_tmpl": query limit exceeded: more than 4 helper calls (no-eol)
+  $ curl -s http://localhost:8002/real_repo.git?render=tmpl/filter_tmpl
+  query ERROR "render=tmpl/filter_tmpl": query limit exceeded: helper "josh-filter" is not allowed (no-eol)
+  $ curl -s http://localhost:8002/real_repo.git?render=tmpl/log_tmpl
+  query ERROR "render=tmpl/log_tmpl": query limit exceeded: output larger than 40 bytes (no-eol)
+  $ curl -s http://localhost:8002/real_repo.git?get=tmpl/loop_tmpl
+  query ERROR "get=tmpl/loop_tmpl": query limit exceeded: output larger than 40 bytes (no-eol)
+  $ curl -s http://localhost:8002/real_repo.git?ls=sub1
+  query ERROR "ls=sub1": query limit exceeded: output larger than 40 bytes (no-eol)
+  $ curl -s http://localhost:8002/real_repo.git?tree=
+  query ERROR "tree=": query limit exceeded: output larger than 40 bytes (no-eol)
+  $ curl -s http://localhost:8002/real_repo.git?diff=
+  query ERROR "diff=": query limit exceeded: output larger than 40 bytes (no-eol)
+
+The built-in queries stop at the deadline as well
+
+  $ kill $(cat ${TESTTMP}/proxy_pid)
+  $ ${TESTDIR}/../../target/debug/josh-proxy \
+  >   --port=8002 \
+  >   --local=${TESTTMP}/remote/scratch/ \
+  >   --remote=http://localhost:8001 \
+  >   --query-timeout=0 \
+  >   > ${TESTTMP}/josh-proxy.out 2>&1 &
+  $ echo $! > ${TESTTMP}/proxy_pid
+  $ sleep 1
+
+  $ curl -s http://localhost:8002/real_repo.git?tree=
+  query ERROR "tree=": query limit exceeded: rendering took longer than 0s (no-eol)
+  $ curl -s http://localhost:8002/real_repo.git?diff=
+  query ERROR "diff=": query limit exceeded: rendering took longer than 0s (no-eol)
+  $ curl -s http://localhost:8002/real_repo.git?get=sub1/file1
+  query ERROR "get=sub1/file1": query limit exceeded: rendering took longer than 0s (no-eol)
+
+Other errors are not explained
+
+  $ curl -s http://localhost:8002/real_repo.git?render=tmpl/nothing
+  query ERROR "render=tmpl/nothing" (no-eol)
+
+  $ bash ${TESTDIR}/destroy_test_env.sh
+  remote/scratch/refs
+  |-- heads
+  |-- josh
+  |   |-- filtered
+  |   |   `-- real_repo.git
+  |   |       |-- %3A%2Fsub1
+  |   |       |   `-- heads
+  |   |       |       `-- master
+  |   |       |-- %3A%2Ftmpl
+  |   |       |   `-- heads
+  |   |       |       `-- master
+  |   |       `-- %3Anop
+  |   |           `-- heads
+  |   |               `-- master
+  |   `-- upstream
+  |       `-- real_repo.git
+  |           |-- HEAD
+  |           `-- refs
+  |               `-- heads
+  |                   `-- master
+  |-- namespaces
+  `-- tags
+  
+  16 directories, 5 files
@@ -0,0 +1,194 @@
+  $ . ${TESTDIR}/setup_test_env.sh
+  $ cd ${TESTTMP}
+
+  $ git clone -q http://localhost:8001/real_repo.git
+  warning: You appear to have cloned an empty repository.
+
+  $ cd real_repo
+
+  $ mkdir sub1
+  $ echo contents1 > sub1/file1
+  $ git add sub1
+  $ git commit -m "add file1" 1> /dev/null
+
+  $ mkdir sub2
+  $ echo contents2 > sub2/file2
+  $ git add sub2
+  $ git commit -m "add file2" 1> /dev/null
+
+  $ echo more >> sub1/file1
+  $ echo contents3 > sub1/file3
+  $ git add sub1
+  $ git commit -m "change sub1" 1> /dev/null
+
+  $ cat > log_tmpl <<EOF
+  > {{#each (git-log limit=10) ~}}
+  > {{ summary }} by {{ author.name }}: {{#each paths}}{{ this }} {{/each}}
+  > {{/each ~}}
+  > EOF
+
+  $ cat > log_path_tmpl <<EOF
+  > {{#each (git-log path="sub2") ~}}
+  > {{ summary }}
+  > {{/each ~}}
+  > EOF
+
+  $ cat > log_range_tmpl <<EOF
+  > {{#each (git-log range="HEAD~2..HEAD~1") ~}}
+  > {{ summary }}
+  > {{/each ~}}
+  > EOF
+
+  $ cat > diff_tmpl <<EOF
+  > {{#with (git-diff-stat range="HEAD~3..HEAD~1") ~}}
+  > {{ files_changed }} files, +{{ insertions }} -{{ deletions }}
+  > {{#each files ~}}
+  > {{ status }} {{ path }} +{{ insertions }} -{{ deletions }}
+  > {{/each ~}}
+  > {{/with ~}}
+  > EOF
+
+  $ mkdir tmpl
+  $ mv *_tmpl tmpl/
+  $ git add tmpl
+  $ git commit -m "add templates" 1> /dev/null
+
+  $ git push -q
+
+  $ cd ${TESTTMP}
+
+  $ curl -s http://localhost:8002/real_repo.git?render=tmpl/log_tmpl
+  add templates by Josh: tmpl/diff_tmpl tmpl/log_path_tmpl tmpl/log_range_tmpl tmpl/log_tmpl 
+  change sub1 by Josh: sub1/file1 sub1/file3 
+  add file2 by Josh: sub2/file2 
+  add file1 by Josh: sub1/file1 
+
+The history is the one of the filtered view
+
+  $ curl -s http://localhost:8002/real_repo.git:/tmpl.git?render=log_tmpl
+  add templates by Josh: diff_tmpl log_path_tmpl log_range_tmpl log_tmpl 
+
+  $ curl -s http://localhost:8002/real_repo.git?render=tmpl/log_path_tmpl
+  add file2
+
+  $ curl -s http://localhost:8002/real_repo.git?render=tmpl/log_range_tmpl
+  change sub1
+
+  $ curl -s http://localhost:8002/real_repo.git?render=tmpl/diff_tmpl
+  3 files, +3 -0
+  modified sub1/file1 +1 -0
+  added sub1/file3 +1 -0
+  added sub2/file2 +1 -0
+
+Templates are compiled once per blob, a changed template is picked up
+
+  $ cd real_repo
+  $ cat > tmpl/blob_tmpl <<EOF
+  > {{#each (git-find "file.*") ~}}
+  > {{ path }}: {{ git-blob path }}
+  > {{~/each}}
+  > EOF
+  $ git add tmpl
+  $ git commit -m "add blob_tmpl" 1> /dev/null
+  $ git push -q
+  $ cd ${TESTTMP}
+
+  $ curl -s http://localhost:8002/real_repo.git?render=tmpl/blob_tmpl
+  sub1/file1: contents1
+  more
+  sub1/file3: contents3
+  sub2/file2: contents2
+  
+
+  $ cd real_repo
+  $ sed -i 's/file\.\*/file2/' tmpl/blob_tmpl
+  $ git commit -am "change blob_tmpl" 1> /dev/null
+  $ git push -q
+  $ cd ${TESTTMP}
+
+  $ curl -s http://localhost:8002/real_repo.git?render=tmpl/blob_tmpl
+  sub2/file2: contents2
+  
+
+Helpers can read other revisions and branches of the view
+
+  $ cd real_repo
+  $ git checkout -q -b other
+  $ echo other > sub1/file1
+  $ git commit -am "change on other" 1> /dev/null
+  $ git push -q origin other
+  $ git checkout -q master
+  $ cat > tmpl/rev_tmpl <<EOF
+  > HEAD: {{ git-blob "sub1/file1" }}
+  > HEAD~6: {{ git-blob "sub1/file1" rev="HEAD~6" }}
+  > other: {{ git-blob "sub1/file1" rev="other" }}
+  > refs/heads/other: {{ git-blob "sub1/file1" rev="refs/heads/other" }}
+  > file3: {{#each (git-find "file3") }}{{ path }}{{/each}}
+  > file3 in HEAD~6: {{#each (git-find "file3" rev="HEAD~6") }}{{ path }}{{/each}}
+  > {{#with (josh-filter ":/sub1" rev="other") }}{{ sha1 }}{{/with}}
+  > EOF
+  $ git add tmpl
+  $ git commit -m "add rev_tmpl" 1> /dev/null
+  $ git push -q
+  $ cd ${TESTTMP}
+
+  $ curl -s http://localhost:8002/real_repo.git?render=tmpl/rev_tmpl | tee rendered
+  HEAD: contents1
+  more
+  
+  HEAD~6: contents1
+  
+  other: other
+  
+  refs/heads/other: other
+  
+  file3: sub1/file3
+  file3 in HEAD~6: 
+  * (glob)
+
+  $ git ls-remote http://localhost:8002/real_repo.git:/sub1.git refs/heads/other | cut -f1 > expected
+  $ tail -n 1 rendered | diff - expected
+
+Commits not reachable from the refs of the view can't be read
+
+  $ cd real_repo
+  $ cat > tmpl/bad_rev_tmpl <<EOF
+  > {{ git-blob "log_tmpl" rev="$(git ls-remote http://localhost:8002/real_repo.git:/tmpl.git refs/heads/master | cut -f1)" }}
+  > EOF
+  $ git add tmpl
+  $ git commit -m "add bad_rev_tmpl" 1> /dev/null
+  $ git push -q
+  $ cd ${TESTTMP}
+
+  $ curl -s http://localhost:8002/real_repo.git?render=tmpl/bad_rev_tmpl
+  query ERROR "render=tmpl/bad_rev_tmpl" (no-eol)
+
+  $ bash ${TESTDIR}/destroy_test_env.sh
+  remote/scratch/refs
+  |-- heads
+  |-- josh
+  |   |-- filtered
+  |   |   `-- real_repo.git
+  |   |       |-- %3A%2Fsub1
+  |   |       |   `-- heads
+  |   |       |       `-- master
+  |   |       |-- %3A%2Fsub2
+  |   |       |   `-- heads
+  |   |       |       `-- master
+  |   |       |-- %3A%2Ftmpl
+  |   |       |   `-- heads
+  |   |       |       `-- master
+  |   |       `-- %3Anop
+  |   |           `-- heads
+  |   |               `-- master
+  |   `-- upstream
+  |       `-- real_repo.git
+  |           |-- HEAD
+  |           `-- refs
+  |               `-- heads
+  |                   |-- master
+  |                   `-- other
+  |-- namespaces
+  `-- tags
+  
+  18 directories, 7 files
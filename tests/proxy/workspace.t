@@ -257,12 +257,13 @@
   |   |               `-- master
   |   `-- upstream
   |       `-- real_repo.git
+  |           |-- HEAD
   |           `-- refs
   |               `-- heads
   |                   `-- master
   |-- namespaces
   `-- tags
   
-  22 directories, 7 files
+  22 directories, 8 files
 
 $ cat ${TESTTMP}/josh-proxy.out | grep VIEW
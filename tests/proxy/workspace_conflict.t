@@ -0,0 +1,151 @@
+  $ . ${TESTDIR}/setup_test_env.sh
+  $ cd ${TESTTMP}
+
+  $ git clone -q http://localhost:8001/real_repo.git
+  warning: You appear to have cloned an empty repository.
+  $ cd real_repo
+
+  $ mkdir shared
+  $ echo contents1 > shared/file1
+  $ mkdir other
+  $ echo contents1 > other/file2
+  $ mkdir ws
+  $ cat > ws/workspace.josh <<EOF
+  > a = :/shared
+  > b = :/shared
+  > EOF
+  $ mkdir ws2
+  $ cat > ws2/workspace.josh <<EOF
+  > c = :/shared
+  > c/d = :/other
+  > EOF
+  $ git add .
+  $ git commit -m "add workspaces" 1> /dev/null
+  $ git push 1> /dev/null
+  To http://localhost:8001/real_repo.git
+   * [new branch]      master -> master
+
+  $ cd ${TESTTMP}
+  $ git clone -q http://localhost:8002/real_repo.git:workspace=ws.git ws
+  $ cd ws
+  $ git ls-tree -r --name-only HEAD
+  a/file1
+  b/file1
+  workspace.josh
+
+Changing one copy of a path mapped twice works
+  $ echo contents2 > a/file1
+  $ git commit -a -m "change a" 1> /dev/null
+  $ git push 1> /dev/null
+  remote: josh-proxy        
+  remote: response from upstream:        
+  remote:  To http://localhost:8001/real_repo.git        
+  remote:    *..*  JOSH_PUSH -> master         (glob)
+  remote: 
+  remote: 
+  To http://localhost:8002/real_repo.git:workspace=ws.git
+     *..*  master -> master (glob)
+
+Changing both copies differently is rejected
+  $ curl -s http://localhost:8002/flush
+  Flushed credential cache
+  $ git pull -q --rebase
+  $ echo contents3 > a/file1
+  $ echo contents4 > b/file1
+  $ git commit -a -m "change a and b" 1> /dev/null
+  $ git push
+  remote: josh-proxy        
+  remote: response from upstream:        
+  remote:  conflicting changes in "b": shared/file1        
+  remote: 
+  remote: 
+  remote: error: hook declined to update refs/heads/master        
+  To http://localhost:8002/real_repo.git:workspace=ws.git
+   ! [remote rejected] master -> master (hook declined)
+  error: failed to push some refs to 'http://localhost:8002/real_repo.git:workspace=ws.git'
+  [1]
+
+Overlapping prefixes are rejected
+  $ cd ${TESTTMP}
+  $ git clone -q http://localhost:8002/real_repo.git:workspace=ws2.git ws2
+  $ cd ws2
+  $ git ls-tree -r --name-only HEAD
+  c/d/file2
+  c/file1
+  workspace.josh
+
+Changes outside of the overlap can be pushed
+  $ echo contents5 > c/file1
+  $ git commit -a -m "change c" 1> /dev/null
+  $ git push 1> /dev/null
+  remote: josh-proxy        
+  remote: response from upstream:        
+  remote:  To http://localhost:8001/real_repo.git        
+  remote:    *..*  JOSH_PUSH -> master         (glob)
+  remote: 
+  remote: 
+  To http://localhost:8002/real_repo.git:workspace=ws2.git
+     *..*  master -> master (glob)
+
+  $ curl -s http://localhost:8002/flush
+  Flushed credential cache
+  $ git pull -q --rebase
+  $ echo contents2 > c/d/file2
+  $ git commit -a -m "change c/d" 1> /dev/null
+  $ git push
+  remote: josh-proxy        
+  remote: response from upstream:        
+  remote:  overlapping prefixes: "c" and "c/d"        
+  remote: 
+  remote: 
+  remote: error: hook declined to update refs/heads/master        
+  To http://localhost:8002/real_repo.git:workspace=ws2.git
+   ! [remote rejected] master -> master (hook declined)
+  error: failed to push some refs to 'http://localhost:8002/real_repo.git:workspace=ws2.git'
+  [1]
+
+  $ cd ${TESTTMP}/real_repo
+  $ git pull -q --rebase
+  $ git log --pretty=%s
+  change c
+  change a
+  add workspaces
+  $ cat shared/file1
+  contents5
+  $ cat other/file2
+  contents1
+
+  $ bash ${TESTDIR}/destroy_test_env.sh
+  remote/scratch/refs
+  |-- heads
+  |-- josh
+  |   |-- filtered
+  |   |   `-- real_repo.git
+  |   |       |-- %3A%2Fother
+  |   |       |   `-- heads
+  |   |       |       `-- master
+  |   |       |-- %3A%2Fshared
+  |   |       |   `-- heads
+  |   |       |       `-- master
+  |   |       |-- %3A%2Fws
+  |   |       |   `-- heads
+  |   |       |       `-- master
+  |   |       |-- %3A%2Fws2
+  |   |       |   `-- heads
+  |   |       |       `-- master
+  |   |       |-- %3Aworkspace=ws
+  |   |       |   `-- heads
+  |   |       |       `-- master
+  |   |       `-- %3Aworkspace=ws2
+  |   |           `-- heads
+  |   |               `-- master
+  |   `-- upstream
+  |       `-- real_repo.git
+  |           |-- HEAD
+  |           `-- refs
+  |               `-- heads
+  |                   `-- master
+  |-- namespaces
+  `-- tags
+  
+  22 directories, 8 files
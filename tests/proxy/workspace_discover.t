@@ -127,12 +127,13 @@
   |   |               `-- master
   |   `-- upstream
   |       `-- real%2Frepo2.git
+  |           |-- HEAD
   |           `-- refs
   |               `-- heads
   |                   `-- master
   |-- namespaces
   `-- tags
   
-  26 directories, 9 files
+  26 directories, 10 files
 
 $ cat ${TESTTMP}/josh-proxy.out
@@ -464,12 +464,13 @@ Note that ws/d/ is now present in the ws
   |   |               `-- master
   |   `-- upstream
   |       `-- real_repo.git
+  |           |-- HEAD
   |           `-- refs
   |               `-- heads
   |                   `-- master
   |-- namespaces
   `-- tags
   
-  24 directories, 8 files
+  24 directories, 9 files
 
 $ cat ${TESTTMP}/josh-proxy.out
@@ -308,12 +308,13 @@
   |   |               `-- master
   |   `-- upstream
   |       `-- real_repo.git
+  |           |-- HEAD
   |           `-- refs
   |               `-- heads
   |                   `-- master
   |-- namespaces
   `-- tags
   
-  24 directories, 8 files
+  24 directories, 9 files
 
 $ cat ${TESTTMP}/josh-proxy.out | grep VIEW
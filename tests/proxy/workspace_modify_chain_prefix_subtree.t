@@ -376,12 +376,13 @@ Note that ws/d/ is now present in the ws
   |   |               `-- master
   |   `-- upstream
   |       `-- real_repo.git
+  |           |-- HEAD
   |           `-- refs
   |               `-- heads
   |                   `-- master
   |-- namespaces
   `-- tags
   
-  26 directories, 9 files
+  26 directories, 10 files
 
 $ cat ${TESTTMP}/josh-proxy.out | grep VIEW
@@ -119,12 +119,13 @@ file was created
   |   |               `-- master
   |   `-- upstream
   |       `-- real_repo.git
+  |           |-- HEAD
   |           `-- refs
   |               `-- heads
   |                   `-- master
   |-- namespaces
   `-- tags
   
-  18 directories, 5 files
+  18 directories, 6 files
 
 $ cat ${TESTTMP}/josh-proxy.out | grep VIEW
@@ -152,12 +152,13 @@
   |   |               `-- master
   |   `-- upstream
   |       `-- real_repo.git
+  |           |-- HEAD
   |           `-- refs
   |               `-- heads
   |                   `-- master
   |-- namespaces
   `-- tags
   
-  16 directories, 4 files
+  16 directories, 5 files
 
 $ cat ${TESTTMP}/josh-proxy.out | grep VIEW